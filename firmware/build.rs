@@ -1,6 +1,5 @@
 use rand::RngCore;
 use regex::{Captures, Regex};
-use std::borrow::Cow;
 use std::env;
 use std::fmt::Write;
 use std::fs;
@@ -73,27 +72,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Extract memory size from device tree source file
     let memory_re =
-        Regex::new(r"(memory@\w+\s*\{\s*reg\s*=\s*/bits/\s*64\s*<)\s*0x(\w+)\s*0x(\w+)").unwrap();
-    let mut memory_base = 0;
-    let mut memory_size = 0;
-    dts = match memory_re.replace(&dts, |caps: &Captures| {
-        memory_base = u64::from_str_radix(&caps[2], 16).unwrap();
-        memory_size = u64::from_str_radix(&caps[3], 16).unwrap();
-
-        // Reserve 2MB for the firmware.
-        format!(
-            "{}{:#010x} {:#010x}",
-            &caps[1],
-            memory_base,
-            memory_size - 0x200000
-        )
-    }) {
-        Cow::Owned(s) => s,
-        _ => panic!("cannot find memory block in device tree"),
-    };
+        Regex::new(r"memory@\w+\s*\{\s*reg\s*=\s*/bits/\s*64\s*<\s*0x(\w+)\s*0x(\w+)").unwrap();
+    let caps = memory_re
+        .captures(&dts)
+        .expect("cannot find memory block in device tree");
+    let memory_base = u64::from_str_radix(&caps[1], 16).unwrap();
+    let memory_size = u64::from_str_radix(&caps[2], 16).unwrap();
     let memory_limit = memory_base + memory_size;
 
-    // Find the address of CLINT and remove that node.
+    // Find the address of CLINT. The node itself is removed by the firmware at runtime.
     let clint_base;
     {
         let node = fdt
@@ -101,24 +88,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .expect("cannot find CLINT node");
         let reg = node.raw_reg().unwrap().next().unwrap();
         clint_base = u64::from_be_bytes(reg.address.try_into()?);
+    };
 
-        let re = Regex::new(&format!(r"{}\s*\{{[^}}]*\}}\s*;\s*", node.name)).unwrap();
-        dts = re.replace(&dts, "").into_owned();
+    // Find the address of PLIC.
+    let plic_base;
+    {
+        let node = fdt
+            .find_compatible(&["sifive,plic-1.0.0", "riscv,plic0"])
+            .expect("cannot find PLIC node");
+        let reg = node.raw_reg().unwrap().next().unwrap();
+        plic_base = u64::from_be_bytes(reg.address.try_into()?);
     };
 
-    // Find the address of display controller and remove that node.
+    // Find the address of display controller. The node itself is removed by the firmware at
+    // runtime.
     let display_base;
     if let Some(node) = fdt.find_compatible(&["garyguo,display-controller"]) {
         let reg = node.raw_reg().unwrap().next().unwrap();
         display_base = Some(u64::from_be_bytes(reg.address.try_into()?));
-
-        let re = Regex::new(&format!(r"{}\s*\{{[^}}]*\}}\s*;\s*", node.name)).unwrap();
-        dts = re.replace(&dts, "").into_owned();
     } else {
         display_base = None;
     }
 
-    // Compile modified device tree source into binary.
+    // Compile device tree source with the MAC address filled in into binary.
     fs::write(&dts_file, dts)?;
     let status = Command::new("dtc")
         .args(&[&dts_file, "-o", &dtb_file])
@@ -147,6 +139,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         clint_base
     )?;
 
+    writeln!(
+        generated_rs,
+        "pub const PLIC_BASE: usize = {:#x};",
+        plic_base
+    )?;
+
     if let Some(display_base) = display_base {
         println!("cargo:rustc-cfg=has_display");
         writeln!(
@@ -171,16 +169,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         )?;
     }
 
+    if let Some(node) = fdt.find_compatible(&["digilent,axi-ps2-1.0"]) {
+        let reg = node.raw_reg().unwrap().next().unwrap();
+        let base = u64::from_be_bytes(reg.address.try_into()?);
+        println!("cargo:rustc-cfg=has_ps2");
+        writeln!(generated_rs, "pub const PS2_BASE: usize = {:#x};", base)?;
+    }
+
     if let Some(node) = fdt.find_compatible(&["garyguo,sdhci"]) {
         let reg = node.raw_reg().unwrap().next().unwrap();
         let base = u64::from_be_bytes(reg.address.try_into()?);
         writeln!(generated_rs, "pub const SD_BASE: usize = {:#x};", base)?;
+        // The interrupt parent is the PLIC, which has a single interrupt cell.
+        let irq = node
+            .property("interrupts-extended")
+            .map(|p| u32::from_be_bytes(p.value[p.value.len() - 4..].try_into().unwrap()));
+        writeln!(generated_rs, "pub const SD_IRQ: Option<u32> = {:?};", irq)?;
     }
 
     if let Some(node) = fdt.find_compatible(&["xlnx,axi-ethernet-1.00.a"]) {
         let mut regs = node.raw_reg().unwrap();
         let mac_base = u64::from_be_bytes(regs.next().unwrap().address.try_into()?);
         let dma_base = u64::from_be_bytes(regs.next().unwrap().address.try_into()?);
+        println!("cargo:rustc-cfg=has_ethernet");
         writeln!(
             generated_rs,
             "pub const ETH_MAC_BASE: usize = {:#x};",
@@ -191,6 +202,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "pub const ETH_DMA_BASE: usize = {:#x};",
             dma_base
         )?;
+    }
+
+    if let Some(node) = fdt.find_compatible(&[
+        "xlnx,xps-ethernetlite-3.00.a",
+        "xlnx,xps-ethernetlite-2.00.a",
+        "xlnx,xps-ethernetlite-1.00.a",
+    ]) {
+        let reg = node.raw_reg().unwrap().next().unwrap();
+        let base = u64::from_be_bytes(reg.address.try_into()?);
+        let ping_pong = |name| {
+            node.property(name)
+                .map(|p| u32::from_be_bytes(p.value.try_into().unwrap()) != 0)
+                .unwrap_or(false)
+        };
+        println!("cargo:rustc-cfg=has_emaclite");
+        writeln!(
+            generated_rs,
+            "pub const EMACLITE_BASE: usize = {:#x};",
+            base
+        )?;
+        writeln!(
+            generated_rs,
+            "pub const EMACLITE_TX_PING_PONG: bool = {};",
+            ping_pong("xlnx,tx-ping-pong")
+        )?;
+        writeln!(
+            generated_rs,
+            "pub const EMACLITE_RX_PING_PONG: bool = {};",
+            ping_pong("xlnx,rx-ping-pong")
+        )?;
+    }
+
+    if fdt
+        .find_compatible(&[
+            "xlnx,axi-ethernet-1.00.a",
+            "xlnx,xps-ethernetlite-3.00.a",
+            "xlnx,xps-ethernetlite-2.00.a",
+            "xlnx,xps-ethernetlite-1.00.a",
+        ])
+        .is_some()
+    {
+        println!("cargo:rustc-cfg=has_net");
         writeln!(
             generated_rs,
             "pub const MAC_ADDRESS: [u8; 6] = {:?};",
@@ -5,8 +5,10 @@
 
 mod part;
 mod sd;
+mod table;
 pub use part::Part;
 pub use sd::Sd;
+pub use table::partitions;
 
 use crate::io::Result;
 
@@ -18,6 +20,9 @@ pub struct Capability {
 
     /// Whether discard operation is supported by the block device.
     pub discard: bool,
+
+    /// Value of every byte of discarded blocks when read back, if the device defines it.
+    pub discard_fill: Option<u8>,
 }
 
 impl Default for Capability {
@@ -25,6 +30,7 @@ impl Default for Capability {
         Capability {
             blksize: 512,
             discard: false,
+            discard_fill: None,
         }
     }
 }
@@ -51,6 +57,9 @@ pub trait Block {
 
     /// Discard contents at the given offset.
     ///
+    /// The contents read back afterwards are given by `discard_fill` queried by `capability`, and
+    /// are unspecified if it is `None`.
+    ///
     /// Caller must ensure `offset` and `len` is aligned to `blksize` queried by `capability`.
     fn discard(&self, offset: u64, len: usize) -> Result<()> {
         let _ = (offset, len);
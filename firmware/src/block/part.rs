@@ -1,49 +1,56 @@
 use super::Block;
-use crate::io::Result as IoResult;
+use crate::io::{Error as IoError, Result as IoResult};
 use alloc::sync::Arc;
 
 pub struct Part {
     blk: Arc<dyn Block>,
     offset: u64,
+    len: u64,
 }
 
 impl Part {
-    pub fn new(blk: Arc<dyn Block>, offset: u64) -> Self {
-        Self { blk, offset }
+    pub fn new(blk: Arc<dyn Block>, offset: u64, len: u64) -> Self {
+        Self { blk, offset, len }
     }
 
-    pub fn first_partition(blk: Arc<dyn Block>) -> IoResult<Self> {
-        let mut buf = [0; 512];
-
-        // Read the MBR
-        blk.read_exact_at(&mut buf, 0)?;
-
-        assert_eq!(buf[510], 0x55, "MBR not valid");
-        assert_eq!(buf[511], 0xAA, "MBR not valid");
-
-        // Check partition 1
-        let part = &buf[0x1BE..0x1CE];
-        assert_eq!(part[4], 0x0C, "Only FAT32 is supported");
-        let mut lba = [0; 4];
-        lba.copy_from_slice(&part[0x8..0xC]);
-        let lba = u32::from_le_bytes(lba);
-        let part_offset = lba as u64 * 512;
-
-        println!("FAT32 partition located at {}KiB", lba / 2);
-        Ok(Self::new(blk, part_offset))
+    fn check_range(&self, offset: u64, len: usize) -> IoResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(IoError::Textual("access beyond end of partition")),
+        }
     }
 }
 
 impl super::Block for Part {
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()> {
+        self.check_range(offset, buf.len())?;
         self.blk.read_exact_at(buf, offset + self.offset)
     }
 
     fn write_all_at(&self, buf: &[u8], offset: u64) -> IoResult<()> {
+        self.check_range(offset, buf.len())?;
         self.blk.write_all_at(buf, offset + self.offset)
     }
 
+    fn write_zero_at(&self, offset: u64, len: usize) -> IoResult<()> {
+        self.check_range(offset, len)?;
+        self.blk.write_zero_at(offset + self.offset, len)
+    }
+
+    fn discard(&self, offset: u64, len: usize) -> IoResult<()> {
+        self.check_range(offset, len)?;
+        self.blk.discard(offset + self.offset, len)
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        self.blk.flush()
+    }
+
     fn len(&self) -> u64 {
-        todo!();
+        self.len
+    }
+
+    fn capability(&self) -> super::Capability {
+        self.blk.capability()
     }
 }
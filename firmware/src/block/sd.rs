@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
-use crate::io::Result as IoResult;
+use crate::io::{Error as IoError, Result as IoResult};
 use crate::iomem::IoMem;
+use crate::plic;
 use crate::timer::{sleep, Timer};
 use byteorder::{ByteOrder, LE};
 use core::time::Duration;
@@ -22,26 +23,214 @@ const TIMEOUT_CTRL: usize = 0x2E;
 const SW_RESET: usize = 0x2F;
 const IRQ_STATUS: usize = 0x30;
 const IRQ_ENABLE: usize = 0x34;
+const IRQ_SIGNAL_ENABLE: usize = 0x38;
 const CAPABILITIES: usize = 0x40;
+const IRQ_BUFFER_WRITE_READY: u32 = 1 << 4;
+const IRQ_BUFFER_READ_READY: u32 = 1 << 5;
+const IRQ_ERRORS: u32 = 0x03FF_0000;
+
+/// Error bits in R1 card status.
+const R1_ERRORS: u32 = 0xFDF9_8008;
+/// Commands with R1 or R1b responses, whose card status is checked.
+const R1_COMMANDS: &[u8] = &[6, 7, 12, 13, 16, 17, 18, 24, 25, 32, 33, 38, 51, 55];
+
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(100);
+const CARD_DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+const CMD_TIMEOUT: Duration = Duration::from_millis(100);
+/// Erase can take up to 250ms per allocation unit.
+const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of blocks in a single transfer, as BLK_CNT is 16 bits.
+const MAX_BLOCKS: usize = 0xFFFF;
+
+/// Number of times an operation is retried, reinitialising the card at a lower clock each time.
+const RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SdError {
+    /// No card in the slot.
+    NoCard,
+    /// Card does not support the supply voltage, or is a legacy card without CMD8.
+    Voltage,
+    /// Card is still initialising, or a transfer did not complete in time.
+    Timeout,
+    Crc,
+    EndBit,
+    /// Response does not belong to the command sent.
+    Index,
+    /// Card status of a R1 response contains error bits.
+    CardStatus(u32),
+    /// Other error interrupt bits, as in the upper half of the interrupt status register.
+    Controller(u16),
+    UnsupportedCard,
+    /// Base or timeout clock is unknown, or cannot be divided down to the clock needed.
+    Clock,
+    /// Command whose response type the driver does not know.
+    UnsupportedCommand(u8),
+    /// Block address cannot be expressed in a command argument.
+    OutOfRange,
+}
+
+impl SdError {
+    /// Decode the error interrupt status.
+    fn from_irq(status: u16) -> Self {
+        // Command errors take precedence, as data errors are often a consequence of them.
+        if status & 0b0001 != 0 {
+            SdError::Timeout
+        } else if status & 0b0010 != 0 {
+            SdError::Crc
+        } else if status & 0b0100 != 0 {
+            SdError::EndBit
+        } else if status & 0b1000 != 0 {
+            SdError::Index
+        } else if status & 0x0010 != 0 {
+            SdError::Timeout
+        } else if status & 0x0020 != 0 {
+            SdError::Crc
+        } else if status & 0x0040 != 0 {
+            SdError::EndBit
+        } else {
+            SdError::Controller(status)
+        }
+    }
+}
+
+impl From<SdError> for IoError {
+    fn from(err: SdError) -> Self {
+        // The textual error loses the status bits, so show them here.
+        println!("SD error: {:?}", err);
+        IoError::Textual(match err {
+            SdError::NoCard => "SD card not present",
+            SdError::Voltage => "SD card voltage not supported",
+            SdError::Timeout => "SD timeout",
+            SdError::Crc => "SD CRC error",
+            SdError::EndBit => "SD end bit error",
+            SdError::Index => "SD index error",
+            SdError::CardStatus(_) => "SD card status error",
+            SdError::Controller(_) => "SD controller error",
+            SdError::UnsupportedCard => "SD card not supported",
+            SdError::Clock => "SD clock not supported",
+            SdError::UnsupportedCommand(_) => "SD command not supported",
+            SdError::OutOfRange => "SD block address out of range",
+        })
+    }
+}
 
 pub struct Inner {
-    base: IoMem<0x80>,
+    base: IoMem<0x100>,
+    /// PLIC interrupt of the controller, used to sleep while waiting for data.
+    irq: Option<u32>,
     init: bool,
     ccs: bool,
     rca: u16,
+    /// Capacity in bytes.
+    capacity: u64,
+    /// SD clock frequency in kHz.
+    clock: u32,
+    high_speed: bool,
+    /// Highest clock to use in kHz, lowered after errors.
+    max_clock: u32,
+    /// Data timeout as configured in TIMEOUT_CTRL.
+    data_timeout: Duration,
+    /// Value of erased bytes, from DATA_STAT_AFTER_ERASE in the SCR.
+    erase_fill: Option<u8>,
 }
 
 impl Inner {
-    pub const unsafe fn new(base: usize) -> Self {
+    pub const unsafe fn new(base: usize, irq: Option<u32>) -> Self {
         Self {
             base: IoMem::new(base),
+            irq,
             init: false,
             ccs: false,
             rca: 0,
+            capacity: 0,
+            clock: 0,
+            high_speed: false,
+            max_clock: 50000,
+            data_timeout: Duration::from_secs(1),
+            erase_fill: None,
+        }
+    }
+
+    /// Poll `cond` until it returns true, giving up after `timeout`.
+    ///
+    /// On timeout, the registers are dumped and the CMD and DAT lines are reset.
+    fn wait_until(
+        &mut self,
+        what: &str,
+        timeout: Duration,
+        mut cond: impl FnMut(&mut Self) -> Result<bool, SdError>,
+    ) -> Result<(), SdError> {
+        let timer = Timer::new(timeout);
+        loop {
+            if cond(self)? {
+                return Ok(());
+            }
+            if timer.fired() {
+                break;
+            }
+        }
+        // Check once more, in case we were descheduled between the poll and the timer check.
+        if cond(self)? {
+            return Ok(());
+        }
+        println!("SD timeout waiting for {}", what);
+        self.dump_core();
+        self.reset_lines();
+        Err(SdError::Timeout)
+    }
+
+    /// Like `wait_until`, but sleep until one of the `signal` interrupts or an error is raised
+    /// between polls.
+    ///
+    /// The `signal` status bits are cleared afterwards.
+    fn wait_irq(
+        &mut self,
+        what: &str,
+        timeout: Duration,
+        signal: u32,
+        mut cond: impl FnMut(&mut Self) -> Result<bool, SdError>,
+    ) -> Result<(), SdError> {
+        let irq = match self.irq {
+            Some(irq) => irq,
+            None => return self.wait_until(what, timeout, cond),
+        };
+        let timer = Timer::new(timeout);
+        self.base.write_u32(IRQ_SIGNAL_ENABLE, signal | IRQ_ERRORS);
+        let result = self.wait_until(what, timeout, |this| {
+            if cond(this)? {
+                return Ok(true);
+            }
+            plic::wait(irq, &timer);
+            Ok(false)
+        });
+        self.base.write_u32(IRQ_SIGNAL_ENABLE, 0);
+        self.base.write_u32(IRQ_STATUS, signal);
+        result
+    }
+
+    /// Reset the CMD and DAT lines so the next command can be issued.
+    fn reset_lines(&mut self) {
+        self.base.write_u8(SW_RESET, 0b110);
+        let timer = Timer::new(RESET_TIMEOUT);
+        while self.base.read_u8(SW_RESET) & 0b110 != 0 {
+            if timer.fired() {
+                println!("SD CMD/DAT line reset timed out");
+                break;
+            }
         }
     }
 
-    fn set_clock(&mut self, clock: u32) {
+    /// Wait for the DAT lines to become inactive, i.e. transfers complete and busy deasserted.
+    fn wait_data_inactive(&mut self, timeout: Duration) -> Result<(), SdError> {
+        self.wait_until("data inhibit", timeout, |this| {
+            Ok(this.base.read_u32(PRESENT_STATE) & 0b10 == 0)
+        })
+    }
+
+    fn set_clock(&mut self, clock: u32) -> Result<(), SdError> {
         // Turn off SD clock
         let state = self.base.read_u16(CLOCK_CTRL);
         self.base.write_u16(CLOCK_CTRL, state & !0b100);
@@ -49,12 +238,15 @@ impl Inner {
         // Read base clock, must not be zero
         let cap = self.base.read_u32(CAPABILITIES);
         let base_clock = (cap >> 8) & 0b111111;
-        assert_ne!(base_clock, 0);
+        if base_clock == 0 {
+            return Err(SdError::Clock);
+        }
 
         // Calculate the divisor. This needs to round up.
         let divisor = (base_clock * 1000 + clock - 1) / clock;
-
-        assert!(divisor <= 512);
+        if divisor > 512 {
+            return Err(SdError::Clock);
+        }
         // If divisor == 1, use the base clock, otherwise do 2x division.
         let freq_select = if divisor == 1 {
             0
@@ -64,57 +256,56 @@ impl Inner {
 
         self.base.write_u16(CLOCK_CTRL, freq_select << 8 | 0b001);
         // Wait for internal clock to stabilise
-        loop {
-            let state = self.base.read_u16(CLOCK_CTRL);
-            if state & 0b10 != 0 {
-                break;
-            }
-        }
+        self.wait_until("internal clock stable", CLOCK_TIMEOUT, |this| {
+            Ok(this.base.read_u16(CLOCK_CTRL) & 0b10 != 0)
+        })?;
 
         // Turn on SD clock
         self.base.write_u16(CLOCK_CTRL, freq_select << 8 | 0b101);
+        self.clock = clock;
         println!("SD clock set to {}kHz", clock);
+        Ok(())
     }
 
-    pub fn power_on(&mut self) {
+    pub fn power_on(&mut self) -> Result<(), SdError> {
         assert!(!self.init);
         println!("Init SD Card");
         self.rca = 0;
 
         // Reset controller
         self.base.write_u8(SW_RESET, 0b1);
-        loop {
-            let state = self.base.read_u8(SW_RESET);
-            if state & 0x7 == 0 {
-                break;
-            }
-        }
-
-        let present = loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            // Wait for Card State Stable to reach 1
-            if state & 0x00020000 == 0x00020000 {
-                break state & 0x00010000 != 0;
-            }
-        };
+        self.wait_until("controller reset", RESET_TIMEOUT, |this| {
+            Ok(this.base.read_u8(SW_RESET) & 0x7 == 0)
+        })?;
+
+        // Wait for Card State Stable to reach 1
+        self.wait_until("card detect", CARD_DETECT_TIMEOUT, |this| {
+            Ok(this.base.read_u32(PRESENT_STATE) & 0x00020000 != 0)
+        })?;
+        let present = self.base.read_u32(PRESENT_STATE) & 0x00010000 != 0;
         if !present {
-            panic!("SD Card is not present");
+            return Err(SdError::NoCard);
         }
         println!("SD Card present");
 
         // Identification clock should be no more than 400 kHz.
-        self.set_clock(400);
+        self.set_clock(400)?;
 
         let cap = self.base.read_u32(CAPABILITIES);
 
         // Read base clock for timeout (in kHZ)
         let base_clock = (cap & 0b111111) * if (cap >> 7) & 1 == 0 { 1 } else { 1000 };
-        assert_ne!(base_clock, 0);
+        if base_clock == 0 {
+            return Err(SdError::Clock);
+        }
 
         // Calculate the log2(divisor) to get 500ms
         let divisor = (32 - (base_clock * 1000 - 1).leading_zeros()).max(13);
-        assert!(divisor <= 27);
+        if divisor > 27 {
+            return Err(SdError::Clock);
+        }
         self.base.write_u8(TIMEOUT_CTRL, (divisor - 13) as u8);
+        self.data_timeout = Duration::from_micros((1u64 << divisor) * 1000 / base_clock as u64);
 
         // Turn on IRQ statuses
         self.base.write_u32(IRQ_ENABLE, 0x03ff_01ff);
@@ -126,61 +317,83 @@ impl Inner {
         println!("SD power on");
 
         // Reset card
-        self.wait_cmd(0, 0).expect("CMD0 shouldn't fail");
+        self.wait_cmd(0, 0)?;
 
-        // Voltage Check
+        // Voltage Check. Legacy cards do not respond to CMD8, and are not supported.
         let resp = self
             .wait_cmd(8, 0b0001_10101010)
-            .expect("Legacy SD card not supported");
-        assert_eq!(resp, 0b0001_10101010);
+            .map_err(|_| SdError::Voltage)?;
+        if resp != 0b0001_10101010 {
+            return Err(SdError::Voltage);
+        }
         println!("voltage check completed");
 
         // ACMD41
         let timer = Timer::new(Duration::from_secs(1));
         let resp = loop {
-            let state = self.wait_app_cmd(41, 0x40300000).expect("ACMD41 failed");
+            let state = self.wait_app_cmd(41, 0x40300000)?;
             if state & 0x80000000 != 0 {
                 break state;
             }
             if timer.fired() {
-                panic!("ACMD41 timed out");
+                return Err(SdError::Timeout);
             }
         };
-        assert!(resp & 0x00300000 != 0);
+        if resp & 0x00300000 == 0 {
+            return Err(SdError::Voltage);
+        }
         self.ccs = resp & 0x40000000 != 0;
         println!("{} detected", if self.ccs { "SDHC/SDXC" } else { "SDSC" });
 
         // Identification complete, ramp up clock to 25MHz.
-        self.set_clock(25000);
+        self.set_clock(core::cmp::min(self.max_clock, 25000))?;
 
         // CMD2
-        self.wait_cmd(2, 0).expect("CMD2 failed");
+        self.wait_cmd(2, 0)?;
 
         // CMD3
-        let resp = self.wait_cmd(3, 0).expect("CMD3 failed");
+        let resp = self.wait_cmd(3, 0)?;
         self.rca = (resp >> 16) as u16;
         println!("RCA = {:x}", self.rca);
 
+        // CMD9 and CMD10, which are only accepted before the card is selected.
+        self.wait_cmd(9, (self.rca as u32) << 16)?;
+        self.capacity = csd_capacity(self.read_long_response()).ok_or(SdError::UnsupportedCard)?;
+        self.wait_cmd(10, (self.rca as u32) << 16)?;
+        print_cid(self.read_long_response());
+        println!("Capacity = {}MiB", self.capacity >> 20);
+
         // Select card
-        self.wait_cmd(7, (self.rca as u32) << 16)
-            .expect("Cannot select card");
+        self.wait_cmd(7, (self.rca as u32) << 16)?;
         // Wait for busy to deassert
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & 0b10 == 0 {
-                break;
-            }
-        }
+        self.wait_data_inactive(self.data_timeout)?;
 
         // Switch mode to 4-bit.
-        self.wait_app_cmd(6, 0b10)
-            .expect("Cannot switch to 4-bit mode");
+        self.wait_app_cmd(6, 0b10)?;
         self.base.write_u8(HOST_CTRL, 0b10);
         println!("4-bit mode switched");
 
         self.base.write_u16(BLK_SIZE, 512);
 
+        // The SCR gives the spec version needed for High-Speed, and the contents of erased blocks.
+        let mut scr = [0; 8];
+        let scr = match self.read_short_block(&mut scr, |this| this.wait_app_cmd(51, 0)) {
+            Ok(()) => Some(scr),
+            Err(err) => {
+                println!("Cannot read SCR: {:?}", err);
+                None
+            }
+        };
+        self.erase_fill = scr.map(|scr| if scr[1] & 0x80 != 0 { 0xFF } else { 0x00 });
+
+        self.high_speed =
+            self.max_clock >= 50000 && scr.is_some_and(|scr| self.enable_high_speed(scr[0] & 0xF));
+        if !self.high_speed {
+            println!("High-Speed mode unavailable, staying at {}kHz", self.clock);
+        }
+
         self.init = true;
+        Ok(())
     }
 
     pub fn power_off(&mut self) {
@@ -197,78 +410,101 @@ impl Inner {
         }
     }
 
-    fn check_err(&mut self) -> Result<(), u16> {
+    fn check_err(&mut self) -> Result<(), SdError> {
         let irq = self.base.read_u32(IRQ_STATUS);
         if irq & 0x8000 != 0 {
             // Clear IRQs
             self.base.write_u32(IRQ_STATUS, 0x03FF0000);
-            Err((irq >> 16) as u16)
+            self.reset_lines();
+            Err(SdError::from_irq((irq >> 16) as u16))
         } else {
             Ok(())
         }
     }
 
-    fn wait_cmd_with_cfg(&mut self, index: u8, argument: u32, config: u8) -> Result<u32, u16> {
+    fn wait_cmd_with_cfg(&mut self, index: u8, argument: u32, config: u8) -> Result<u32, SdError> {
         self.base.write_u32(ARGUMENT, argument);
         self.base
             .write_u16(CMD, (index as u16) << 8 | config as u16);
 
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & 0b1 == 0 {
-                break;
-            }
-        }
+        self.wait_until("command inhibit", CMD_TIMEOUT, |this| {
+            Ok(this.base.read_u32(PRESENT_STATE) & 0b1 == 0)
+        })?;
 
         self.check_err()?;
-        Ok(self.base.read_u32(RESPONSE))
+        let resp = self.base.read_u32(RESPONSE);
+        if R1_COMMANDS.contains(&index) && resp & R1_ERRORS != 0 {
+            return Err(SdError::CardStatus(resp));
+        }
+        Ok(resp)
     }
 
-    fn wait_cmd(&mut self, index: u8, argument: u32) -> Result<u32, u16> {
+    fn wait_cmd(&mut self, index: u8, argument: u32) -> Result<u32, SdError> {
         let config = match index {
             // R0
             0 => 0b00000000,
             // R1
-            8 => 0b00011010,
+            8 | 13 | 32 | 33 => 0b00011010,
             // R1b
-            7 => 0b00011011,
+            7 | 38 => 0b00011011,
             // R2
-            2 => 0b00001001,
+            2 | 9 | 10 => 0b00001001,
             // R4
             5 => 0b00000010,
+            // R1 with data
+            6 => 0b00111010,
             // R6
             3 => 0b00011010,
-            _ => panic!("unknown index {}", index),
+            _ => return Err(SdError::UnsupportedCommand(index)),
         };
         self.wait_cmd_with_cfg(index, argument, config)
     }
 
-    fn wait_app_cmd(&mut self, index: u8, argument: u32) -> Result<u32, u16> {
+    /// Read a R2 response, returning the full 128-bit register with the CRC bits zeroed.
+    fn read_long_response(&self) -> u128 {
+        let mut resp = 0;
+        for i in (0..4).rev() {
+            resp = resp << 32 | self.base.read_u32(RESPONSE + i * 4) as u128;
+        }
+        // The controller strips the CRC, shifting the remaining bits down.
+        resp << 8
+    }
+
+    fn wait_app_cmd(&mut self, index: u8, argument: u32) -> Result<u32, SdError> {
         let config = match index {
             // R1
             6 => 0b00011010,
             // R3
             41 => 0b00000010,
-            _ => panic!("unknown index {}", index),
+            // R1 with data
+            51 => 0b00111010,
+            _ => return Err(SdError::UnsupportedCommand(index)),
         };
-        let resp = self
-            .wait_cmd_with_cfg(55, (self.rca as u32) << 16, 0b00011010)
-            .expect("cannot send APP cmd");
-        assert!(resp & 1 << 5 != 0);
+        let resp = self.wait_cmd_with_cfg(55, (self.rca as u32) << 16, 0b00011010)?;
+        // APP_CMD must be set for the next command to be interpreted as application-specific.
+        if resp & 1 << 5 == 0 {
+            return Err(SdError::CardStatus(resp));
+        }
         self.wait_cmd_with_cfg(index, argument, config)
     }
 
-    fn read_buffer(&mut self, data: &mut [u8]) -> Result<(), u16> {
-        assert_eq!(data.len(), 512);
+    fn read_buffer(&mut self, data: &mut [u8]) -> Result<(), SdError> {
+        assert_eq!(data.len() % 4, 0);
 
         // Wait for buffer to become available
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & (1 << 11) != 0 {
-                break;
-            }
-            self.check_err()?;
-        }
+        let timeout = self.data_timeout;
+        self.wait_irq(
+            "buffer read enable",
+            timeout,
+            IRQ_BUFFER_READ_READY,
+            |this| {
+                if this.base.read_u32(PRESENT_STATE) & (1 << 11) != 0 {
+                    return Ok(true);
+                }
+                this.check_err()?;
+                Ok(false)
+            },
+        )?;
 
         // Read data from buffer into data
         for chunk in data.chunks_exact_mut(4) {
@@ -279,17 +515,23 @@ impl Inner {
         Ok(())
     }
 
-    fn write_buffer(&mut self, data: &[u8]) -> Result<(), u16> {
+    fn write_buffer(&mut self, data: &[u8]) -> Result<(), SdError> {
         assert_eq!(data.len(), 512);
 
         // Wait for buffer to become available
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & (1 << 10) != 0 {
-                break;
-            }
-            self.check_err()?;
-        }
+        let timeout = self.data_timeout;
+        self.wait_irq(
+            "buffer write enable",
+            timeout,
+            IRQ_BUFFER_WRITE_READY,
+            |this| {
+                if this.base.read_u32(PRESENT_STATE) & (1 << 10) != 0 {
+                    return Ok(true);
+                }
+                this.check_err()?;
+                Ok(false)
+            },
+        )?;
 
         // Write data into buffer
         for chunk in data.chunks_exact(4) {
@@ -300,19 +542,107 @@ impl Inner {
         Ok(())
     }
 
+    /// Read a single data block shorter than 512 bytes, with the command issued by `cmd`.
+    fn read_short_block(
+        &mut self,
+        data: &mut [u8],
+        cmd: impl FnOnce(&mut Self) -> Result<u32, SdError>,
+    ) -> Result<(), SdError> {
+        self.base.write_u16(BLK_SIZE, data.len() as u16);
+        self.base.write_u16(XFER_MODE, 0b010000);
+        let result = cmd(self).and_then(|_| self.read_buffer(data));
+        self.base.write_u16(BLK_SIZE, 512);
+        result?;
+
+        // Wait for data transfer to complete
+        self.wait_data_inactive(self.data_timeout)?;
+
+        self.check_err()
+    }
+
+    /// Try to switch to High-Speed mode at 50MHz, returning whether it succeeded.
+    ///
+    /// Falls back to 25MHz if the card misbehaves after switching.
+    /// `sd_spec` is the SD_SPEC field of the SCR.
+    fn enable_high_speed(&mut self, sd_spec: u8) -> bool {
+        let cap = self.base.read_u32(CAPABILITIES);
+        let base_clock = (cap >> 8) & 0b111111;
+        // Controller must support High-Speed and be able to generate 50MHz.
+        if cap & (1 << 21) == 0 || base_clock < 50 {
+            return false;
+        }
+
+        // CMD6 is supported from SD spec 1.10 onwards.
+        if sd_spec == 0 {
+            return false;
+        }
+
+        // Query function group 1 (access mode) for High-Speed support, then switch to it.
+        let mut status = [0; 64];
+        if self
+            .read_short_block(&mut status, |this| this.wait_cmd(6, 0x00FF_FFF1))
+            .is_err()
+            || status[13] & 0b10 == 0
+        {
+            return false;
+        }
+        if self
+            .read_short_block(&mut status, |this| this.wait_cmd(6, 0x80FF_FFF1))
+            .is_err()
+            || status[16] & 0xF != 1
+        {
+            return false;
+        }
+
+        // Card switches within 8 clocks after the status block.
+        sleep(Duration::from_micros(10));
+        self.base.write_u8(HOST_CTRL, 0b110);
+
+        // Verify that transfers work at the new speed.
+        let mut block = [0; 512];
+        if self.set_clock(50000).is_err() || self.read_blocks(0, 1, &mut block).is_err() {
+            println!("SD test read failed at High-Speed");
+            self.base.write_u8(HOST_CTRL, 0b10);
+            // If the clock cannot be set either, the next operation will fail and retry.
+            let _ = self.set_clock(25000);
+            return false;
+        }
+        println!("High-Speed mode enabled");
+        true
+    }
+
+    /// Describe the current bus speed.
+    pub fn bus_speed(&self) -> (&'static str, u32) {
+        let mode = if self.high_speed {
+            "High-Speed"
+        } else {
+            "Default Speed"
+        };
+        (mode, self.clock / 1000)
+    }
+
+    /// Convert a block address to a command argument.
+    ///
+    /// SDHC/SDXC cards are block addressed, while SDSC cards are byte addressed.
+    fn block_arg(&self, block_addr: u64) -> Result<u32, SdError> {
+        let addr = if self.ccs {
+            Some(block_addr)
+        } else {
+            block_addr.checked_mul(512)
+        };
+        addr.and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(SdError::OutOfRange)
+    }
+
     pub fn read_blocks(
         &mut self,
-        block_addr: u32,
+        block_addr: u64,
         block_cnt: u16,
         data: &mut [u8],
-    ) -> Result<(), u16> {
+    ) -> Result<(), SdError> {
         assert_ne!(block_cnt, 0);
         assert_eq!(data.len(), block_cnt as usize * 512);
-        let arg = if self.ccs {
-            block_addr
-        } else {
-            block_addr * 512
-        };
+        let arg = self.block_arg(block_addr)?;
 
         if block_cnt != 1 {
             self.base.write_u16(BLK_CNT, block_cnt);
@@ -328,12 +658,7 @@ impl Inner {
         }
 
         // Wait for data transfer to complete
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & 0b10 == 0 {
-                break;
-            }
-        }
+        self.wait_data_inactive(self.data_timeout)?;
 
         self.check_err()?;
         Ok(())
@@ -341,17 +666,13 @@ impl Inner {
 
     pub fn write_blocks(
         &mut self,
-        block_addr: u32,
+        block_addr: u64,
         block_cnt: u16,
         data: &[u8],
-    ) -> Result<(), u16> {
+    ) -> Result<(), SdError> {
         assert_ne!(block_cnt, 0);
         assert_eq!(data.len(), block_cnt as usize * 512);
-        let arg = if self.ccs {
-            block_addr
-        } else {
-            block_addr * 512
-        };
+        let arg = self.block_arg(block_addr)?;
 
         if block_cnt != 1 {
             self.base.write_u16(BLK_CNT, block_cnt);
@@ -367,19 +688,82 @@ impl Inner {
         }
 
         // Wait for data transfer to complete
-        loop {
-            let state = self.base.read_u32(PRESENT_STATE);
-            if state & 0b10 == 0 {
-                break;
-            }
-        }
+        self.wait_data_inactive(self.data_timeout)?;
 
         self.check_err()?;
         Ok(())
     }
+
+    /// Wait for the card to finish programming, after which written data is persistent.
+    pub fn wait_ready(&mut self) -> Result<(), SdError> {
+        let rca = self.rca;
+        self.wait_until("card ready", self.data_timeout, |this| {
+            let status = this.wait_cmd(13, (rca as u32) << 16)?;
+            // READY_FOR_DATA set and card not in programming state.
+            Ok(status & (1 << 8) != 0 && (status >> 9) & 0xF != 7)
+        })
+    }
+
+    /// Erase `block_cnt` blocks starting from `block_addr`.
+    ///
+    /// Erased blocks read back as all zeros or all ones depending on the card.
+    pub fn erase_blocks(&mut self, block_addr: u64, block_cnt: u64) -> Result<(), SdError> {
+        assert_ne!(block_cnt, 0);
+        let start = self.block_arg(block_addr)?;
+        let end = self.block_arg(block_addr + block_cnt - 1)?;
+
+        self.wait_cmd(32, start)?;
+        self.wait_cmd(33, end)?;
+        self.wait_cmd(38, 0)?;
+
+        // Wait for busy to deassert
+        self.wait_data_inactive(ERASE_TIMEOUT)?;
+        self.wait_ready()
+    }
 }
 
-pub struct Sd(pub Mutex<Inner>);
+/// Extract bits `hi..=lo` of a CSD or CID register.
+fn bits(reg: u128, hi: u32, lo: u32) -> u64 {
+    ((reg >> lo) & ((1 << (hi - lo + 1)) - 1)) as u64
+}
+
+/// Decode the card capacity in bytes from the CSD register.
+fn csd_capacity(csd: u128) -> Option<u64> {
+    match bits(csd, 127, 126) {
+        // CSD version 1.0, used by SDSC cards.
+        0 => {
+            let read_bl_len = bits(csd, 83, 80);
+            let c_size = bits(csd, 73, 62);
+            let c_size_mult = bits(csd, 49, 47);
+            Some((c_size + 1) << (c_size_mult + 2) << read_bl_len)
+        }
+        // CSD version 2.0 (SDHC/SDXC) and 3.0 (SDUC), in units of 512KiB.
+        1 => Some((bits(csd, 69, 48) + 1) << 19),
+        2 => Some((bits(csd, 75, 48) + 1) << 19),
+        _ => None,
+    }
+}
+
+fn print_cid(cid: u128) {
+    let mut name = [0; 5];
+    for (i, c) in name.iter_mut().enumerate() {
+        *c = bits(cid, 103 - i as u32 * 8, 96 - i as u32 * 8) as u8;
+    }
+    let oem = [bits(cid, 119, 112) as u8, bits(cid, 111, 104) as u8];
+    println!(
+        "SD card: manufacturer {:02x}, OEM {}, name {}, revision {}.{}, serial {:08x}, date {}/{:02}",
+        bits(cid, 127, 120),
+        core::str::from_utf8(&oem).unwrap_or("??"),
+        core::str::from_utf8(&name).unwrap_or("?????"),
+        bits(cid, 63, 60),
+        bits(cid, 59, 56),
+        bits(cid, 55, 24),
+        bits(cid, 19, 12) + 2000,
+        bits(cid, 11, 8),
+    );
+}
+
+pub struct Sd(Mutex<Inner>);
 
 impl Drop for Sd {
     fn drop(&mut self) {
@@ -388,12 +772,40 @@ impl Drop for Sd {
 }
 
 impl Sd {
-    pub const unsafe fn new(base: usize) -> Self {
-        Sd(Mutex::new(Inner::new(base)))
+    pub const unsafe fn new(base: usize, irq: Option<u32>) -> Self {
+        Sd(Mutex::new(Inner::new(base, irq)))
+    }
+
+    pub fn power_on(&self) -> IoResult<()> {
+        self.0.lock().power_on()?;
+        Ok(())
+    }
+
+    /// Run `op`, reinitialising the card at a lower clock and retrying if it fails.
+    fn retry<T>(&self, mut op: impl FnMut(&mut Inner) -> Result<T, SdError>) -> IoResult<T> {
+        let mut inner = self.0.lock();
+        let mut result = op(&mut inner);
+        for _ in 0..RETRIES {
+            match result {
+                Ok(v) => return Ok(v),
+                Err(SdError::NoCard | SdError::OutOfRange) => break,
+                Err(err) => println!("SD error: {:?}, reinitializing", err),
+            }
+            inner.max_clock = core::cmp::max(inner.max_clock / 2, 400);
+            inner.power_off();
+            result = inner.power_on().and_then(|()| op(&mut inner));
+        }
+        Ok(result?)
+    }
+
+    /// Bus speed mode and clock in MHz.
+    pub fn bus_speed(&self) -> (&'static str, u32) {
+        self.0.lock().bus_speed()
     }
 
-    pub fn power_on(&self) {
-        self.0.lock().power_on();
+    /// Print the host controller registers, for debugging.
+    pub fn dump_registers(&self) {
+        self.0.lock().dump_core()
     }
 }
 
@@ -402,22 +814,67 @@ impl super::Block for Sd {
         assert_eq!(offset % 512, 0, "offset must be sector-aligned");
         assert_eq!(buffer.len() % 512, 0, "buffer size must be sector-aligned");
 
-        let count = buffer.len() / 512;
-        let sector_id = (offset / 512) as u32;
+        // Split into transfers that fit in BLK_CNT, each retried individually.
+        let mut sector_id = offset / 512;
+        for chunk in buffer.chunks_mut(MAX_BLOCKS * 512) {
+            let count = (chunk.len() / 512) as u16;
+            self.retry(|inner| inner.read_blocks(sector_id, count, chunk))?;
+            sector_id += count as u64;
+        }
+        Ok(())
+    }
 
-        assert!(count <= 65536);
-        let mut inner = self.0.lock();
-        inner
-            .read_blocks(sector_id as u32, count as u16, buffer)
-            .unwrap();
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> IoResult<()> {
+        assert_eq!(offset % 512, 0, "offset must be sector-aligned");
+        assert_eq!(buffer.len() % 512, 0, "buffer size must be sector-aligned");
+
+        let mut sector_id = offset / 512;
+        for chunk in buffer.chunks(MAX_BLOCKS * 512) {
+            let count = (chunk.len() / 512) as u16;
+            self.retry(|inner| inner.write_blocks(sector_id, count, chunk))?;
+            sector_id += count as u64;
+        }
         Ok(())
     }
 
-    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> IoResult<()> {
-        unimplemented!();
+    fn write_zero_at(&self, offset: u64, len: usize) -> IoResult<()> {
+        // Write in chunks to bound the size of the zero buffer.
+        const CHUNK: usize = 64 * 1024;
+        let buf = vec![0; core::cmp::min(len, CHUNK)];
+        let mut done = 0;
+        while done < len {
+            let size = core::cmp::min(len - done, CHUNK);
+            self.write_all_at(&buf[..size], offset + done as u64)?;
+            done += size;
+        }
+        Ok(())
+    }
+
+    /// Erase blocks, which then read back as all 0s or all 1s depending on the card, as given by
+    /// `discard_fill` in [`capability`](Self::capability).
+    fn discard(&self, offset: u64, len: usize) -> IoResult<()> {
+        assert_eq!(offset % 512, 0, "offset must be sector-aligned");
+        assert_eq!(len % 512, 0, "length must be sector-aligned");
+        if len == 0 {
+            return Ok(());
+        }
+
+        self.retry(|inner| inner.erase_blocks(offset / 512, (len / 512) as u64))
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        self.retry(|inner| inner.wait_ready())
     }
 
     fn len(&self) -> u64 {
-        todo!();
+        self.0.lock().capacity
+    }
+
+    fn capability(&self) -> super::Capability {
+        super::Capability {
+            discard: true,
+            discard_fill: self.0.lock().erase_fill,
+            ..Default::default()
+        }
     }
 }
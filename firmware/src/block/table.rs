@@ -0,0 +1,334 @@
+//! Partition tables.
+//!
+//! Both MBR (with logical partitions in an extended partition) and GPT are supported. GPT headers
+//! and entry arrays are verified with their CRC-32, falling back to the backup copy at the end of
+//! the disk if the primary one is damaged or cannot be read.
+
+use super::{Block, Part};
+use crate::crc::crc32;
+use crate::io::{Error as IoError, Result as IoResult};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LE};
+use core::fmt;
+
+const SECTOR_SIZE: u64 = 512;
+
+const MBR_EXTENDED: &[u8] = &[0x05, 0x0F, 0x85];
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_LINUX: u8 = 0x83;
+/// Upper bound on the number of logical partitions, in case the EBR chain loops.
+const MAX_LOGICAL: u32 = 128;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Upper bound on the size of the GPT entry array, to reject corrupted headers.
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+/// A GUID, stored in its on-disk mixed-endian form.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4.
+    pub const LINUX_FS: Guid = Guid([
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            LE::read_u32(&g[0..4]),
+            LE::read_u16(&g[4..6]),
+            LE::read_u16(&g[6..8]),
+            g[8],
+            g[9]
+        )?;
+        for b in &g[10..] {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PartType {
+    /// MBR system ID.
+    Mbr(u8),
+    /// GPT partition type GUID.
+    Gpt(Guid),
+}
+
+impl PartType {
+    /// Whether this partition type is used for Linux filesystems.
+    pub fn is_linux(&self) -> bool {
+        match *self {
+            PartType::Mbr(id) => id == MBR_LINUX,
+            PartType::Gpt(guid) => guid == Guid::LINUX_FS,
+        }
+    }
+}
+
+impl fmt::Display for PartType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartType::Mbr(id) => write!(f, "{:#04x}", id),
+            PartType::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+pub struct Partition {
+    /// Partition number as used by Linux: 1-4 for MBR primary partitions, 5 onwards for logical
+    /// partitions, and the entry index plus one for GPT.
+    pub number: u32,
+    pub ty: PartType,
+    /// Partition name. Always empty for MBR.
+    pub name: String,
+    /// Offset in bytes.
+    pub offset: u64,
+    /// Length in bytes.
+    pub len: u64,
+}
+
+impl Partition {
+    /// Open this partition on the block device it was read from.
+    pub fn open(&self, blk: Arc<dyn Block>) -> Part {
+        Part::new(blk, self.offset, self.len)
+    }
+}
+
+/// Read the partition table of a block device.
+///
+/// Returns an empty list if the device has no partition table.
+pub fn partitions(blk: &dyn Block) -> IoResult<Vec<Partition>> {
+    let mut mbr = [0; 512];
+    blk.read_exact_at(&mut mbr, 0)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Ok(Vec::new());
+    }
+    // A FAT boot sector carries the same signature, with boot code where the entries would be.
+    let boot_indicators_valid = (0..4).all(|i| matches!(mbr[0x1BE + i * 16], 0x00 | 0x80));
+    if !boot_indicators_valid || has_bpb(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|&(ty, _, _)| ty == MBR_PROTECTIVE) {
+        return gpt_partitions(blk);
+    }
+
+    let mut parts = Vec::new();
+    for (i, &(ty, lba, sectors)) in entries.iter().enumerate() {
+        if ty == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&ty) {
+            logical_partitions(blk, lba, &mut parts)?;
+            continue;
+        }
+        push_checked(
+            blk,
+            &mut parts,
+            Partition {
+                number: i as u32 + 1,
+                ty: PartType::Mbr(ty),
+                name: String::new(),
+                offset: lba as u64 * SECTOR_SIZE,
+                len: sectors as u64 * SECTOR_SIZE,
+            },
+        );
+    }
+    // Logical partitions are found before later primary partitions.
+    parts.sort_by_key(|part| part.number);
+    Ok(parts)
+}
+
+/// Whether `sector` is a FAT boot sector with a plausible BPB.
+fn has_bpb(sector: &[u8; 512]) -> bool {
+    let bytes_per_sector = LE::read_u16(&sector[0x0B..0x0D]);
+    let media = sector[0x15];
+    matches!(sector[0], 0xEB | 0xE9)
+        && bytes_per_sector.is_power_of_two()
+        && (512..=4096).contains(&bytes_per_sector)
+        && sector[0x0D].is_power_of_two()
+        && LE::read_u16(&sector[0x0E..0x10]) != 0
+        && sector[0x10] != 0
+        && (media == 0xF0 || media >= 0xF8)
+}
+
+/// Decode the four partition entries of a MBR or EBR into (system ID, start LBA, sector count).
+fn mbr_entries(sector: &[u8; 512]) -> [(u8, u32, u32); 4] {
+    let mut entries = [(0, 0, 0); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let raw = &sector[0x1BE + i * 16..0x1CE + i * 16];
+        *entry = (
+            raw[4],
+            LE::read_u32(&raw[8..12]),
+            LE::read_u32(&raw[12..16]),
+        );
+    }
+    entries
+}
+
+/// Walk the chain of EBRs in the extended partition starting at `extended`.
+fn logical_partitions(blk: &dyn Block, extended: u32, parts: &mut Vec<Partition>) -> IoResult<()> {
+    let mut ebr_lba = extended as u64;
+    for number in 5..5 + MAX_LOGICAL {
+        let mut ebr = [0; 512];
+        blk.read_exact_at(&mut ebr, ebr_lba * SECTOR_SIZE)?;
+        if ebr[510] != 0x55 || ebr[511] != 0xAA {
+            return Err(IoError::Textual("EBR not valid"));
+        }
+
+        let entries = mbr_entries(&ebr);
+        // The first entry is relative to this EBR, the link to the next EBR is relative to the
+        // extended partition.
+        let (ty, lba, sectors) = entries[0];
+        if ty != 0 {
+            push_checked(
+                blk,
+                parts,
+                Partition {
+                    number,
+                    ty: PartType::Mbr(ty),
+                    name: String::new(),
+                    offset: (ebr_lba + lba as u64) * SECTOR_SIZE,
+                    len: sectors as u64 * SECTOR_SIZE,
+                },
+            );
+        }
+
+        let (next_ty, next_lba, _) = entries[1];
+        if next_ty == 0 || next_lba == 0 {
+            return Ok(());
+        }
+        ebr_lba = extended as u64 + next_lba as u64;
+    }
+    Err(IoError::Textual("too many logical partitions"))
+}
+
+fn gpt_partitions(blk: &dyn Block) -> IoResult<Vec<Partition>> {
+    let last_lba = (blk.len() / SECTOR_SIZE).saturating_sub(1);
+    // A primary copy that cannot be read is treated like a damaged one.
+    let primary = gpt_read(blk, 1).unwrap_or_else(|err| {
+        println!("Cannot read primary GPT: {:?}", err);
+        None
+    });
+    let (header, entries) = match primary {
+        Some(v) => v,
+        None => {
+            println!("Primary GPT not valid, using backup");
+            gpt_read(blk, last_lba)?.ok_or(IoError::Textual("GPT not valid"))?
+        }
+    };
+
+    let entry_size = LE::read_u32(&header[0x54..0x58]) as usize;
+    let mut parts = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let mut ty = [0; 16];
+        ty.copy_from_slice(&entry[0..16]);
+        if ty == [0; 16] {
+            continue;
+        }
+        let first = LE::read_u64(&entry[32..40]);
+        let last = LE::read_u64(&entry[40..48]);
+        let range = last
+            .checked_sub(first)
+            .and_then(|x| x.checked_add(1))
+            .and_then(|x| x.checked_mul(SECTOR_SIZE))
+            .zip(first.checked_mul(SECTOR_SIZE));
+        let (len, offset) = match range {
+            Some(range) => range,
+            None => {
+                println!("GPT entry {} has invalid range, ignored", i + 1);
+                continue;
+            }
+        };
+
+        let name = char::decode_utf16(
+            entry[56..128]
+                .chunks_exact(2)
+                .map(LE::read_u16)
+                .take_while(|&c| c != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+
+        push_checked(
+            blk,
+            &mut parts,
+            Partition {
+                number: i as u32 + 1,
+                ty: PartType::Gpt(Guid(ty)),
+                name,
+                offset,
+                len,
+            },
+        );
+    }
+    Ok(parts)
+}
+
+/// Read and verify the GPT header at `lba` and its entry array.
+///
+/// Returns `None` if either fails verification.
+fn gpt_read(blk: &dyn Block, lba: u64) -> IoResult<Option<([u8; 512], Vec<u8>)>> {
+    let mut header = [0; 512];
+    blk.read_exact_at(&mut header, lba * SECTOR_SIZE)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = LE::read_u32(&header[0x0C..0x10]) as usize;
+    if !(92..=512).contains(&header_size) {
+        return Ok(None);
+    }
+    let header_crc = LE::read_u32(&header[0x10..0x14]);
+    let mut copy = header;
+    copy[0x10..0x14].fill(0);
+    if crc32(0, &copy[..header_size]) != header_crc {
+        return Ok(None);
+    }
+
+    let entries_lba = LE::read_u64(&header[0x48..0x50]);
+    let num_entries = LE::read_u32(&header[0x50..0x54]) as usize;
+    let entry_size = LE::read_u32(&header[0x54..0x58]) as usize;
+    let entries_crc = LE::read_u32(&header[0x58..0x5C]);
+    if entry_size < 128 || entry_size % 8 != 0 {
+        return Ok(None);
+    }
+    let entries_size = match num_entries.checked_mul(entry_size) {
+        Some(size) if size <= GPT_MAX_ENTRIES_SIZE => size,
+        _ => return Ok(None),
+    };
+
+    let entries_offset = match entries_lba.checked_mul(SECTOR_SIZE) {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    let mut entries = vec![0; (entries_size + 511) & !511];
+    blk.read_exact_at(&mut entries, entries_offset)?;
+    entries.truncate(entries_size);
+    if crc32(0, &entries) != entries_crc {
+        return Ok(None);
+    }
+    Ok(Some((header, entries)))
+}
+
+/// Add a partition, unless it extends beyond the end of the device.
+fn push_checked(blk: &dyn Block, parts: &mut Vec<Partition>, part: Partition) {
+    match part.offset.checked_add(part.len) {
+        Some(end) if end <= blk.len() => parts.push(part),
+        _ => println!(
+            "Partition {} extends beyond end of disk, ignored",
+            part.number
+        ),
+    }
+}
@@ -0,0 +1,94 @@
+//! CRC checksums.
+
+const fn make_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_table(0xEDB88320);
+
+/// Update a CRC-32 (as used by gzip, Ethernet and GPT) with more data.
+///
+/// Start with `crc = 0` for a new checksum.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+static CRC32C_TABLE: [u32; 256] = make_table(0x82F63B78);
+
+/// Update a CRC-32C (Castagnoli, as used by ext4 and iSCSI) with more data.
+///
+/// Start with `crc = 0` for a new checksum.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// CRC-16/ARC is reflected, so it shares the table generator with CRC-32.
+static CRC16_ARC_TABLE: [u32; 256] = make_table(0xA001);
+
+/// Update a CRC-16/ARC (as used by ext4 group descriptors without metadata_csum) with more data.
+///
+/// Start with `crc = 0` for a new checksum.
+pub fn crc16_arc(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for &byte in data {
+        crc = CRC16_ARC_TABLE[((crc ^ byte as u16) & 0xFF) as usize] as u16 ^ (crc >> 8);
+    }
+    crc
+}
+
+const fn make_table16(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC16_TABLE: [u16; 256] = make_table16(0x1021);
+
+/// Update a CRC-16/XMODEM (as used by XMODEM and YMODEM) with more data.
+///
+/// Start with `crc = 0` for a new checksum.
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for &byte in data {
+        crc = CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize] ^ (crc << 8);
+    }
+    crc
+}
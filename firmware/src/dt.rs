@@ -0,0 +1,133 @@
+//! Device tree fixups.
+//!
+//! These are applied to the device tree right before control is transferred to the kernel, so
+//! that it reflects what the firmware has probed and configured at runtime.
+
+use crate::fdt::Fdt;
+
+static BUILTIN_DTB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/device_tree.dtb"));
+
+#[derive(Debug)]
+pub enum Error {
+    NoMemoryNode,
+}
+
+/// Check that a user-supplied device tree describes this platform.
+fn check_compatible(fdt: &Fdt, builtin: &Fdt) -> bool {
+    fdt.root()
+        .property_strs("compatible")
+        .any(|compatible| builtin.root().is_compatible(compatible))
+}
+
+/// Load the device tree to hand over to the kernel.
+///
+/// A user-supplied device tree is used if it is valid and compatible with the platform, otherwise
+/// the built-in one is used.
+pub fn load(user: Option<&[u8]>) -> Fdt {
+    let builtin = Fdt::new(BUILTIN_DTB).unwrap();
+    let mut fdt = match user.map(Fdt::new) {
+        None => builtin,
+        Some(Ok(fdt)) if check_compatible(&fdt, &builtin) => {
+            println!("Using device tree from boot filesystem");
+            fdt
+        }
+        Some(Ok(_)) => {
+            println!("Device tree is not compatible with this platform, using built-in one");
+            builtin
+        }
+        Some(Err(err)) => {
+            println!("Device tree is invalid ({:?}), using built-in one", err);
+            builtin
+        }
+    };
+    strip_firmware_devices(&mut fdt);
+    fdt
+}
+
+/// Remove devices that are managed by the firmware and must not be touched by the kernel.
+pub fn strip_firmware_devices(fdt: &mut Fdt) {
+    // CLINT is accessed through SBI, and the display controller is handed over to the kernel as a
+    // simple framebuffer.
+    fdt.root_mut().remove_nodes(|node| {
+        node.is_compatible("sifive,clint0") || node.is_compatible("garyguo,display-controller")
+    });
+}
+
+/// Set the memory node to cover memory available to the kernel.
+pub fn fixup_memory(fdt: &mut Fdt, base: usize, size: usize) -> Result<(), Error> {
+    let root = fdt.root_mut();
+    let (address_cells, size_cells) = (root.address_cells(), root.size_cells());
+    let memory = root
+        .children
+        .iter_mut()
+        .find(|node| node.property_str("device_type") == Some("memory"))
+        .ok_or(Error::NoMemoryNode)?;
+    memory.set_reg(address_cells, size_cells, &[(base as u64, size as u64)]);
+    Ok(())
+}
+
+/// Disable CPU nodes for harts that are not present.
+pub fn fixup_cpus(fdt: &mut Fdt, hart_count: usize) {
+    let cpus = match fdt.node_mut("/cpus") {
+        Some(v) => v,
+        None => return,
+    };
+    for cpu in cpus.children.iter_mut() {
+        if cpu.property_str("device_type") != Some("cpu") {
+            continue;
+        }
+        match cpu.property_u32("reg") {
+            Some(hartid) if (hartid as usize) < hart_count => (),
+            _ => {
+                info!("Disabling {} in device tree", cpu.name);
+                cpu.set_str("status", "disabled");
+            }
+        }
+    }
+}
+
+pub fn set_bootargs(fdt: &mut Fdt, bootargs: &str) {
+    fdt.root_mut()
+        .child_or_insert("chosen")
+        .set_str("bootargs", bootargs);
+}
+
+pub fn set_initrd(fdt: &mut Fdt, start: usize, end: usize) {
+    let chosen = fdt.root_mut().child_or_insert("chosen");
+    chosen.set_u64("linux,initrd-start", start as u64);
+    chosen.set_u64("linux,initrd-end", end as u64);
+}
+
+/// Describe the framebuffer set up by the firmware as a simple framebuffer.
+///
+/// An existing `simple-framebuffer` node is updated. Otherwise one is inserted under `/chosen`,
+/// and the framebuffer memory is reserved so the kernel does not allocate it.
+#[cfg(has_display)]
+pub fn fixup_framebuffer(fdt: &mut Fdt, info: &crate::video::FramebufferInfo) {
+    let size = (info.stride * info.height) as u64;
+    if fdt.find_compatible("simple-framebuffer").is_none() {
+        let root = fdt.root_mut();
+        let (address_cells, size_cells) = (root.address_cells(), root.size_cells());
+        let chosen = root.child_or_insert("chosen");
+        // Children of /chosen are addressed like those of the root.
+        if chosen.property("ranges").is_none() {
+            chosen.set_property("ranges", &[]);
+            chosen.set_u32("#address-cells", address_cells);
+            chosen.set_u32("#size-cells", size_cells);
+        }
+        let framebuffer = chosen.child_or_insert(&alloc::format!("framebuffer@{:x}", info.base));
+        framebuffer.set_str("compatible", "simple-framebuffer");
+        framebuffer.set_str("status", "okay");
+        fdt.add_reserved(info.base as u64, size);
+    }
+
+    let (framebuffer, address_cells, size_cells) = fdt
+        .root_mut()
+        .find_by_mut_with_cells(|node| node.is_compatible("simple-framebuffer"))
+        .unwrap();
+    framebuffer.set_reg(address_cells, size_cells, &[(info.base as u64, size)]);
+    framebuffer.set_u32("width", info.width);
+    framebuffer.set_u32("height", info.height);
+    framebuffer.set_u32("stride", info.stride);
+    framebuffer.set_str("format", info.format);
+}
@@ -9,6 +9,7 @@ type Elf64_Xword = u64;
 type Elf64_Sxword = i64;
 
 const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
 
 #[repr(C)]
 struct Elf64_Ehdr {
@@ -40,6 +41,46 @@ struct Elf64_Phdr {
     pub p_align: Elf64_Xword,
 }
 
+#[repr(C)]
+struct Elf64_Dyn {
+    pub d_tag: Elf64_Sxword,
+    pub d_val: Elf64_Xword,
+}
+
+#[repr(C)]
+struct Elf64_Rela {
+    pub r_offset: Elf64_Addr,
+    pub r_info: Elf64_Xword,
+    pub r_addend: Elf64_Sxword,
+}
+
+#[repr(C)]
+struct Elf64_Sym {
+    pub st_name: Elf64_Word,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: Elf64_Half,
+    pub st_value: Elf64_Addr,
+    pub st_size: Elf64_Xword,
+}
+
+const DT_NULL: Elf64_Sxword = 0;
+const DT_PLTRELSZ: Elf64_Sxword = 2;
+const DT_SYMTAB: Elf64_Sxword = 6;
+const DT_RELA: Elf64_Sxword = 7;
+const DT_RELASZ: Elf64_Sxword = 8;
+const DT_RELAENT: Elf64_Sxword = 9;
+const DT_SYMENT: Elf64_Sxword = 11;
+const DT_JMPREL: Elf64_Sxword = 23;
+
+const SHN_UNDEF: Elf64_Half = 0;
+const STB_WEAK: u8 = 2;
+
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_64: u32 = 2;
+const R_RISCV_RELATIVE: u32 = 3;
+const R_RISCV_JUMP_SLOT: u32 = 5;
+
 const PF_R: u32 = 0x4;
 const PF_W: u32 = 0x2;
 const PF_X: u32 = 0x1;
@@ -47,9 +88,43 @@ const ET_EXEC: Elf64_Half = 2;
 const ET_DYN: Elf64_Half = 3;
 const EM_RISCV: Elf64_Half = 243;
 
-#[repr(C)]
-struct Loader {
-    memory: *const u8,
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file does not start with the ELF magic.
+    NotElf,
+    /// The file is not a 64-bit little-endian ELF.
+    UnsupportedClass,
+    /// The file is neither an executable nor a dynamic binary.
+    NotExecutable,
+    /// The file is not for RISC-V.
+    WrongMachine,
+    /// A header or segment extends past the end of the file.
+    Truncated,
+    /// A program header is malformed.
+    InvalidSegment,
+    /// There is nothing to load.
+    NoLoadableSegment,
+    /// The image does not fit in the memory window.
+    TooLarge,
+    /// The image would overwrite the file being loaded.
+    OverlapsFile,
+    /// Relocation tables or targets lie outside the loaded image.
+    InvalidRelocation,
+    UnsupportedRelocation(u32),
+    UndefinedSymbol,
+    /// The entry point lies outside the loaded image.
+    InvalidEntry,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+struct Loader<'a> {
+    file: &'a [u8],
 }
 
 struct PhdrIter<'a> {
@@ -72,9 +147,27 @@ impl<'a> Iterator for PhdrIter<'a> {
     }
 }
 
-impl Loader {
+/// Location of the loaded image in memory.
+struct Image {
+    start: u64,
+    end: u64,
+    bias: u64,
+}
+
+impl Image {
+    /// Translate a virtual address range into a pointer to loaded memory.
+    fn translate(&self, vaddr: u64, len: u64) -> Result<usize> {
+        let addr = vaddr.wrapping_add(self.bias);
+        match addr.checked_add(len) {
+            Some(end) if addr >= self.start && end <= self.end => Ok(addr as usize),
+            _ => Err(Error::InvalidRelocation),
+        }
+    }
+}
+
+impl<'a> Loader<'a> {
     fn ehdr(&self) -> &Elf64_Ehdr {
-        unsafe { &*(self.memory as *const Elf64_Ehdr) }
+        unsafe { &*(self.file.as_ptr() as *const Elf64_Ehdr) }
     }
 
     fn phdr(&self) -> PhdrIter {
@@ -84,53 +177,176 @@ impl Loader {
         }
     }
 
-    pub fn new(ptr: *const u8) -> Loader {
+    pub fn new(file: &'a [u8]) -> Result<Loader<'a>> {
         // Must be properly aligned.
-        assert!(ptr as usize % 8 == 0);
-        Loader { memory: ptr }
-    }
-
-    unsafe fn load_kernel(&self, load_addr: u64) -> u64 {
-        let header = self.ehdr();
+        assert!(file.as_ptr() as usize % 8 == 0);
 
-        // Check the ELF magic numbers
-        if &header.e_ident[0..4] != "\x7FELF".as_bytes() {
-            panic!("Not ELF file")
+        if file.len() < core::mem::size_of::<Elf64_Ehdr>() || &file[0..4] != b"\x7FELF" {
+            return Err(Error::NotElf);
+        }
+        if file[EI_CLASS] != ELFCLASS64 || file[EI_DATA] != ELFDATA2LSB {
+            return Err(Error::UnsupportedClass);
         }
 
+        let loader = Loader { file };
+        let header = loader.ehdr();
+
         // We can only proceed with executable or dynamic binary.
         if header.e_type != ET_EXEC && header.e_type != ET_DYN {
-            panic!("the binary is not executable.");
+            return Err(Error::NotExecutable);
         }
 
         // Check that the ELF is for RISC-V
         if header.e_machine != EM_RISCV {
-            panic!("the binary is not for RISC-V.");
+            return Err(Error::WrongMachine);
+        }
+
+        // Check that program headers are within the file.
+        if header.e_phnum != 0 {
+            if (header.e_phentsize as usize) < core::mem::size_of::<Elf64_Phdr>()
+                || header.e_phoff % 8 != 0
+            {
+                return Err(Error::InvalidSegment);
+            }
+            let end = (header.e_phnum as u64 * header.e_phentsize as u64)
+                .checked_add(header.e_phoff)
+                .ok_or(Error::Truncated)?;
+            if end > file.len() as u64 {
+                return Err(Error::Truncated);
+            }
         }
 
-        // Scan the bounds of the image.
+        Ok(loader)
+    }
+
+    /// Apply dynamic relocations to an image already loaded.
+    unsafe fn relocate(&self, image: &Image, dynamic: u64) -> Result<()> {
+        let mut rela = None;
+        let mut relasz = 0;
+        let mut relaent = core::mem::size_of::<Elf64_Rela>() as u64;
+        let mut jmprel = None;
+        let mut pltrelsz = 0;
+        let mut symtab = None;
+        let mut syment = core::mem::size_of::<Elf64_Sym>() as u64;
+
+        let dyn_size = core::mem::size_of::<Elf64_Dyn>() as u64;
+        for i in 0.. {
+            let entry = &*(image.translate(dynamic + i * dyn_size, dyn_size)? as *const Elf64_Dyn);
+            match entry.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(entry.d_val),
+                DT_RELASZ => relasz = entry.d_val,
+                DT_RELAENT => relaent = entry.d_val,
+                DT_JMPREL => jmprel = Some(entry.d_val),
+                DT_PLTRELSZ => pltrelsz = entry.d_val,
+                DT_SYMTAB => symtab = Some(entry.d_val),
+                DT_SYMENT => syment = entry.d_val,
+                _ => (),
+            }
+        }
+
+        if relaent < core::mem::size_of::<Elf64_Rela>() as u64
+            || syment < core::mem::size_of::<Elf64_Sym>() as u64
+        {
+            return Err(Error::InvalidRelocation);
+        }
+
+        let symbol = |index: u64| -> Result<u64> {
+            let symtab = symtab.ok_or(Error::InvalidRelocation)?;
+            let sym = &*(image.translate(symtab + index * syment, syment)? as *const Elf64_Sym);
+            if sym.st_shndx != SHN_UNDEF {
+                Ok(sym.st_value.wrapping_add(image.bias))
+            } else if sym.st_info >> 4 == STB_WEAK {
+                Ok(0)
+            } else {
+                Err(Error::UndefinedSymbol)
+            }
+        };
+
+        for (start, size) in [(rela, relasz), (jmprel, pltrelsz)] {
+            let start = match start {
+                Some(v) => image.translate(v, size)? as u64,
+                None => continue,
+            };
+            for i in 0..size / relaent {
+                let rela = &*((start + i * relaent) as usize as *const Elf64_Rela);
+                let value = match rela.r_info as u32 {
+                    R_RISCV_NONE => continue,
+                    R_RISCV_RELATIVE => image.bias.wrapping_add(rela.r_addend as u64),
+                    R_RISCV_64 => symbol(rela.r_info >> 32)?.wrapping_add(rela.r_addend as u64),
+                    R_RISCV_JUMP_SLOT => symbol(rela.r_info >> 32)?,
+                    ty => return Err(Error::UnsupportedRelocation(ty)),
+                };
+                let target = image.translate(rela.r_offset, 8)? as *mut u64;
+                target.write_unaligned(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the ELF into memory at `load_addr`, which must stay below `limit`.
+    ///
+    /// Returns the size of memory occupied relative to `load_addr`, and the entry point.
+    unsafe fn load_kernel(&self, load_addr: u64, limit: u64) -> Result<(u64, u64)> {
+        let header = self.ehdr();
+
+        // Scan and validate the bounds of the image.
         let mut loaddr = u64::max_value();
         let mut hiaddr = 0;
         for h in self.phdr() {
             if h.p_type == PT_LOAD {
+                // size in memory cannot be smaller than size in file
+                if h.p_filesz > h.p_memsz {
+                    return Err(Error::InvalidSegment);
+                }
+                match h.p_offset.checked_add(h.p_filesz) {
+                    Some(end) if end <= self.file.len() as u64 => (),
+                    _ => return Err(Error::Truncated),
+                }
+                let end = h
+                    .p_vaddr
+                    .checked_add(h.p_memsz)
+                    .ok_or(Error::InvalidSegment)?;
+
                 loaddr = core::cmp::min(loaddr, h.p_vaddr);
-                hiaddr = core::cmp::max(hiaddr, h.p_vaddr + h.p_memsz);
+                hiaddr = core::cmp::max(hiaddr, end);
             }
         }
+        if loaddr > hiaddr {
+            return Err(Error::NoLoadableSegment);
+        }
 
         loaddr &= !4095;
-        hiaddr = (hiaddr + 4095) & !4095;
+        hiaddr = hiaddr.checked_add(4095).ok_or(Error::InvalidSegment)? & !4095;
+
+        let size = hiaddr - loaddr;
+        if size > limit.saturating_sub(load_addr) {
+            return Err(Error::TooLarge);
+        }
+
+        // The file itself must not be overwritten while it is being loaded.
+        let file_start = self.file.as_ptr() as u64;
+        let file_end = file_start + self.file.len() as u64;
+        if file_start < load_addr + size && load_addr < file_end {
+            return Err(Error::OverlapsFile);
+        }
+
+        let image = Image {
+            start: load_addr,
+            end: load_addr + size,
+            bias: load_addr.wrapping_sub(loaddr),
+        };
+        let entry = header.e_entry.wrapping_add(image.bias);
+        if entry < image.start || entry >= image.end {
+            return Err(Error::InvalidEntry);
+        }
 
         for h in self.phdr() {
             if h.p_type == PT_LOAD {
-                // size in memory cannot be smaller than size in file
-                if h.p_filesz > h.p_memsz {
-                    panic!("invalid elf file: constraint p_filesz <= p_memsz is not satisified");
-                }
-
                 // Copy across.
                 core::ptr::copy_nonoverlapping(
-                    (self.memory as usize + h.p_offset as usize) as *const u8,
+                    self.file[h.p_offset as usize..].as_ptr(),
                     (h.p_vaddr - loaddr + load_addr) as usize as *mut u8,
                     h.p_filesz as usize,
                 );
@@ -144,10 +360,23 @@ impl Loader {
             }
         }
 
-        hiaddr - loaddr
+        // Executables are linked for a fixed address and do not need relocation.
+        if header.e_type == ET_DYN {
+            for h in self.phdr() {
+                if h.p_type == PT_DYNAMIC {
+                    self.relocate(&image, h.p_vaddr)?;
+                }
+            }
+        }
+
+        Ok((size, entry))
     }
 }
 
-pub unsafe fn load_elf(slice: &[u8], addr: usize) -> usize {
-    Loader::new(slice.as_ptr()).load_kernel(addr as _) as _
+/// Load an ELF file into memory at `addr`, which must stay below `limit`.
+///
+/// Returns the size of memory occupied relative to `addr`, and the entry point.
+pub unsafe fn load_elf(slice: &[u8], addr: usize, limit: usize) -> Result<(usize, usize)> {
+    let (size, entry) = Loader::new(slice)?.load_kernel(addr as _, limit as _)?;
+    Ok((size as _, entry as _))
 }
@@ -0,0 +1,514 @@
+//! Flattened device tree editor.
+//!
+//! The blob is unflattened into an owned tree, edited in place and then flattened again when it
+//! is handed over. Device trees are only a few KiB, so the simplicity is well worth the copies.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BE};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+
+#[derive(Debug)]
+pub enum Error {
+    BadMagic,
+    BadVersion,
+    Truncated,
+    BadStructure,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.into(),
+            properties: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|node| node.name == name)
+    }
+
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        self.children.iter_mut().find(|node| node.name == name)
+    }
+
+    /// Get a child node with given name, creating an empty one if it does not exist.
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|node| node.name == name) {
+            Some(idx) => &mut self.children[idx],
+            None => {
+                self.children.push(Node::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Remove a child node with given name.
+    pub fn remove_child(&mut self, name: &str) -> Option<Node> {
+        let idx = self.children.iter().position(|node| node.name == name)?;
+        Some(self.children.remove(idx))
+    }
+
+    /// Remove all descendant nodes for which `f` returns true.
+    pub fn remove_nodes<F: FnMut(&Node) -> bool>(&mut self, mut f: F) -> usize {
+        fn remove<F: FnMut(&Node) -> bool>(node: &mut Node, f: &mut F) -> usize {
+            let len = node.children.len();
+            node.children.retain(|child| !f(child));
+            let mut removed = len - node.children.len();
+            for child in node.children.iter_mut() {
+                removed += remove(child, f);
+            }
+            removed
+        }
+        remove(self, &mut f)
+    }
+
+    /// Find a descendant node using a path relative to this node, e.g. `cpus/cpu@0`.
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(self, |node, name| node.child(name))
+    }
+
+    pub fn find_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(self, |node, name| node.child_mut(name))
+    }
+
+    /// Find the first node in pre-order (including this node) for which `f` returns true.
+    pub fn find_by<F: FnMut(&Node) -> bool>(&self, mut f: F) -> Option<&Node> {
+        fn find<'a, F: FnMut(&Node) -> bool>(node: &'a Node, f: &mut F) -> Option<&'a Node> {
+            if f(node) {
+                return Some(node);
+            }
+            node.children.iter().find_map(|child| find(child, f))
+        }
+        find(self, &mut f)
+    }
+
+    pub fn find_by_mut<F: FnMut(&Node) -> bool>(&mut self, mut f: F) -> Option<&mut Node> {
+        fn find<'a, F: FnMut(&Node) -> bool>(
+            node: &'a mut Node,
+            f: &mut F,
+        ) -> Option<&'a mut Node> {
+            if f(node) {
+                return Some(node);
+            }
+            node.children.iter_mut().find_map(|child| find(child, f))
+        }
+        find(self, &mut f)
+    }
+
+    /// Like `find_by_mut`, but only searches descendants and also returns the `#address-cells`
+    /// and `#size-cells` of the parent, which are needed to interpret `reg` of the node found.
+    pub fn find_by_mut_with_cells<F: FnMut(&Node) -> bool>(
+        &mut self,
+        mut f: F,
+    ) -> Option<(&mut Node, u32, u32)> {
+        fn find<'a, F: FnMut(&Node) -> bool>(
+            node: &'a mut Node,
+            f: &mut F,
+        ) -> Option<(&'a mut Node, u32, u32)> {
+            let (address_cells, size_cells) = (node.address_cells(), node.size_cells());
+            for child in node.children.iter_mut() {
+                if f(child) {
+                    return Some((child, address_cells, size_cells));
+                }
+                if let Some(v) = find(child, f) {
+                    return Some(v);
+                }
+            }
+            None
+        }
+        find(self, &mut f)
+    }
+
+    /// Name of the node with the unit address stripped.
+    pub fn base_name(&self) -> &str {
+        self.name.split('@').next().unwrap()
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|prop| prop.name == name)
+            .map(|prop| &prop.value[..])
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        let value = self.property(name)?;
+        if value.len() != 4 {
+            return None;
+        }
+        Some(BE::read_u32(value))
+    }
+
+    pub fn property_str(&self, name: &str) -> Option<&str> {
+        read_str(self.property(name)?).ok()
+    }
+
+    /// Iterate over a string list property, e.g. `compatible`.
+    pub fn property_strs<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+        self.property(name)
+            .unwrap_or(&[])
+            .split(|&x| x == 0)
+            .filter(|x| !x.is_empty())
+            .filter_map(|x| core::str::from_utf8(x).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property_strs("compatible").any(|x| x == compatible)
+    }
+
+    /// Value of `#address-cells` that applies to children of this node.
+    pub fn address_cells(&self) -> u32 {
+        self.property_u32("#address-cells").unwrap_or(2)
+    }
+
+    /// Value of `#size-cells` that applies to children of this node.
+    pub fn size_cells(&self) -> u32 {
+        self.property_u32("#size-cells").unwrap_or(1)
+    }
+
+    /// Decode the `reg` property of this node, given the cell sizes from its parent.
+    pub fn reg(&self, address_cells: u32, size_cells: u32) -> Option<Vec<(u64, u64)>> {
+        let value = self.property("reg")?;
+        let stride = (address_cells.checked_add(size_cells)? as usize).checked_mul(4)?;
+        if stride == 0 || value.len() % stride != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks_exact(stride)
+                .map(|chunk| {
+                    let (address, size) = chunk.split_at(address_cells as usize * 4);
+                    (read_cells(address), read_cells(size))
+                })
+                .collect(),
+        )
+    }
+
+    /// Set the value of a property, adding the property if it does not exist.
+    pub fn set_property(&mut self, name: &str, value: &[u8]) {
+        match self.properties.iter_mut().find(|prop| prop.name == name) {
+            Some(prop) => {
+                prop.value.clear();
+                prop.value.extend_from_slice(value);
+            }
+            None => self.properties.push(Property {
+                name: name.into(),
+                value: value.into(),
+            }),
+        }
+    }
+
+    pub fn remove_property(&mut self, name: &str) -> Option<Vec<u8>> {
+        let idx = self.properties.iter().position(|prop| prop.name == name)?;
+        Some(self.properties.remove(idx).value)
+    }
+
+    pub fn set_u32(&mut self, name: &str, value: u32) {
+        self.set_property(name, &value.to_be_bytes());
+    }
+
+    pub fn set_u64(&mut self, name: &str, value: u64) {
+        self.set_property(name, &value.to_be_bytes());
+    }
+
+    pub fn set_str(&mut self, name: &str, value: &str) {
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(0);
+        self.set_property(name, &buf);
+    }
+
+    /// Encode and set the `reg` property of this node, given the cell sizes from its parent.
+    pub fn set_reg(&mut self, address_cells: u32, size_cells: u32, reg: &[(u64, u64)]) {
+        let mut buf = Vec::new();
+        for &(address, size) in reg {
+            write_cells(&mut buf, address, address_cells);
+            write_cells(&mut buf, size, size_cells);
+        }
+        self.set_property("reg", &buf);
+    }
+}
+
+fn read_cells(buf: &[u8]) -> u64 {
+    buf.chunks_exact(4)
+        .fold(0, |acc, cell| acc << 32 | BE::read_u32(cell) as u64)
+}
+
+fn write_cells(buf: &mut Vec<u8>, value: u64, cells: u32) {
+    for i in (0..cells).rev() {
+        let cell = if i >= 2 {
+            0
+        } else {
+            (value >> (i * 32)) as u32
+        };
+        buf.extend_from_slice(&cell.to_be_bytes());
+    }
+}
+
+pub struct Fdt {
+    boot_cpuid: u32,
+    reserved: Vec<(u64, u64)>,
+    root: Node,
+}
+
+fn read_str(buf: &[u8]) -> Result<&str> {
+    let len = buf.iter().position(|&x| x == 0).ok_or(Error::Truncated)?;
+    core::str::from_utf8(&buf[..len]).map_err(|_| Error::BadStructure)
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+impl Fdt {
+    pub fn new(blob: &[u8]) -> Result<Self> {
+        if blob.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if BE::read_u32(&blob[0..]) != FDT_MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        let total_size = BE::read_u32(&blob[4..]) as usize;
+        let off_struct = BE::read_u32(&blob[8..]) as usize;
+        let off_strings = BE::read_u32(&blob[12..]) as usize;
+        let off_rsvmap = BE::read_u32(&blob[16..]) as usize;
+        let version = BE::read_u32(&blob[20..]);
+        let last_comp_version = BE::read_u32(&blob[24..]);
+        let boot_cpuid = BE::read_u32(&blob[28..]);
+        let size_strings = BE::read_u32(&blob[32..]) as usize;
+        let size_struct = BE::read_u32(&blob[36..]) as usize;
+
+        if version < FDT_LAST_COMP_VERSION || last_comp_version > FDT_VERSION {
+            return Err(Error::BadVersion);
+        }
+        if total_size > blob.len()
+            || off_struct
+                .checked_add(size_struct)
+                .map_or(true, |x| x > total_size)
+            || off_strings
+                .checked_add(size_strings)
+                .map_or(true, |x| x > total_size)
+            || off_rsvmap >= total_size
+        {
+            return Err(Error::Truncated);
+        }
+
+        let mut reserved = Vec::new();
+        let mut ptr = off_rsvmap;
+        loop {
+            if ptr + 16 > total_size {
+                return Err(Error::Truncated);
+            }
+            let address = BE::read_u64(&blob[ptr..]);
+            let size = BE::read_u64(&blob[ptr + 8..]);
+            ptr += 16;
+            if address == 0 && size == 0 {
+                break;
+            }
+            reserved.push((address, size));
+        }
+
+        let structs = &blob[off_struct..][..size_struct];
+        let strings = &blob[off_strings..][..size_strings];
+
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+        let mut ptr = 0;
+        loop {
+            if ptr + 4 > structs.len() {
+                return Err(Error::Truncated);
+            }
+            let token = BE::read_u32(&structs[ptr..]);
+            ptr += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    if root.is_some() {
+                        return Err(Error::BadStructure);
+                    }
+                    let name = read_str(&structs[ptr..])?;
+                    ptr = align4(ptr + name.len() + 1);
+                    stack.push(Node::new(name));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or(Error::BadStructure)?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    if ptr + 8 > structs.len() {
+                        return Err(Error::Truncated);
+                    }
+                    let len = BE::read_u32(&structs[ptr..]) as usize;
+                    let nameoff = BE::read_u32(&structs[ptr + 4..]) as usize;
+                    ptr += 8;
+                    if ptr + len > structs.len() {
+                        return Err(Error::Truncated);
+                    }
+                    let name = read_str(strings.get(nameoff..).ok_or(Error::Truncated)?)?;
+                    let node = stack.last_mut().ok_or(Error::BadStructure)?;
+                    node.properties.push(Property {
+                        name: name.into(),
+                        value: structs[ptr..][..len].into(),
+                    });
+                    ptr = align4(ptr + len);
+                }
+                FDT_NOP => (),
+                FDT_END => break,
+                _ => return Err(Error::BadStructure),
+            }
+        }
+
+        if !stack.is_empty() {
+            return Err(Error::BadStructure);
+        }
+
+        Ok(Fdt {
+            boot_cpuid,
+            reserved,
+            root: root.ok_or(Error::BadStructure)?,
+        })
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn root_mut(&mut self) -> &mut Node {
+        &mut self.root
+    }
+
+    /// Find a node by its absolute path.
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        self.root.find(path.strip_prefix('/')?)
+    }
+
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        self.root.find_mut(path.strip_prefix('/')?)
+    }
+
+    /// Find the first node that is compatible with `compatible`.
+    pub fn find_compatible(&self, compatible: &str) -> Option<&Node> {
+        self.root.find_by(|node| node.is_compatible(compatible))
+    }
+
+    pub fn find_compatible_mut(&mut self, compatible: &str) -> Option<&mut Node> {
+        self.root.find_by_mut(|node| node.is_compatible(compatible))
+    }
+
+    /// Add an entry to the memory reservation block.
+    pub fn add_reserved(&mut self, address: u64, size: u64) {
+        self.reserved.push((address, size));
+    }
+
+    /// Flatten the tree into a device tree blob.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_u32(buf: &mut Vec<u8>, value: u32) {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+
+        fn push_padded(buf: &mut Vec<u8>, value: &[u8]) {
+            buf.extend_from_slice(value);
+            buf.resize(align4(buf.len()), 0);
+        }
+
+        // Find a name in the string block, appending it if not present yet.
+        fn string_offset(strings: &mut Vec<u8>, name: &str) -> usize {
+            let mut offset = 0;
+            for s in strings.split_inclusive(|&x| x == 0) {
+                if &s[..s.len() - 1] == name.as_bytes() {
+                    return offset;
+                }
+                offset += s.len();
+            }
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        }
+
+        fn flatten(node: &Node, structs: &mut Vec<u8>, strings: &mut Vec<u8>) {
+            push_u32(structs, FDT_BEGIN_NODE);
+            structs.extend_from_slice(node.name.as_bytes());
+            push_padded(structs, &[0]);
+
+            for prop in node.properties.iter() {
+                let nameoff = string_offset(strings, &prop.name);
+                push_u32(structs, FDT_PROP);
+                push_u32(structs, prop.value.len() as u32);
+                push_u32(structs, nameoff as u32);
+                push_padded(structs, &prop.value);
+            }
+
+            for child in node.children.iter() {
+                flatten(child, structs, strings);
+            }
+
+            push_u32(structs, FDT_END_NODE);
+        }
+
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+        flatten(&self.root, &mut structs, &mut strings);
+        push_u32(&mut structs, FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + (self.reserved.len() + 1) * 16;
+        let off_strings = off_struct + structs.len();
+        let total_size = off_strings + strings.len();
+
+        let mut blob = Vec::with_capacity(align4(total_size));
+        for value in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structs.len() as u32,
+        ] {
+            push_u32(&mut blob, value);
+        }
+        for &(address, size) in self.reserved.iter().chain([(0, 0)].iter()) {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+}
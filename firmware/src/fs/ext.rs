@@ -6,9 +6,20 @@ mod sys {
     pub const INCOMPAT_EXTENTS: u32 = 0x40;
     pub const INCOMPAT_64BIT: u32 = 0x80;
     pub const INCOMPAT_FLEX_BG: u32 = 0x200;
+    pub const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+
+    pub const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+    pub const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+    pub const EXT4_INDEX_FL: u32 = 0x1000;
 
     pub const EXT4_EXTENTS_FL: u32 = 0x80000;
 
+    pub const S_IFMT: u16 = 0xF000;
+    pub const S_IFLNK: u16 = 0xA000;
+    pub const S_IFREG: u16 = 0x8000;
+    pub const S_IFDIR: u16 = 0x4000;
+
     #[repr(C)]
     pub struct SuperBlock {
         pub inodes_count: u32,
@@ -167,16 +178,63 @@ mod sys {
     }
 }
 
+use crate::crc::{crc16_arc, crc32c};
 use crate::io::{Error, Read, ReadAt, Result as IoResult};
 use crate::util::OnceCell;
 use crate::{block::Block, util::uninit_slice};
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
-use core::mem;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LE};
+use core::{fmt, mem};
 use spin::Mutex;
 use sys::*;
 
-const INCOMPAT_SUPPORTED: u32 = 0x2C6;
+const INCOMPAT_SUPPORTED: u32 = 0x22C6;
+
+/// Number of direct block pointers in an inode without extents. They are followed by the single,
+/// double and triple indirect block pointers.
+const NDIR_BLOCKS: usize = 12;
+
+/// Maximum number of symbolic links followed in a path lookup, as in Linux.
+const MAX_SYMLINKS: u32 = 40;
+/// Maximum length of a symbolic link target.
+const SYMLINK_MAX: usize = 4096;
+
+/// What to do when metadata fails checksum verification.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// Print a warning and use the metadata anyway.
+    Warn,
+    /// Print a warning and fail the operation.
+    Refuse,
+}
+
+/// Checksum policy. Refusing is the default, as corrupted metadata may otherwise result in a
+/// half-corrupt kernel being loaded; the `ext-csum-warn` feature only warns instead.
+pub const CHECKSUM_POLICY: ChecksumPolicy = if cfg!(feature = "ext-csum-warn") {
+    ChecksumPolicy::Warn
+} else {
+    ChecksumPolicy::Refuse
+};
+
+/// CRC-32C as used by ext4, which chains checksums without the final inversion.
+fn ext4_chksum(seed: u32, data: &[u8]) -> u32 {
+    !crc32c(!seed, data)
+}
+
+/// Apply [`CHECKSUM_POLICY`] to the result of a checksum verification.
+fn check_csum(ok: bool, what: fmt::Arguments) -> IoResult<()> {
+    if ok {
+        return Ok(());
+    }
+    println!("ext: {} checksum mismatch", what);
+    match CHECKSUM_POLICY {
+        ChecksumPolicy::Warn => Ok(()),
+        ChecksumPolicy::Refuse => Err(Error::Textual("Metadata checksum mismatch")),
+    }
+}
 
 pub struct File<'a> {
     fs: &'a FileSystem,
@@ -230,6 +288,12 @@ impl Read for File<'_> {
     }
 }
 
+impl super::File for File<'_> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
 pub struct Dir<'a> {
     file: File<'a>,
 }
@@ -239,6 +303,13 @@ impl<'a> Iterator for Dir<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
+            let block_mask = (1024 << self.file.fs.superblock.log_block_size) - 1;
+            if self.file.ptr & block_mask == 0 && self.file.ptr < self.file.size() {
+                if let Err(err) = self.file.fs.verify_dir_block(&self.file, self.file.ptr) {
+                    return Some(Err(err));
+                }
+            }
+
             let mut dir: DirEntryRaw = unsafe { mem::zeroed() };
 
             // Read into the DirEntry
@@ -316,7 +387,7 @@ impl<'a> DirEntry<'a> {
     }
 
     pub fn readdir(&self) -> IoResult<Dir<'a>> {
-        assert!(self.is_file());
+        assert!(self.is_dir());
         let inode = self.fetch_inode()?;
         Ok(Dir {
             file: File::new(self.fs, self.raw.inode, *inode),
@@ -327,9 +398,14 @@ impl<'a> DirEntry<'a> {
 pub struct FileSystem {
     block: Arc<dyn Block>,
     superblock: SuperBlock,
+    /// Checksum seed if metadata_csum is enabled.
+    csum_seed: Option<u32>,
 
     group_desc_cache: Mutex<(u64, Box<[u8]>)>,
     extent_cache: Mutex<(u32, Box<[u32]>)>,
+    /// Indirect blocks keyed by block number, one for each level of indirection. The block
+    /// pointing directly to data uses the first entry.
+    indirect_cache: Mutex<[(u64, Box<[u32]>); 3]>,
     cache: Mutex<(u64, Box<[u8]>)>,
 }
 
@@ -347,8 +423,27 @@ impl FileSystem {
 
         if superblock.rev_level == 0 {
             superblock.feature_incompat = 0;
+            superblock.feature_ro_compat = 0;
+            superblock.inode_size = 128;
         }
 
+        let csum_seed = if superblock.feature_ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            if buf[0x175] != 1 {
+                return Err(Error::Textual("Unsupported metadata checksum type"));
+            }
+            check_csum(
+                LE::read_u32(&buf[0x3FC..]) == ext4_chksum(!0, &buf[..0x3FC]),
+                format_args!("superblock"),
+            )?;
+            Some(if superblock.feature_incompat & INCOMPAT_CSUM_SEED != 0 {
+                LE::read_u32(&buf[0x270..])
+            } else {
+                ext4_chksum(!0, &superblock.uuid)
+            })
+        } else {
+            None
+        };
+
         if superblock.feature_incompat & !INCOMPAT_SUPPORTED != 0 {
             return Err(Error::Textual(
                 "Filesystem contains unsupported incompatible features",
@@ -365,8 +460,12 @@ impl FileSystem {
         let fs = FileSystem {
             block,
             superblock,
+            csum_seed,
             group_desc_cache: spin::Mutex::new((0, unsafe { uninit_slice(block_size) })),
             extent_cache: spin::Mutex::new((0, unsafe { uninit_slice(block_size / 4) })),
+            indirect_cache: spin::Mutex::new(
+                [(); 3].map(|_| (0, unsafe { uninit_slice(block_size / 4) })),
+            ),
             cache: spin::Mutex::new((0, unsafe { uninit_slice(block_size) })),
         };
 
@@ -451,12 +550,34 @@ impl FileSystem {
             &mut cache,
             &mut buf[..self.superblock.desc_size as usize],
             desc_offset,
-        )
-        .unwrap();
+        )?;
+        drop(cache);
         // This is safe and will produce sensible GroupDesc because we pre-init buf to zero,
         // so any unfilled fields are all zeroed out.
         let desc: GroupDesc = unsafe { mem::transmute_copy(&buf) };
 
+        // Both checksums cover the descriptor with the checksum field itself skipped.
+        let raw = &buf[..self.superblock.desc_size as usize];
+        if let Some(seed) = self.csum_seed {
+            let mut csum = ext4_chksum(seed, &group.to_le_bytes());
+            csum = ext4_chksum(csum, &raw[..0x1E]);
+            csum = ext4_chksum(csum, &[0; 2]);
+            csum = ext4_chksum(csum, &raw[0x20..]);
+            check_csum(
+                csum as u16 == desc.checksum,
+                format_args!("group descriptor {}", group),
+            )?;
+        } else if self.superblock.feature_ro_compat & RO_COMPAT_GDT_CSUM != 0 {
+            let mut crc = crc16_arc(!0, &self.superblock.uuid);
+            crc = crc16_arc(crc, &group.to_le_bytes());
+            crc = crc16_arc(crc, &raw[..0x1E]);
+            crc = crc16_arc(crc, &raw[0x20..]);
+            check_csum(
+                crc == desc.checksum,
+                format_args!("group descriptor {}", group),
+            )?;
+        }
+
         Ok(desc)
     }
 
@@ -466,23 +587,81 @@ impl FileSystem {
 
         let desc = self.get_group_desc(inode_group_index)?;
 
-        let mut buf = [0; mem::size_of::<Inode>()];
+        // The whole on-disk inode is needed for its checksum. Fields beyond it are zeroed.
+        let inode_size = self.superblock.inode_size as usize;
+        let mut buf = vec![0; inode_size.max(mem::size_of::<Inode>())];
         let ino_offset = ((desc.inode_table_lo as u64 | (desc.inode_table_hi as u64) << 32)
             << (self.superblock.log_block_size + 10))
-            + inode_group_offset as u64 * self.superblock.inode_size as u64;
-        self.read(
-            &mut buf[..mem::size_of::<Inode>().min(self.superblock.inode_size as usize)],
-            ino_offset,
-        )
-        .unwrap();
-        let ino: Inode = unsafe { mem::transmute_copy(&buf) };
+            + inode_group_offset as u64 * inode_size as u64;
+        self.read(&mut buf[..inode_size], ino_offset)?;
+        let ino: Inode = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Inode) };
+
+        if let Some(seed) = self.inode_csum_seed(inode, ino.generation) {
+            let raw = &buf[..inode_size];
+            // The high half of the checksum only exists if it fits in the extra inode space.
+            let has_hi = inode_size > 128 && 128 + ino.extra_isize as usize >= 0x84;
+            let hi_offset = if has_hi { 0x82 } else { inode_size };
+            let mut csum = ext4_chksum(seed, &raw[..0x7C]);
+            csum = ext4_chksum(csum, &[0; 2]);
+            csum = ext4_chksum(csum, &raw[0x7E..hi_offset]);
+            let stored = if has_hi {
+                csum = ext4_chksum(csum, &[0; 2]);
+                csum = ext4_chksum(csum, &raw[0x84..]);
+                (ino.checksum_hi as u32) << 16 | LE::read_u16(&raw[0x7C..]) as u32
+            } else {
+                csum &= 0xFFFF;
+                LE::read_u16(&raw[0x7C..]) as u32
+            };
+            check_csum(csum == stored, format_args!("inode {}", inode))?;
+        }
 
         Ok(ino)
     }
 
+    /// Checksum seed of an inode, used for the inode and for its extent and directory blocks.
+    fn inode_csum_seed(&self, ino: u32, generation: u32) -> Option<u32> {
+        self.csum_seed.map(|seed| {
+            ext4_chksum(
+                ext4_chksum(seed, &ino.to_le_bytes()),
+                &generation.to_le_bytes(),
+            )
+        })
+    }
+
+    /// Verify the checksum in the tail of a directory leaf block.
+    ///
+    /// Only directories with an htree index have blocks without a tail, namely the index nodes.
+    fn verify_dir_block(&self, file: &File<'_>, offset: u64) -> IoResult<()> {
+        let seed = match self.inode_csum_seed(file.ino, file.inode.generation) {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+
+        let block_size = 1024 << self.superblock.log_block_size;
+        let mut block = vec![0; block_size];
+        self.read_inode(file.ino, &file.inode, &mut block, offset)?;
+        let (data, tail) = block.split_at(block_size - 12);
+        let has_tail = LE::read_u32(&tail[0..]) == 0
+            && LE::read_u16(&tail[4..]) == 12
+            && tail[6] == 0
+            && tail[7] == 0xDE;
+        if !has_tail && file.inode.flags & EXT4_INDEX_FL != 0 {
+            return Ok(());
+        }
+        check_csum(
+            has_tail && LE::read_u32(&tail[8..]) == ext4_chksum(seed, data),
+            format_args!(
+                "directory block {} of inode {}",
+                offset >> (self.superblock.log_block_size + 10),
+                file.ino
+            ),
+        )
+    }
+
     fn get_extent_leaf<'a>(
         &self,
         ino: u32,
+        csum_seed: Option<u32>,
         cache: &mut (u32, Box<[u32]>),
         extent: &'a [u32],
         block: u32,
@@ -602,6 +781,18 @@ impl FileSystem {
                     };
                     cache.0 = 0;
                     self.read_block(buffer_view, block)?;
+
+                    // The checksum follows the maximum number of entries.
+                    if let Some(seed) = csum_seed {
+                        let tail = 12 + 12 * LE::read_u16(&buffer_view[4..]) as usize;
+                        let ok = match buffer_view.get(tail..tail + 4) {
+                            Some(stored) => {
+                                LE::read_u32(stored) == ext4_chksum(seed, &buffer_view[..tail])
+                            }
+                            None => false,
+                        };
+                        check_csum(ok, format_args!("extent block {}", block))?;
+                    }
                     cache.0 = ino;
                     cur_extent = &cache.1;
                 }
@@ -609,6 +800,78 @@ impl FileSystem {
         }
     }
 
+    /// Map a file block through the block map used by ext2 and ext3 (and by ext4 files without
+    /// extents).
+    ///
+    /// Returns the physical block, or `None` for a hole, and the number of blocks that are
+    /// contiguous from it on disk.
+    fn map_indirect(
+        &self,
+        cache: &mut [(u64, Box<[u32]>); 3],
+        inode: &Inode,
+        block: u32,
+    ) -> IoResult<(Option<u64>, usize)> {
+        // Number of contiguous blocks starting at `ptrs[index]`.
+        fn contiguous(ptrs: &[u32], index: usize) -> (Option<u64>, usize) {
+            let start = ptrs[index];
+            if start == 0 {
+                return (None, 1);
+            }
+            let len = ptrs[index..]
+                .iter()
+                .zip(start..)
+                .take_while(|&(&ptr, expected)| ptr == expected)
+                .count();
+            (Some(start as u64), len)
+        }
+
+        let index_bits = self.superblock.log_block_size + 8;
+        let mut index = block as u64;
+        if index < NDIR_BLOCKS as u64 {
+            return Ok(contiguous(&inode.blocks[..NDIR_BLOCKS], index as usize));
+        }
+        index -= NDIR_BLOCKS as u64;
+
+        // Find the level of indirection: single, double or triple.
+        let mut depth = 1;
+        while index >= 1 << (index_bits * depth) {
+            index -= 1 << (index_bits * depth);
+            depth += 1;
+            if depth > 3 {
+                return Err(Error::Textual("File block out of range"));
+            }
+        }
+
+        let mut ptr = inode.blocks[NDIR_BLOCKS + depth as usize - 1];
+        for level in (0..depth as usize).rev() {
+            // A missing indirect block means everything below it is a hole.
+            if ptr == 0 {
+                return Ok((None, 1));
+            }
+
+            let (cached, buffer) = &mut cache[level];
+            if *cached != ptr as u64 {
+                // SAFETY: This make a [u8] view of [u32].
+                let buffer_view = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        buffer.as_mut_ptr() as *mut u8,
+                        buffer.len() * 4,
+                    )
+                };
+                *cached = 0;
+                self.read_block(buffer_view, ptr as u64)?;
+                *cached = ptr as u64;
+            }
+
+            let entry = (index >> (index_bits * level as u32)) as usize & (buffer.len() - 1);
+            if level == 0 {
+                return Ok(contiguous(buffer, entry));
+            }
+            ptr = buffer[entry];
+        }
+        unreachable!()
+    }
+
     fn read_inode(
         &self,
         ino: u32,
@@ -617,13 +880,14 @@ impl FileSystem {
         mut offset: u64,
     ) -> IoResult<()> {
         let shift = self.superblock.log_block_size + 10;
+        let csum_seed = self.inode_csum_seed(ino, inode.generation);
         while !buf.is_empty() {
             let blk = (offset >> shift) as u32;
             let blk_offset = offset - ((blk as u64) << shift);
 
             let (blk_idx, nr_blk) = if inode.flags & EXT4_EXTENTS_FL != 0 {
                 let mut cache = self.extent_cache.lock();
-                let node = self.get_extent_leaf(ino, &mut cache, &inode.blocks, blk)?;
+                let node = self.get_extent_leaf(ino, csum_seed, &mut cache, &inode.blocks, blk)?;
 
                 match node {
                     Some(node) => {
@@ -642,7 +906,8 @@ impl FileSystem {
                     None => (None, 1),
                 }
             } else {
-                todo!("direct {}", blk);
+                let mut cache = self.indirect_cache.lock();
+                self.map_indirect(&mut cache, inode, blk)?
             };
 
             let len = buf.len().min((nr_blk << shift) - blk_offset as usize);
@@ -664,4 +929,158 @@ impl FileSystem {
             file: File::new(self, 2, ino),
         })
     }
+
+    /// Volume label, as set by `e2label`.
+    pub fn label(&self) -> &str {
+        let name = &self.superblock.volume_name;
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("")
+    }
+
+    /// Open a regular file by path, following symbolic links.
+    pub fn open_path(&self, path: &str) -> IoResult<File<'_>> {
+        let (ino, inode) = self.lookup(path, true)?;
+        if inode.mode & S_IFMT != S_IFREG {
+            return Err(Error::Textual("Not a regular file"));
+        }
+        Ok(File::new(self, ino, inode))
+    }
+
+    /// Find the inode number of `name` in a directory.
+    fn find_entry(&self, ino: u32, inode: Inode, name: &str) -> IoResult<u32> {
+        for entry in (Dir {
+            file: File::new(self, ino, inode),
+        }) {
+            let entry = entry?;
+            if entry.file_name() == name {
+                return Ok(entry.raw.inode);
+            }
+        }
+        Err(Error::Textual("No such file or directory"))
+    }
+
+    /// Read the target of a symbolic link.
+    fn read_link(&self, ino: u32, inode: Inode) -> IoResult<String> {
+        let size = (inode.size_high as u64) << 32 | inode.size_lo as u64;
+        if size > SYMLINK_MAX as u64 {
+            return Err(Error::Textual("Symbolic link too long"));
+        }
+        let size = size as usize;
+
+        // Fast symlinks store the target in place of the block map. The only block a fast symlink
+        // may have is its extended attribute block.
+        let xattr_sectors = if inode.file_acl_lo != 0 {
+            (1024 << self.superblock.log_block_size) / 512
+        } else {
+            0
+        };
+        let mut target = vec![0; size];
+        if size < mem::size_of_val(&inode.blocks) && inode.blocks_count_lo == xattr_sectors {
+            let bytes = inode.blocks.iter().flat_map(|word| word.to_le_bytes());
+            for (dst, src) in target.iter_mut().zip(bytes) {
+                *dst = src;
+            }
+        } else {
+            File::new(self, ino, inode).read_exact_at(&mut target, 0)?;
+        }
+        String::from_utf8(target).map_err(|_| Error::Textual("Symbolic link not UTF-8"))
+    }
+
+    /// Look up the inode of a path.
+    ///
+    /// Symbolic links are followed, except for the last component if `follow` is false.
+    fn lookup(&self, path: &str, follow: bool) -> IoResult<(u32, Inode)> {
+        let root = (2, self.get_inode(2)?);
+        let mut current = root;
+        // Directories leading to the current one, for `..`.
+        let mut parents = Vec::new();
+        // Components still to be resolved, in reverse order.
+        let mut pending: Vec<String> = path.split('/').rev().map(Into::into).collect();
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            match name.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    current = parents.pop().unwrap_or(root);
+                    continue;
+                }
+                _ => (),
+            }
+            if current.1.mode & S_IFMT != S_IFDIR {
+                return Err(Error::Textual("Not a directory"));
+            }
+
+            let ino = self.find_entry(current.0, current.1, &name)?;
+            let inode = self.get_inode(ino)?;
+            if inode.mode & S_IFMT == S_IFLNK && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Error::Textual("Too many levels of symbolic links"));
+                }
+                let target = self.read_link(ino, inode)?;
+                // Relative targets are resolved from the directory containing the link.
+                if target.starts_with('/') {
+                    current = root;
+                    parents.clear();
+                }
+                pending.extend(target.split('/').rev().map(Into::into));
+                continue;
+            }
+
+            parents.push(current);
+            current = (ino, inode);
+        }
+        Ok(current)
+    }
+}
+
+fn file_type(mode: u16) -> super::FileType {
+    match mode & S_IFMT {
+        S_IFREG => super::FileType::File,
+        S_IFDIR => super::FileType::Dir,
+        S_IFLNK => super::FileType::Symlink,
+        _ => super::FileType::Other,
+    }
+}
+
+impl super::FileSystem for FileSystem {
+    fn label(&self) -> &str {
+        FileSystem::label(self)
+    }
+
+    fn stat(&self, path: &str) -> IoResult<super::Metadata> {
+        let (_, inode) = self.lookup(path, true)?;
+        Ok(super::Metadata {
+            file_type: file_type(inode.mode),
+            size: (inode.size_high as u64) << 32 | inode.size_lo as u64,
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> IoResult<Vec<super::DirEntry>> {
+        let (ino, inode) = self.lookup(path, true)?;
+        if inode.mode & S_IFMT != S_IFDIR {
+            return Err(Error::Textual("Not a directory"));
+        }
+        let dir = Dir {
+            file: File::new(self, ino, inode),
+        };
+        dir.map(|entry| {
+            let entry = entry?;
+            Ok(super::DirEntry {
+                name: entry.file_name().into(),
+                file_type: match entry.raw.file_type {
+                    1 => super::FileType::File,
+                    2 => super::FileType::Dir,
+                    7 => super::FileType::Symlink,
+                    _ => super::FileType::Other,
+                },
+            })
+        })
+        .collect()
+    }
+
+    fn open(&self, path: &str) -> IoResult<Box<dyn super::File + '_>> {
+        Ok(Box::new(self.open_path(path)?))
+    }
 }
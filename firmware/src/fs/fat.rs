@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 
 use crate::block::Block;
-use crate::io::{Read, Result};
+use crate::io::{Error, Read, ReadAt, Result};
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use byteorder::{ByteOrder, LE};
 
 enum FatEntry {
@@ -15,6 +17,12 @@ enum FatEntry {
 pub struct File<'a> {
     fs: &'a FileSystem,
 
+    /// First cluster of the file, for seeking
+    first_cluster: u32,
+
+    /// Size of the file
+    len: usize,
+
     /// Current cluster to work on
     cluster: u32,
 
@@ -37,6 +45,8 @@ impl<'a> File<'a> {
     fn new(fs: &'a FileSystem, cluster: u32, size: usize) -> Self {
         File {
             fs,
+            first_cluster: cluster,
+            len: size,
             cluster,
             sector: 0,
             buf: [0; 512],
@@ -48,6 +58,30 @@ impl<'a> File<'a> {
     pub fn size(&self) -> u64 {
         self.size as u64
     }
+
+    /// Reposition to `offset` by walking the cluster chain from the start.
+    fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset >= self.len {
+            self.size = 0;
+            return Ok(());
+        }
+
+        let cluster_size = self.fs.sectors_per_cluster as usize * 512;
+        self.cluster = self.first_cluster;
+        for _ in 0..offset / cluster_size {
+            self.cluster = match self.fs.read_fat_entry(self.cluster)? {
+                FatEntry::Next(v) => v,
+                _ => return Err(Error::Textual("FAT chain shorter than file")),
+            };
+        }
+        self.sector = ((offset % cluster_size) / 512) as u32;
+        self.pointer = 512;
+        self.size = self.len - (offset & !511);
+
+        // Read the partial sector into the buffer.
+        let mut skip = [0; 512];
+        self.read_exact(&mut skip[..offset % 512])
+    }
 }
 
 impl Read for File<'_> {
@@ -121,6 +155,22 @@ impl Read for File<'_> {
     }
 }
 
+impl ReadAt for File<'_> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let offset = offset as usize;
+        if offset != self.len - self.size {
+            self.seek(offset)?;
+        }
+        self.read(buf)
+    }
+}
+
+impl super::File for File<'_> {
+    fn size(&self) -> u64 {
+        self.len as u64
+    }
+}
+
 pub struct Dir<'a> {
     chain: File<'a>,
 }
@@ -269,6 +319,7 @@ pub struct FileSystem {
     reserved_sector_count: u32,
     first_data_sector: u32,
     root_cluster: u32,
+    label: arrayvec::ArrayString<11>,
 
     cache: spin::Mutex<(u32, [u8; 512])>,
 }
@@ -300,6 +351,85 @@ impl FileSystem {
             chain: File::new(self, self.root_cluster, core::usize::MAX),
         }
     }
+
+    /// Look up a path, returning its first cluster, size and whether it is a directory.
+    fn lookup(&self, path: &str) -> Result<(u32, usize, bool)> {
+        let mut current = (self.root_cluster, 0, true);
+        for name in super::components(path) {
+            if !current.2 {
+                return Err(Error::Textual("Not a directory"));
+            }
+            let dir = Dir {
+                chain: File::new(self, current.0, usize::MAX),
+            };
+            let mut found = None;
+            for entry in dir {
+                let entry = entry?;
+                // FAT names are case-insensitive.
+                if entry.name.eq_ignore_ascii_case(name) {
+                    found = Some((entry.cluster, entry.size, entry.dir));
+                    break;
+                }
+            }
+            current = found.ok_or(Error::Textual("No such file or directory"))?;
+        }
+        Ok(current)
+    }
+}
+
+impl super::FileSystem for FileSystem {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn stat(&self, path: &str) -> Result<super::Metadata> {
+        let (_, size, dir) = self.lookup(path)?;
+        Ok(if dir {
+            super::Metadata {
+                file_type: super::FileType::Dir,
+                size: 0,
+            }
+        } else {
+            super::Metadata {
+                file_type: super::FileType::File,
+                size: size as u64,
+            }
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<super::DirEntry>> {
+        let (cluster, _, dir) = self.lookup(path)?;
+        if !dir {
+            return Err(Error::Textual("Not a directory"));
+        }
+        let dir = Dir {
+            chain: File::new(self, cluster, usize::MAX),
+        };
+        let mut entries = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            if entry.file_name() == "." || entry.file_name() == ".." {
+                continue;
+            }
+            entries.push(super::DirEntry {
+                name: entry.file_name().into(),
+                file_type: if entry.dir {
+                    super::FileType::Dir
+                } else {
+                    super::FileType::File
+                },
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn super::File + '_>> {
+        let (cluster, size, dir) = self.lookup(path)?;
+        if dir {
+            return Err(Error::Textual("Not a regular file"));
+        }
+        Ok(Box::new(File::new(self, cluster, size)))
+    }
 }
 
 impl FileSystem {
@@ -310,10 +440,13 @@ impl FileSystem {
         block.read_exact_at(&mut buf, 0)?;
 
         // Verify it's FAT32, not FAT12/16
-        let fat_size = LE::read_u16(&buf[0x16..]);
-        assert_eq!(fat_size, 0, "Only FAT32 is supported");
+        if LE::read_u16(&buf[0x16..]) != 0 {
+            return Err(Error::Textual("Only FAT32 is supported"));
+        }
 
-        assert_eq!(LE::read_u16(&buf[0x0B..]), 512, "FAT sector size not 512");
+        if LE::read_u16(&buf[0x0B..]) != 512 {
+            return Err(Error::Textual("FAT sector size not 512"));
+        }
         let cluster_size = buf[0x0D] as u64 * 512;
         println!("FAT cluster size is {}", cluster_size);
 
@@ -327,12 +460,25 @@ impl FileSystem {
         let root_cluster = LE::read_u32(&buf[0x2C..]);
         println!("Root directory is located at cluster {}", root_cluster);
 
+        // Volume label in the extended BPB, padded with spaces.
+        let mut label = arrayvec::ArrayString::new();
+        if buf[0x42] == 0x29 {
+            for &c in buf[0x47..0x52].iter() {
+                label.push(if c.is_ascii() { c as char } else { '?' });
+            }
+            label.truncate(label.trim_end().len());
+            if label.as_str() == "NO NAME" {
+                label.clear();
+            }
+        }
+
         Ok(FileSystem {
             block,
             reserved_sector_count,
             sectors_per_cluster: buf[0x0D] as u32,
             first_data_sector,
             root_cluster,
+            label,
             cache: spin::Mutex::new((0, [0; 512])),
         })
     }
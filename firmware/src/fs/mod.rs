@@ -1,2 +1,95 @@
+//! Filesystems.
+//!
+//! This module provides a [`FileSystem`] trait implemented by all supported filesystems, and
+//! [`mount`] which detects the filesystem on a block device.
+
 pub mod ext;
 pub mod fat;
+
+use crate::block::Block;
+use crate::io::{Error, Read, ReadAt, Result};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+pub struct Metadata {
+    pub file_type: FileType,
+    /// Size in bytes. Only meaningful for regular files.
+    pub size: u64,
+}
+
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file opened for reading.
+pub trait File: Read + ReadAt {
+    fn size(&self) -> u64;
+}
+
+/// Common interface of filesystems.
+///
+/// Paths are `/`-separated and relative to the root directory, whether or not they start with
+/// `/`.
+pub trait FileSystem {
+    /// Volume label, or an empty string if there is none.
+    fn label(&self) -> &str;
+
+    fn stat(&self, path: &str) -> Result<Metadata>;
+
+    /// List the entries of a directory, excluding `.` and `..`.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>>;
+
+    /// Open a regular file.
+    fn open(&self, path: &str) -> Result<Box<dyn File + '_>>;
+}
+
+/// Split a path into its components, resolving `.` and `..`.
+pub(crate) fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    components
+}
+
+/// Detect the filesystem on a block device and mount it.
+pub fn mount(block: Arc<dyn Block>) -> Result<Box<dyn FileSystem>> {
+    let mut buf = [0; 2048];
+    block.read_exact_at(&mut buf, 0)?;
+
+    // ext superblock magic
+    if LE::read_u16(&buf[1024 + 0x38..]) == 0xEF53 {
+        return Ok(Box::new(ext::FileSystem::new(block)?));
+    }
+
+    // FAT32 boot sector: boot signature, 512-byte sectors, and 16-bit FAT size of 0. A MBR has the
+    // boot signature too, but no valid sectors per cluster.
+    if buf[510] == 0x55
+        && buf[511] == 0xAA
+        && LE::read_u16(&buf[0x0B..]) == 512
+        && buf[0x0D].is_power_of_two()
+        && LE::read_u16(&buf[0x16..]) == 0
+    {
+        return Ok(Box::new(fat::FileSystem::new(block)?));
+    }
+
+    Err(Error::Textual("Unknown filesystem"))
+}
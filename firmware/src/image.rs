@@ -0,0 +1,63 @@
+//! Loader for the Linux RISC-V flat `Image` format.
+
+use byteorder::{ByteOrder, LE};
+
+#[derive(Debug)]
+pub enum Error {
+    /// `image_size` is smaller than the file.
+    InvalidSize,
+    /// The image does not fit in the memory window.
+    TooLarge,
+    /// The image would overwrite the file being loaded.
+    OverlapsFile,
+}
+
+const HEADER_SIZE: usize = 64;
+const MAGIC2: &[u8; 4] = b"RSC\x05";
+
+pub fn is_image(slice: &[u8]) -> bool {
+    slice.len() >= HEADER_SIZE && &slice[56..60] == MAGIC2
+}
+
+/// Load an `Image` into memory at `addr`, which must stay below `limit`.
+///
+/// Returns the size of memory occupied relative to `addr`, and the entry point.
+pub unsafe fn load_image(slice: &[u8], addr: usize, limit: usize) -> Result<(usize, usize), Error> {
+    assert!(is_image(slice), "Not Image file");
+
+    let text_offset = LE::read_u64(&slice[8..]) as usize;
+    // Older kernels leave image_size as zero, in which case only the file is known to be needed.
+    let image_size = match LE::read_u64(&slice[16..]) as usize {
+        0 => slice.len(),
+        v => v,
+    };
+    if image_size < slice.len() {
+        return Err(Error::InvalidSize);
+    }
+
+    let size = text_offset
+        .checked_add(image_size)
+        .and_then(|x| x.checked_add(4095))
+        .ok_or(Error::TooLarge)?
+        & !4095;
+    if size > limit.saturating_sub(addr) {
+        return Err(Error::TooLarge);
+    }
+
+    let load_addr = addr + text_offset;
+    let file_start = slice.as_ptr() as usize;
+    if file_start < load_addr + image_size && load_addr < file_start + slice.len() {
+        return Err(Error::OverlapsFile);
+    }
+
+    core::ptr::copy_nonoverlapping(slice.as_ptr(), load_addr as *mut u8, slice.len());
+
+    // Zero-out the BSS
+    core::ptr::write_bytes(
+        (load_addr + slice.len()) as *mut u8,
+        0,
+        image_size - slice.len(),
+    );
+
+    Ok((size, load_addr))
+}
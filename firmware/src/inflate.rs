@@ -0,0 +1,438 @@
+//! DEFLATE and gzip decompression.
+//!
+//! Huffman codes are decoded through a lookup table indexed by the next `FAST_BITS` bits of input,
+//! and only longer codes fall back to canonical bit-by-bit decoding. Output goes into a
+//! preallocated buffer, which for gzip is sized from the trailer.
+
+use crate::util::zeroed_slice;
+use alloc::boxed::Box;
+use byteorder::{ByteOrder, LE};
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidHeader,
+    InvalidBlockType,
+    InvalidStoredLength,
+    InvalidCode,
+    InvalidDistance,
+    UnexpectedEof,
+    OutputOverflow,
+    ChecksumMismatch,
+    SizeMismatch,
+    /// The decompressed content would not fit in the memory it is loaded into.
+    TooLarge,
+    OutOfMemory,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+const MAX_BITS: usize = 15;
+const MAX_LITLEN: usize = 288;
+const MAX_DIST: usize = 32;
+const FAST_BITS: u32 = 10;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which code length code lengths are stored in a dynamic block header.
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position of the next byte to load into `buf`. May go past the end of `data`, in which case
+    /// zeroes are loaded instead.
+    pos: usize,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            buf: 0,
+            cnt: 0,
+        }
+    }
+
+    #[inline]
+    fn refill(&mut self) {
+        while self.cnt <= 56 {
+            let byte = self.data.get(self.pos).copied().unwrap_or(0);
+            self.buf |= (byte as u64) << self.cnt;
+            self.pos += 1;
+            self.cnt += 8;
+        }
+    }
+
+    #[inline]
+    fn peek(&mut self) -> u64 {
+        if self.cnt < 32 {
+            self.refill();
+        }
+        self.buf
+    }
+
+    #[inline]
+    fn consume(&mut self, n: u32) {
+        debug_assert!(n <= self.cnt);
+        self.buf >>= n;
+        self.cnt -= n;
+    }
+
+    #[inline]
+    fn bits(&mut self, n: u32) -> u32 {
+        let value = (self.peek() & ((1 << n) - 1)) as u32;
+        self.consume(n);
+        value
+    }
+
+    /// Discard bits until the next byte boundary, and return the byte offset reached.
+    fn align(&mut self) -> usize {
+        self.consume(self.cnt % 8);
+        let offset = self.pos - self.cnt as usize / 8;
+        self.pos = offset;
+        self.buf = 0;
+        self.cnt = 0;
+        offset
+    }
+
+    /// Check that no bits past the end of input have been consumed.
+    fn check_eof(&self) -> Result<()> {
+        if self.pos - (self.cnt as usize / 8) > self.data.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        Ok(())
+    }
+}
+
+/// Decoding tables of a Huffman code.
+///
+/// The tables are kept on the heap, as the decoder runs deep in the boot path where stack space is
+/// scarce.
+struct Huffman<const N: usize> {
+    /// Lookup table for codes no longer than `FAST_BITS`, indexed by input bits.
+    /// Each entry is `symbol << 4 | length`, with zero meaning the code is longer.
+    fast: Box<[u16; 1 << FAST_BITS]>,
+    count: [u16; MAX_BITS + 1],
+    symbol: Box<[u16; N]>,
+}
+
+/// Allocate a zeroed array on the heap without building it on the stack first.
+fn zeroed_array<const N: usize>() -> Box<[u16; N]> {
+    unsafe { zeroed_slice(N) }
+        .try_into()
+        .map_err(|_| ())
+        .unwrap()
+}
+
+impl<const N: usize> Huffman<N> {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut huffman = Huffman {
+            fast: zeroed_array(),
+            count: [0; MAX_BITS + 1],
+            symbol: zeroed_array(),
+        };
+
+        for &len in lengths {
+            huffman.count[len as usize] += 1;
+        }
+        huffman.count[0] = 0;
+
+        // Check for over-subscribed codes. Incomplete codes are allowed.
+        let mut left = 1i32;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - huffman.count[len] as i32;
+            if left < 0 {
+                return Err(Error::InvalidCode);
+            }
+        }
+
+        let mut offset = [0u16; MAX_BITS + 2];
+        let mut next_code = [0u32; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offset[len + 1] = offset[len] + huffman.count[len];
+            next_code[len + 1] = (next_code[len] + huffman.count[len] as u32) << 1;
+        }
+
+        for (sym, &len) in lengths.iter().enumerate() {
+            let len = len as usize;
+            if len == 0 {
+                continue;
+            }
+            huffman.symbol[offset[len] as usize] = sym as u16;
+            offset[len] += 1;
+
+            let code = next_code[len];
+            next_code[len] += 1;
+            if len as u32 <= FAST_BITS {
+                // Codes are stored MSB first, but bits are read LSB first.
+                let rev = code.reverse_bits() >> (32 - len);
+                let entry = (sym as u16) << 4 | len as u16;
+                for fill in (rev..1 << FAST_BITS).step_by(1 << len) {
+                    huffman.fast[fill as usize] = entry;
+                }
+            }
+        }
+
+        Ok(huffman)
+    }
+
+    #[inline]
+    fn decode(&self, br: &mut BitReader) -> Result<u16> {
+        let bits = br.peek();
+        let entry = self.fast[(bits & ((1 << FAST_BITS) - 1)) as usize];
+        if entry != 0 {
+            br.consume((entry & 0xF) as u32);
+            return Ok(entry >> 4);
+        }
+
+        // Canonical decoding, one bit at a time.
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= ((bits >> (len - 1)) & 1) as i32;
+            let count = self.count[len] as i32;
+            if code - first < count {
+                br.consume(len as u32);
+                return Ok(self.symbol[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::InvalidCode)
+    }
+}
+
+fn fixed_tables() -> (Huffman<MAX_LITLEN>, Huffman<MAX_DIST>) {
+    let mut lengths = [0; MAX_LITLEN];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    let litlen = Huffman::new(&lengths).unwrap();
+    let dist = Huffman::new(&[5; 30]).unwrap();
+    (litlen, dist)
+}
+
+fn dynamic_tables(br: &mut BitReader) -> Result<(Huffman<MAX_LITLEN>, Huffman<MAX_DIST>)> {
+    let nlen = br.bits(5) as usize + 257;
+    let ndist = br.bits(5) as usize + 1;
+    let ncode = br.bits(4) as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(Error::InvalidCode);
+    }
+
+    let mut lengths = [0u8; 19];
+    for &idx in CLEN_ORDER[..ncode].iter() {
+        lengths[idx] = br.bits(3) as u8;
+    }
+    let clen = Huffman::<19>::new(&lengths)?;
+
+    let mut lengths = [0u8; 286 + 30];
+    let mut idx = 0;
+    while idx < nlen + ndist {
+        let sym = clen.decode(br)?;
+        let (value, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => {
+                if idx == 0 {
+                    return Err(Error::InvalidCode);
+                }
+                (lengths[idx - 1], 3 + br.bits(2) as usize)
+            }
+            17 => (0, 3 + br.bits(3) as usize),
+            _ => (0, 11 + br.bits(7) as usize),
+        };
+        if idx + repeat > nlen + ndist {
+            return Err(Error::InvalidCode);
+        }
+        lengths[idx..idx + repeat].fill(value);
+        idx += repeat;
+    }
+
+    // There must be a code for end-of-block.
+    if lengths[256] == 0 {
+        return Err(Error::InvalidCode);
+    }
+
+    let litlen = Huffman::new(&lengths[..nlen])?;
+    let dist = Huffman::new(&lengths[nlen..nlen + ndist])?;
+    Ok((litlen, dist))
+}
+
+fn inflate_block(
+    br: &mut BitReader,
+    out: &mut [u8],
+    mut pos: usize,
+    litlen: &Huffman<MAX_LITLEN>,
+    dist: &Huffman<MAX_DIST>,
+) -> Result<usize> {
+    loop {
+        // A length/distance pair takes at most 48 bits, so refill once per symbol.
+        br.refill();
+        let sym = litlen.decode(br)? as usize;
+        if sym < 256 {
+            *out.get_mut(pos).ok_or(Error::OutputOverflow)? = sym as u8;
+            pos += 1;
+            continue;
+        }
+        if sym == 256 {
+            return Ok(pos);
+        }
+
+        let sym = sym - 257;
+        if sym >= 29 {
+            return Err(Error::InvalidCode);
+        }
+        let len = LENGTH_BASE[sym] as usize + br.bits(LENGTH_EXTRA[sym] as u32) as usize;
+
+        let sym = dist.decode(br)? as usize;
+        if sym >= 30 {
+            return Err(Error::InvalidDistance);
+        }
+        let distance = DIST_BASE[sym] as usize + br.bits(DIST_EXTRA[sym] as u32) as usize;
+
+        if distance > pos {
+            return Err(Error::InvalidDistance);
+        }
+        if pos + len > out.len() {
+            return Err(Error::OutputOverflow);
+        }
+
+        if distance >= len {
+            out.copy_within(pos - distance..pos - distance + len, pos);
+        } else {
+            // Overlapping copy, which must be done byte by byte.
+            for i in pos..pos + len {
+                out[i] = out[i - distance];
+            }
+        }
+        pos += len;
+    }
+}
+
+/// Decompress a raw DEFLATE stream into `out`.
+///
+/// Returns the number of bytes consumed from `data` and the number of bytes written to `out`.
+pub fn inflate(data: &[u8], out: &mut [u8]) -> Result<(usize, usize)> {
+    let mut br = BitReader::new(data);
+    let mut pos = 0;
+    let mut fixed = None;
+
+    loop {
+        let last = br.bits(1) != 0;
+        match br.bits(2) {
+            0 => {
+                let offset = br.align();
+                if offset + 4 > data.len() {
+                    return Err(Error::UnexpectedEof);
+                }
+                let len = LE::read_u16(&data[offset..]) as usize;
+                let nlen = LE::read_u16(&data[offset + 2..]) as usize;
+                if len != !nlen & 0xFFFF {
+                    return Err(Error::InvalidStoredLength);
+                }
+                let src = data
+                    .get(offset + 4..offset + 4 + len)
+                    .ok_or(Error::UnexpectedEof)?;
+                out.get_mut(pos..pos + len)
+                    .ok_or(Error::OutputOverflow)?
+                    .copy_from_slice(src);
+                pos += len;
+                br.pos = offset + 4 + len;
+            }
+            1 => {
+                let (litlen, dist) = fixed.get_or_insert_with(fixed_tables);
+                pos = inflate_block(&mut br, out, pos, litlen, dist)?;
+            }
+            2 => {
+                let (litlen, dist) = dynamic_tables(&mut br)?;
+                pos = inflate_block(&mut br, out, pos, &litlen, &dist)?;
+            }
+            _ => return Err(Error::InvalidBlockType),
+        }
+        br.check_eof()?;
+
+        if last {
+            break;
+        }
+    }
+
+    Ok((br.align(), pos))
+}
+
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.len() >= 18 && data[0] == 0x1F && data[1] == 0x8B
+}
+
+/// Size of the decompressed content, according to the gzip trailer.
+pub fn gunzip_size(data: &[u8]) -> Result<usize> {
+    if !is_gzip(data) {
+        return Err(Error::InvalidHeader);
+    }
+    Ok(LE::read_u32(&data[data.len() - 4..]) as usize)
+}
+
+/// Decompress a gzip file into `out`, which must be exactly of the size given by `gunzip_size`.
+pub fn gunzip(data: &[u8], out: &mut [u8]) -> Result<()> {
+    if !is_gzip(data) || data[2] != 8 {
+        return Err(Error::InvalidHeader);
+    }
+    let flags = data[3];
+
+    // Skip MTIME, XFL and OS.
+    let mut offset = 10;
+    if flags & GZIP_FEXTRA != 0 {
+        let xlen = LE::read_u16(data.get(offset..offset + 2).ok_or(Error::InvalidHeader)?);
+        offset += 2 + xlen as usize;
+    }
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            let len = data
+                .get(offset..)
+                .and_then(|x| x.iter().position(|&x| x == 0))
+                .ok_or(Error::InvalidHeader)?;
+            offset += len + 1;
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        offset += 2;
+    }
+    if offset + 8 > data.len() {
+        return Err(Error::InvalidHeader);
+    }
+
+    let (consumed, size) = inflate(&data[offset..data.len() - 8], out)?;
+    let trailer = &data[offset + consumed..];
+    if size != out.len() || LE::read_u32(&trailer[4..]) as usize != size {
+        return Err(Error::SizeMismatch);
+    }
+    if LE::read_u32(trailer) != crate::crc::crc32(0, out) {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok(())
+}
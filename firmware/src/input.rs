@@ -0,0 +1,87 @@
+//! Console input from all available input devices.
+
+use spin::Mutex;
+
+const QUEUE_SIZE: usize = 16;
+
+/// Characters decoded from input devices but not yet consumed.
+///
+/// A single key press may produce multiple characters, e.g. escape sequences for arrow keys.
+struct Queue {
+    buf: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            buf: [0; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        // Drop input if nobody is reading it.
+        if self.len == QUEUE_SIZE {
+            return;
+        }
+        self.buf[(self.head + self.len) % QUEUE_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue::new());
+
+pub fn try_getchar() -> Option<u8> {
+    let mut queue = QUEUE.lock();
+    if let Some(byte) = crate::uart::uart_try_recv_byte() {
+        queue.push(byte);
+    }
+    #[cfg(has_ps2)]
+    crate::ps2::poll(|byte| queue.push(byte));
+    queue.pop()
+}
+
+pub fn getchar() -> u8 {
+    loop {
+        if let Some(byte) = try_getchar() {
+            return byte;
+        }
+    }
+}
+
+/// Read a line with echo and basic editing, starting with `initial`.
+pub fn read_line(initial: &str) -> alloc::string::String {
+    let mut line = alloc::string::String::from(initial);
+    print!("{}", line);
+    loop {
+        match getchar() {
+            b'\r' | b'\n' => break,
+            // Backspace or DEL
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            byte @ 0x20..=0x7E => {
+                line.push(byte as char);
+                print!("{}", byte as char);
+            }
+            _ => (),
+        }
+    }
+    println!("");
+    line
+}
@@ -35,17 +35,31 @@ mod address {
 }
 
 mod allocator;
+mod crc;
+mod dt;
 mod elf;
+#[allow(dead_code)]
+mod fdt;
+mod image;
+mod inflate;
+mod input;
 mod interp;
 mod ipi;
 mod memory;
+mod menu;
 mod misalign;
+#[cfg(has_net)]
+mod net;
+mod plic;
+#[cfg(has_ps2)]
+mod ps2;
 mod sbi;
 mod timer;
 #[allow(dead_code)]
 mod uart;
 #[cfg(has_display)]
 mod video;
+mod ymodem;
 
 use core::arch::asm;
 use core::sync::atomic::{AtomicUsize, Ordering};
@@ -90,46 +104,366 @@ pub struct TrapInfo {
     pub tval: usize,
 }
 
-fn load_kernel() -> alloc::vec::Vec<u8> {
-    use alloc::sync::Arc;
-    use io::Read;
+/// File names recognised as kernels, in order of preference.
+const KERNEL_NAMES: &[&str] = &["kernel", "Image", "Image.gz", "vmlinux", "vmlinux.gz"];
 
-    let sd = Arc::new(unsafe { block::Sd::new(crate::address::SD_BASE) });
-    sd.power_on();
+struct BootFiles {
+    /// Size of memory occupied by the loaded kernel, relative to `MEMORY_BASE`.
+    kernel_size: usize,
+    entry: usize,
+    initrd: Option<alloc::vec::Vec<u8>>,
+    /// Device tree with all fixups applied. The initrd properties are present but not yet set.
+    fdt: fdt::Fdt,
+}
 
-    // let part = Arc::new(block::Part::first_partition(sd.clone()).unwrap());
+/// Build the device tree to hand over to the kernel.
+fn device_tree(dtb: Option<&[u8]>, bootargs: Option<&str>, initrd: bool) -> fdt::Fdt {
+    let mut fdt = dt::load(dtb);
 
-    let fs = fs::ext::FileSystem::new(sd.clone()).unwrap();
-    let root = fs.root().unwrap();
+    // Reserve 2MB for the firmware.
+    let memory_size = address::MEMORY_SIZE - 0x200000;
+    if let Err(err) = dt::fixup_memory(&mut fdt, address::MEMORY_BASE, memory_size) {
+        println!("Cannot fix up device tree ({:?}), using built-in one", err);
+        fdt = dt::load(None);
+        dt::fixup_memory(&mut fdt, address::MEMORY_BASE, memory_size).unwrap();
+    }
+    dt::fixup_cpus(&mut fdt, hart_count());
+    #[cfg(has_display)]
+    dt::fixup_framebuffer(&mut fdt, &video::framebuffer_info());
+    if let Some(bootargs) = bootargs {
+        dt::set_bootargs(&mut fdt, bootargs);
+    }
+    // Insert the properties now so the size of the DTB is final.
+    if initrd {
+        dt::set_initrd(&mut fdt, 0, 0);
+    }
+    fdt
+}
 
-    let mut kernel: Option<fs::ext::File> = None;
+/// Memory needed right after the kernel for the DTB and the page-aligned initrd following it.
+fn boot_files_size(fdt: &fdt::Fdt, initrd: Option<&[u8]>) -> usize {
+    ((fdt.to_bytes().len() + 4095) & !4095) + initrd.map_or(0, |initrd| initrd.len())
+}
 
-    for entry in root {
-        let entry = entry.unwrap();
-        if !entry.is_dir() {
-            println!("/{}", entry.file_name());
+fn read_file(
+    sd: &block::Sd,
+    name: &str,
+    mut file: alloc::boxed::Box<dyn fs::File + '_>,
+) -> io::Result<alloc::vec::Vec<u8>> {
+    let size = file.size() as usize;
 
-            if entry.file_name() == "kernel" || entry.file_name() == "vmlinux" {
-                kernel = Some(entry.open().unwrap());
-            }
-        }
+    println!("Loading {}, size = {}KiB", name, size / 1024);
+    let mut buffer = alloc::vec::Vec::with_capacity(size);
+    unsafe { buffer.set_len(size) };
+    let time = timer::time();
+    file.read_exact(&mut buffer)?;
+    let elapsed = timer::time() - time;
+    let (mode, clock) = sd.bus_speed();
+    println!("Elapsed: {:?} (SD {} at {}MHz)", elapsed, mode, clock);
+
+    Ok(buffer)
+}
+
+/// Read a file that booting cannot do without.
+fn read_required_file(
+    sd: &block::Sd,
+    name: &str,
+    file: alloc::boxed::Box<dyn fs::File + '_>,
+) -> alloc::vec::Vec<u8> {
+    match read_file(sd, name, file) {
+        Ok(v) => v,
+        Err(err) => panic!("Cannot read {}: {:?}", name, err),
     }
+}
 
-    let mut kernel = kernel.expect("Cannot locate kernel");
-    let size = kernel.size() as usize;
+/// Decompress the kernel if it is gzip-compressed.
+///
+/// The decompressed size is taken from the gzip trailer, and must not exceed `max_size`.
+fn decompress_kernel(
+    kernel: alloc::vec::Vec<u8>,
+    max_size: usize,
+) -> inflate::Result<alloc::vec::Vec<u8>> {
+    if !inflate::is_gzip(&kernel) {
+        return Ok(kernel);
+    }
 
-    println!("Loading kernel, size = {}KiB", size / 1024);
-    let mut buffer = alloc::vec::Vec::with_capacity(size);
-    unsafe { buffer.set_len(size) };
+    let size = inflate::gunzip_size(&kernel)?;
+    if size > max_size {
+        return Err(inflate::Error::TooLarge);
+    }
+    println!("Decompressing kernel, size = {}KiB", size / 1024);
+    let mut buffer = alloc::vec::Vec::new();
+    buffer
+        .try_reserve_exact(size)
+        .map_err(|_| inflate::Error::OutOfMemory)?;
+    buffer.resize(size, 0);
     let time = timer::time();
-    kernel.read_exact(&mut buffer).unwrap();
+    inflate::gunzip(&kernel, &mut buffer)?;
     let elapsed = timer::time() - time;
     println!("Elapsed: {:?}", elapsed);
 
+    Ok(buffer)
+}
+
+/// Open the filesystem to boot from.
+///
+/// This is the partition named or labelled "boot" if there is one, otherwise the first Linux
+/// partition with a supported filesystem, or failing that the first partition with one. Cards
+/// without a partition table are used as a whole.
+fn open_boot_fs(sd: alloc::sync::Arc<block::Sd>) -> alloc::boxed::Box<dyn fs::FileSystem> {
+    use alloc::sync::Arc;
+
+    let parts = block::partitions(&*sd).unwrap_or_else(|err| {
+        println!("Cannot read partition table: {:?}", err);
+        alloc::vec::Vec::new()
+    });
+    if parts.is_empty() {
+        return fs::mount(sd).expect("Cannot mount filesystem");
+    }
+
+    let mut fallback = None;
+    for part in &parts {
+        println!(
+            "Partition {}: type {}, name \"{}\", offset {}KiB, size {}KiB",
+            part.number,
+            part.ty,
+            part.name,
+            part.offset / 1024,
+            part.len / 1024
+        );
+    }
+    for part in &parts {
+        let fs = match fs::mount(Arc::new(part.open(sd.clone()))) {
+            Ok(fs) => fs,
+            Err(_) => continue,
+        };
+        if part.name == "boot" || fs.label() == "boot" {
+            println!("Booting from partition {}", part.number);
+            return fs;
+        }
+        let linux = part.ty.is_linux();
+        if fallback
+            .as_ref()
+            .map_or(true, |&(fallback_linux, _, _)| linux && !fallback_linux)
+        {
+            fallback = Some((linux, part.number, fs));
+        }
+    }
+    match fallback {
+        Some((_, number, fs)) => {
+            println!("Booting from partition {}", number);
+            fs
+        }
+        None => {
+            // The partition table may be bogus, with the filesystem on the whole device.
+            println!("No partition can be mounted, trying the whole device");
+            fs::mount(sd).expect("Cannot mount filesystem")
+        }
+    }
+}
+
+/// Load boot files, and the first kernel candidate that loads successfully into memory at
+/// `MEMORY_BASE`, which must stay below `limit`.
+fn load_kernel(limit: usize) -> BootFiles {
+    use alloc::sync::Arc;
+
+    let sd = Arc::new(unsafe { block::Sd::new(crate::address::SD_BASE, crate::address::SD_IRQ) });
+    sd.power_on().expect("Cannot initialise SD card");
+
+    let fs = open_boot_fs(sd.clone());
+
+    let mut kernels: alloc::vec::Vec<(
+        alloc::string::String,
+        alloc::boxed::Box<dyn fs::File + '_>,
+    )> = alloc::vec::Vec::new();
+    let mut initrd: Option<alloc::boxed::Box<dyn fs::File + '_>> = None;
+    let mut cmdline: Option<alloc::boxed::Box<dyn fs::File + '_>> = None;
+    let mut dtb: Option<alloc::boxed::Box<dyn fs::File + '_>> = None;
+    // All regular files found, which may be chain-loaded from the boot menu.
+    let mut files = alloc::vec::Vec::new();
+
+    // Kernels may live in the root directory or in `/boot`, possibly behind a symlink such as
+    // `/boot/vmlinux -> vmlinux-6.1`. Files in the root directory take precedence.
+    for dir in ["/", "/boot/"] {
+        let entries = match fs.read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            // Only symlinks need to be resolved to find out whether they point to a file.
+            if !matches!(entry.file_type, fs::FileType::File | fs::FileType::Symlink) {
+                continue;
+            }
+            let path = alloc::format!("{}{}", dir, entry.name);
+            // Dangling symlinks fail to stat and are skipped.
+            let metadata = match fs.stat(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.file_type != fs::FileType::File {
+                continue;
+            }
+            println!("{} ({}KiB)", path, metadata.size / 1024);
+            files.push(path.clone());
+
+            // Files that cannot be opened are skipped, leaving the slot to later candidates.
+            let open = |path: &str| match fs.open(path) {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    println!("Cannot open {}: {:?}", path, err);
+                    None
+                }
+            };
+            match entry.name.as_str() {
+                name if KERNEL_NAMES.contains(&name) => {
+                    if let Some(file) = open(&path) {
+                        kernels.push((path, file))
+                    }
+                }
+                "initrd" | "initrd.img" | "initramfs.img" if initrd.is_none() => {
+                    initrd = open(&path)
+                }
+                "cmdline" if cmdline.is_none() => cmdline = open(&path),
+                name if name.ends_with(".dtb") && dtb.is_none() => dtb = open(&path),
+                _ => (),
+            }
+        }
+    }
+
+    // The sort is stable, so for the same name the root directory still comes first.
+    kernels.sort_by_key(|(path, _)| {
+        let name = path.rsplit('/').next().unwrap();
+        KERNEL_NAMES.iter().position(|&x| x == name)
+    });
+
+    let bootargs = cmdline.map(|cmdline| {
+        let cmdline = read_required_file(&sd, "cmdline", cmdline);
+        let cmdline = core::str::from_utf8(&cmdline).expect("cmdline is not UTF-8");
+        cmdline.trim_end().into()
+    });
+
+    let kernel_names: alloc::vec::Vec<&str> =
+        kernels.iter().map(|(path, _)| path.as_str()).collect();
+    let file_names: alloc::vec::Vec<&str> = files.iter().map(|path| path.as_str()).collect();
+    let options = menu::prompt(
+        &sd,
+        &kernel_names,
+        &file_names,
+        bootargs.as_deref(),
+        address::MEMORY_BASE..limit,
+    );
+    let bootargs = options.bootargs.or(bootargs);
+
+    // Files uploaded over serial take precedence over those on the SD card.
+    if let Some(upload) = options.upload {
+        let (name, kernel) = upload.kernel;
+        let initrd = upload
+            .initrd
+            .or_else(|| initrd.map(|initrd| read_required_file(&sd, "initrd", initrd)));
+        let dtb = upload
+            .dtb
+            .or_else(|| dtb.map(|dtb| read_required_file(&sd, "device tree", dtb)));
+        let fdt = device_tree(dtb.as_deref(), bootargs.as_deref(), initrd.is_some());
+        let reserve = boot_files_size(&fdt, initrd.as_deref());
+        let (kernel_size, entry) =
+            match load_kernel_binary(kernel, address::MEMORY_BASE, limit, reserve, false) {
+                Ok(v) => v,
+                Err(err) => panic!("Cannot load {}: {:?}", name, err),
+            };
+        return BootFiles {
+            kernel_size,
+            entry,
+            initrd,
+            fdt,
+        };
+    }
+
+    let initrd = initrd.map(|initrd| read_required_file(&sd, "initrd", initrd));
+    let dtb = dtb.map(|dtb| read_required_file(&sd, "device tree", dtb));
+    let fdt = device_tree(dtb.as_deref(), bootargs.as_deref(), initrd.is_some());
+    let reserve = boot_files_size(&fdt, initrd.as_deref());
+    if let Some(name) = options.kernel {
+        let file = fs.open(&name).unwrap();
+        kernels = vec![(name, file)];
+    }
+    assert!(!kernels.is_empty(), "Cannot locate kernel");
+
+    // Candidates are read lazily, so later ones are only read if earlier ones fail to read or load.
+    let (kernel_size, entry) = kernels
+        .into_iter()
+        .find_map(|(name, file)| {
+            let kernel = read_file(&sd, &name, file)
+                .map_err(|err| println!("Cannot read {}: {:?}", name, err))
+                .ok()?;
+            load_kernel_binary(kernel, address::MEMORY_BASE, limit, reserve, options.chain)
+                .map_err(|err| println!("Cannot load {}: {:?}", name, err))
+                .ok()
+        })
+        .expect("No bootable kernel");
+
     drop(fs);
     drop(sd);
 
-    buffer
+    BootFiles {
+        kernel_size,
+        entry,
+        initrd,
+        fdt,
+    }
+}
+
+#[derive(Debug)]
+enum KernelError {
+    Inflate(inflate::Error),
+    Elf(elf::Error),
+    Image(image::Error),
+    TooLarge,
+    /// The kernel fits, but the device tree and initrd placed after it do not.
+    NoRoomForBootFiles,
+}
+
+/// Decompress and load a kernel into memory at `addr`, which must stay below `limit`.
+///
+/// `reserve` bytes are kept free between the end of the kernel and `limit` for the device tree
+/// and initrd. If `raw` is set, files that are neither ELF nor Image are loaded as flat binaries.
+///
+/// Returns the size of memory occupied relative to `addr`, and the entry point.
+fn load_kernel_binary(
+    kernel: alloc::vec::Vec<u8>,
+    addr: usize,
+    limit: usize,
+    reserve: usize,
+    raw: bool,
+) -> Result<(usize, usize), KernelError> {
+    let kernel = decompress_kernel(kernel, limit - addr).map_err(KernelError::Inflate)?;
+    let (size, entry) = if image::is_image(&kernel) {
+        unsafe { image::load_image(&kernel, addr, limit) }.map_err(KernelError::Image)?
+    } else {
+        match unsafe { elf::load_elf(&kernel, addr, limit) } {
+            Err(elf::Error::NotElf) if raw => {
+                let size = (kernel.len() + 4095) & !4095;
+                if size > limit - addr {
+                    return Err(KernelError::TooLarge);
+                }
+                unsafe {
+                    core::ptr::copy_nonoverlapping(kernel.as_ptr(), addr as *mut u8, kernel.len())
+                };
+                (size, addr)
+            }
+            result => result.map_err(KernelError::Elf)?,
+        }
+    };
+    if size > (limit - addr).saturating_sub(reserve) {
+        return Err(KernelError::NoRoomForBootFiles);
+    }
+    Ok((size, entry))
+}
+
+/// Information passed to `entry.S` for transferring control to the kernel.
+#[repr(C)]
+pub struct Handoff {
+    dtb: usize,
+    entry: usize,
 }
 
 // Functions only reachable during initialization may still be called after
@@ -143,8 +477,9 @@ extern "C" fn main_hot() {
 }
 
 #[no_mangle]
-extern "C" fn main(boot: bool) -> usize {
+extern "C" fn main(boot: bool) -> Handoff {
     static DTB_PTR: AtomicUsize = AtomicUsize::new(0);
+    static ENTRY: AtomicUsize = AtomicUsize::new(0);
 
     let hartid = hartid();
 
@@ -161,6 +496,8 @@ extern "C" fn main(boot: bool) -> usize {
 
         #[cfg(has_display)]
         video::init();
+        #[cfg(has_ps2)]
+        ps2::init();
 
         println!("Booting...");
 
@@ -181,25 +518,48 @@ extern "C" fn main(boot: bool) -> usize {
         //     core::slice::from_raw_parts_mut(address::MEMORY_BASE as *mut usize, kernel_memory_size / 8)
         // });
 
-        let kernel_size = allocator::scoped_with_memory(
+        // The upper half of kernel memory is used as scratch space while loading.
+        let scratch_start = address::MEMORY_BASE + kernel_memory_size / 2;
+
+        let (dtb_ptr, entry) = allocator::scoped_with_memory(
             unsafe {
-                core::slice::from_raw_parts_mut(
-                    (address::MEMORY_BASE + kernel_memory_size / 2) as *mut u8,
-                    kernel_memory_size / 2,
-                )
+                core::slice::from_raw_parts_mut(scratch_start as *mut u8, kernel_memory_size / 2)
             },
             || {
-                let elf_file = load_kernel();
-                let kernel_size = unsafe { elf::load_elf(&elf_file, address::MEMORY_BASE) };
-                kernel_size
+                let files = load_kernel(scratch_start);
+                let (kernel_size, entry) = (files.kernel_size, files.entry);
+
+                let mut fdt = files.fdt;
+
+                // Place DTB at the end of kernel, and initrd after DTB. `load_kernel` has already
+                // rejected kernels that leave no room for them, so the assertions cannot fail.
+                let dtb_ptr = address::MEMORY_BASE + kernel_size;
+                if let Some(initrd) = &files.initrd {
+                    let initrd_start = (dtb_ptr + fdt.to_bytes().len() + 4095) & !4095;
+                    let initrd_end = initrd_start + initrd.len();
+                    assert!(initrd_end <= scratch_start, "initrd too large");
+                    dt::set_initrd(&mut fdt, initrd_start, initrd_end);
+
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            initrd.as_ptr(),
+                            initrd_start as *mut u8,
+                            initrd.len(),
+                        )
+                    };
+                    println!("initrd placed at {:#x}", initrd_start);
+                }
+
+                let dtb = fdt.to_bytes();
+                assert!(dtb_ptr + dtb.len() <= scratch_start, "kernel too large");
+                unsafe {
+                    core::ptr::copy_nonoverlapping(dtb.as_ptr(), dtb_ptr as *mut u8, dtb.len())
+                };
+                (dtb_ptr, entry)
             },
         );
-
-        // Copy DTB to end of kenrel.
-        let dtb = include_bytes!(concat!(env!("OUT_DIR"), "/device_tree.dtb"));
-        let dtb_ptr = address::MEMORY_BASE + kernel_size;
         DTB_PTR.store(dtb_ptr, Ordering::Relaxed);
-        unsafe { core::ptr::copy_nonoverlapping(dtb.as_ptr(), dtb_ptr as *mut u8, dtb.len()) };
+        ENTRY.store(entry, Ordering::Relaxed);
 
         println!("Control transfer to kernel");
 
@@ -216,7 +576,10 @@ extern "C" fn main(boot: bool) -> usize {
 
     println!("Core {} up", hartid);
 
-    DTB_PTR.load(Ordering::Relaxed)
+    Handoff {
+        dtb: DTB_PTR.load(Ordering::Relaxed),
+        entry: ENTRY.load(Ordering::Relaxed),
+    }
 }
 
 /// Delegate a interrupt to S-mode
@@ -0,0 +1,249 @@
+//! Interactive boot menu.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::time::Duration;
+
+use crate::block::Sd;
+use crate::input;
+use crate::timer::Timer;
+use crate::ymodem;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Files received over serial or network.
+pub struct Upload {
+    pub kernel: (String, Vec<u8>),
+    pub initrd: Option<Vec<u8>>,
+    pub dtb: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct BootOptions {
+    /// Boot this file instead of searching for a kernel.
+    pub kernel: Option<String>,
+    /// Load `kernel` as a raw binary if it is neither ELF nor Image.
+    pub chain: bool,
+    pub bootargs: Option<String>,
+    pub upload: Option<Upload>,
+}
+
+/// Wait for a key press, returning whether one arrived before the timeout.
+fn wait_for_key() -> bool {
+    println!("Press any key to enter boot menu...");
+    let timer = Timer::new(TIMEOUT);
+    while !timer.fired() {
+        if input::try_getchar().is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Let the user pick one of `paths`.
+fn select_file(paths: &[&str]) -> Option<String> {
+    if paths.is_empty() {
+        println!("No files found");
+        return None;
+    }
+    for (i, path) in paths.iter().enumerate() {
+        println!("  {}) {}", i + 1, path);
+    }
+    print!("Select file: ");
+    let index: usize = input::read_line("").trim().parse().ok()?;
+    paths.get(index.checked_sub(1)?).map(|&path| path.into())
+}
+
+/// Receive files over serial, and classify them by name.
+///
+/// The files must fit in `max_size` bytes, the memory they are later loaded into.
+fn receive_upload(max_size: usize) -> Option<Upload> {
+    println!("Send kernel, and optionally device tree and initrd, using YMODEM now");
+    let files = match ymodem::receive(max_size) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Transfer failed: {:?}", err);
+            return None;
+        }
+    };
+
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut dtb = None;
+    for file in files {
+        println!(
+            "Received {}, size = {}KiB",
+            file.name,
+            file.data.len() / 1024
+        );
+        let name = file.name.as_str();
+        if name.ends_with(".dtb") {
+            dtb = Some(file.data);
+        } else if name.contains("initrd")
+            || name.contains("initramfs")
+            || name.ends_with(".cpio")
+            || name.ends_with(".cpio.gz")
+        {
+            initrd = Some(file.data);
+        } else {
+            kernel = Some((file.name, file.data));
+        }
+    }
+
+    match kernel {
+        Some(kernel) => Some(Upload {
+            kernel,
+            initrd,
+            dtb,
+        }),
+        None => {
+            println!("No kernel received");
+            None
+        }
+    }
+}
+
+/// Default boot file if DHCP does not provide one.
+#[cfg(has_net)]
+const NETBOOT_KERNEL: &str = "Image";
+
+/// Fetch the kernel named by DHCP over TFTP, and initrd, device tree and cmdline next to it.
+///
+/// `bootargs` is set from the fetched cmdline unless already set. The files must fit in
+/// `max_size` bytes, the memory they are later loaded into.
+#[cfg(has_net)]
+fn netboot(bootargs: &mut Option<String>, max_size: usize) -> crate::io::Result<Option<Upload>> {
+    use crate::net::{self, dhcp, tftp, Interface};
+
+    let mut iface = Interface::new(net::open()?);
+
+    let lease = dhcp::configure(&mut iface)?;
+    println!("IP address: {}/{}", lease.ip, lease.netmask);
+    let server = lease.boot_server.unwrap_or(lease.server_id);
+    let kernel_name = lease.boot_file.as_deref().unwrap_or(NETBOOT_KERNEL);
+    let dir = match kernel_name.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/", dir),
+        None => String::new(),
+    };
+
+    let mut remaining = max_size;
+    let mut fetch = |name: &str| -> crate::io::Result<Option<Vec<u8>>> {
+        let time = crate::timer::time();
+        let data = tftp::read(&mut iface, server, name, remaining)?;
+        if let Some(data) = &data {
+            remaining -= data.len();
+            println!(
+                "Fetched {}:{}, size = {}KiB",
+                server,
+                name,
+                data.len() / 1024
+            );
+            println!("Elapsed: {:?}", crate::timer::time() - time);
+        }
+        Ok(data)
+    };
+
+    let kernel = match fetch(kernel_name)? {
+        Some(v) => v,
+        None => {
+            println!("{} not found on {}", kernel_name, server);
+            return Ok(None);
+        }
+    };
+    let initrd = fetch(&format!("{}initrd", dir))?;
+    let dtb = fetch(&format!("{}device_tree.dtb", dir))?;
+    if bootargs.is_none() {
+        if let Some(cmdline) = fetch(&format!("{}cmdline", dir))? {
+            match core::str::from_utf8(&cmdline) {
+                Ok(cmdline) => *bootargs = Some(cmdline.trim_end().into()),
+                Err(_) => println!("cmdline is not UTF-8"),
+            }
+        }
+    }
+
+    Ok(Some(Upload {
+        kernel: (kernel_name.into(), kernel),
+        initrd,
+        dtb,
+    }))
+}
+
+/// Show the boot prompt, and the menu if a key is pressed before the timeout.
+///
+/// `kernels` are the kernel candidates found on the boot filesystem, and `files` all regular files
+/// that may be chain-loaded. `memory` is the range that is free to be used by the memory test.
+pub fn prompt(
+    sd: &Sd,
+    kernels: &[&str],
+    files: &[&str],
+    bootargs: Option<&str>,
+    memory: Range<usize>,
+) -> BootOptions {
+    let mut options = BootOptions::default();
+    if !wait_for_key() {
+        return options;
+    }
+
+    loop {
+        println!("\nBoot menu");
+        println!(
+            "  1) Boot {}",
+            options.kernel.as_deref().unwrap_or("default kernel")
+        );
+        println!("  2) Select kernel");
+        println!("  3) Edit bootargs");
+        println!("  4) Memory test");
+        println!("  5) Dump SD controller registers");
+        println!("  6) Chain-load image");
+        println!("  7) Receive files over serial");
+        #[cfg(has_net)]
+        println!("  8) Network boot");
+        print!("> ");
+
+        match input::read_line("").trim() {
+            "1" => return options,
+            "2" => {
+                if let Some(name) = select_file(kernels) {
+                    options.kernel = Some(name);
+                    options.chain = false;
+                }
+            }
+            "3" => {
+                let current = options.bootargs.as_deref().or(bootargs).unwrap_or("");
+                print!("bootargs: ");
+                options.bootargs = Some(input::read_line(current));
+            }
+            "4" => crate::memtest::memtest(unsafe {
+                core::slice::from_raw_parts_mut(
+                    memory.start as *mut usize,
+                    memory.len() / core::mem::size_of::<usize>(),
+                )
+            }),
+            "5" => sd.dump_registers(),
+            "6" => {
+                if let Some(name) = select_file(files) {
+                    options.kernel = Some(name);
+                    options.chain = true;
+                    return options;
+                }
+            }
+            "7" => {
+                if let Some(upload) = receive_upload(memory.len()) {
+                    options.upload = Some(upload);
+                    return options;
+                }
+            }
+            #[cfg(has_net)]
+            "8" => match netboot(&mut options.bootargs, memory.len()) {
+                Ok(Some(upload)) => {
+                    options.upload = Some(upload);
+                    return options;
+                }
+                Ok(None) => (),
+                Err(err) => println!("Network boot failed: {:?}", err),
+            },
+            _ => println!("Invalid choice"),
+        }
+    }
+}
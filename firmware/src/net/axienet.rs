@@ -0,0 +1,300 @@
+//! Driver for the Xilinx AXI 1G Ethernet subsystem, with the AXI DMA in scatter-gather mode.
+
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{fence, Ordering};
+use core::time::Duration;
+
+use super::phy::{self, Mdio};
+use super::NetDevice;
+use crate::io::{Error, Result};
+use crate::iomem::IoMem;
+use crate::timer::Timer;
+
+// MAC registers
+const RCW1: usize = 0x404;
+const TC: usize = 0x408;
+const EMMC: usize = 0x410;
+const MDIO_MC: usize = 0x500;
+const MDIO_MCR: usize = 0x504;
+const MDIO_MWD: usize = 0x508;
+const MDIO_MRD: usize = 0x50C;
+const UAW0: usize = 0x700;
+const UAW1: usize = 0x704;
+const FMI: usize = 0x708;
+
+const RCW1_RX: u32 = 0x1000_0000;
+const TC_TX: u32 = 0x1000_0000;
+const EMMC_1000: u32 = 0x8000_0000;
+const EMMC_100: u32 = 0x4000_0000;
+/// MDIO enable, with the slowest clock so any AXI clock frequency stays within 2.5MHz.
+const MDIO_MC_ENABLE: u32 = 0x40 | 0x3F;
+const MDIO_MCR_READ: u32 = 0x8000;
+const MDIO_MCR_WRITE: u32 = 0x4000;
+const MDIO_MCR_INITIATE: u32 = 0x800;
+const MDIO_MCR_READY: u32 = 0x80;
+
+// DMA registers
+const MM2S_CR: usize = 0x00;
+const MM2S_CURDESC: usize = 0x08;
+const MM2S_TAILDESC: usize = 0x10;
+const S2MM_CR: usize = 0x30;
+const S2MM_CURDESC: usize = 0x38;
+const S2MM_TAILDESC: usize = 0x40;
+
+const CR_RUNSTOP: u32 = 0x1;
+const CR_RESET: u32 = 0x4;
+
+const CONTROL_SOF: u32 = 0x0800_0000;
+const CONTROL_EOF: u32 = 0x0400_0000;
+const STATUS_COMPLETE: u32 = 0x8000_0000;
+const STATUS_ERROR: u32 = 0x7000_0000;
+const STATUS_LENGTH: u32 = 0x03FF_FFFF;
+
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+const RX_COUNT: usize = 16;
+const TX_COUNT: usize = 4;
+const BUFFER_SIZE: usize = 1536;
+
+#[repr(C, align(64))]
+struct Descriptor {
+    next: u64,
+    buffer: u64,
+    _reserved: u64,
+    control: u32,
+    status: u32,
+    _app: [u32; 5],
+}
+
+#[repr(C, align(64))]
+struct Buffer([u8; BUFFER_SIZE]);
+
+#[repr(C)]
+struct Rings {
+    rx: [Descriptor; RX_COUNT],
+    tx: [Descriptor; TX_COUNT],
+    rx_buffer: [Buffer; RX_COUNT],
+    tx_buffer: [Buffer; TX_COUNT],
+}
+
+pub struct AxiEthernet {
+    mac: IoMem<0x800>,
+    dma: IoMem<0x80>,
+    address: [u8; 6],
+    phy: u8,
+    rings: Box<Rings>,
+    rx_next: usize,
+    tx_next: usize,
+}
+
+impl AxiEthernet {
+    /// Reset the controller, negotiate the link and start the DMA rings.
+    ///
+    /// # Safety
+    /// `mac_base` and `dma_base` must be the register blocks of an AXI Ethernet and its AXI DMA,
+    /// not used by anything else while this driver exists.
+    pub unsafe fn new(mac_base: usize, dma_base: usize, address: [u8; 6]) -> Result<Self> {
+        // Box::new would construct the rings on the stack first, which is too large for it.
+        let rings = unsafe {
+            let ptr = alloc_zeroed(Layout::new::<Rings>()) as *mut Rings;
+            assert!(!ptr.is_null());
+            Box::from_raw(ptr)
+        };
+        let mut eth = AxiEthernet {
+            mac: unsafe { IoMem::new(mac_base) },
+            dma: unsafe { IoMem::new(dma_base) },
+            address,
+            phy: 0,
+            rings,
+            rx_next: 0,
+            tx_next: 0,
+        };
+
+        eth.reset_dma()?;
+
+        eth.mac.write_u32(
+            UAW0,
+            u32::from_le_bytes([address[0], address[1], address[2], address[3]]),
+        );
+        eth.mac
+            .write_u32(UAW1, u16::from_le_bytes([address[4], address[5]]) as u32);
+        // Not promiscuous.
+        eth.mac.write_u32(FMI, 0);
+
+        eth.mac.write_u32(MDIO_MC, MDIO_MC_ENABLE);
+        eth.negotiate()?;
+
+        eth.start_dma();
+        eth.mac.write_u32(RCW1, eth.mac.read_u32(RCW1) | RCW1_RX);
+        eth.mac.write_u32(TC, eth.mac.read_u32(TC) | TC_TX);
+        Ok(eth)
+    }
+
+    fn reset_dma(&self) -> Result<()> {
+        // Resetting either channel resets the whole DMA engine.
+        self.dma.write_u32(MM2S_CR, CR_RESET);
+        let timer = Timer::new(Duration::from_millis(100));
+        while self.dma.read_u32(MM2S_CR) & CR_RESET != 0 {
+            if timer.fired() {
+                return Err(Error::Textual("DMA reset timeout"));
+            }
+        }
+        Ok(())
+    }
+
+    fn mdio_wait(&self) -> Result<()> {
+        let timer = Timer::new(Duration::from_millis(10));
+        while self.mac.read_u32(MDIO_MCR) & MDIO_MCR_READY == 0 {
+            if timer.fired() {
+                return Err(Error::Textual("MDIO timeout"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run auto-negotiation and configure the MAC for the resulting speed.
+    fn negotiate(&mut self) -> Result<()> {
+        self.phy = phy::probe(self)?;
+        let link = phy::negotiate(self, self.phy, true)?;
+        println!(
+            "Ethernet link up at {}Mbps {} duplex",
+            link.speed,
+            if link.full_duplex { "full" } else { "half" }
+        );
+        let emmc = match link.speed {
+            1000 => EMMC_1000,
+            100 => EMMC_100,
+            _ => 0,
+        };
+        self.mac.write_u32(EMMC, emmc);
+        Ok(())
+    }
+
+    fn start_dma(&mut self) {
+        let rings = &mut *self.rings;
+        for i in 0..RX_COUNT {
+            rings.rx[i].next = addr_of!(rings.rx[(i + 1) % RX_COUNT]) as u64;
+            rings.rx[i].buffer = addr_of!(rings.rx_buffer[i]) as u64;
+            rings.rx[i].control = BUFFER_SIZE as u32;
+        }
+        for i in 0..TX_COUNT {
+            rings.tx[i].next = addr_of!(rings.tx[(i + 1) % TX_COUNT]) as u64;
+            rings.tx[i].buffer = addr_of!(rings.tx_buffer[i]) as u64;
+        }
+        let rx_head = addr_of!(rings.rx[0]) as u64;
+        let rx_tail = addr_of!(rings.rx[RX_COUNT - 1]) as u64;
+        let tx_head = addr_of!(rings.tx[0]) as u64;
+        fence(Ordering::SeqCst);
+
+        self.write_desc_reg(S2MM_CURDESC, rx_head);
+        self.dma.write_u32(S2MM_CR, CR_RUNSTOP);
+        self.write_desc_reg(S2MM_TAILDESC, rx_tail);
+
+        self.write_desc_reg(MM2S_CURDESC, tx_head);
+        self.dma.write_u32(MM2S_CR, CR_RUNSTOP);
+    }
+
+    fn write_desc_reg(&self, offset: usize, addr: u64) {
+        self.dma.write_u32(offset, addr as u32);
+        self.dma.write_u32(offset + 4, (addr >> 32) as u32);
+    }
+}
+
+impl Mdio for AxiEthernet {
+    fn mdio_read(&self, phy: u8, reg: u8) -> Result<u16> {
+        self.mdio_wait()?;
+        self.mac.write_u32(
+            MDIO_MCR,
+            (phy as u32) << 24 | (reg as u32) << 16 | MDIO_MCR_READ | MDIO_MCR_INITIATE,
+        );
+        self.mdio_wait()?;
+        Ok(self.mac.read_u32(MDIO_MRD) as u16)
+    }
+
+    fn mdio_write(&self, phy: u8, reg: u8, value: u16) -> Result<()> {
+        self.mdio_wait()?;
+        self.mac.write_u32(MDIO_MWD, value as u32);
+        self.mac.write_u32(
+            MDIO_MCR,
+            (phy as u32) << 24 | (reg as u32) << 16 | MDIO_MCR_WRITE | MDIO_MCR_INITIATE,
+        );
+        self.mdio_wait()
+    }
+}
+
+impl NetDevice for AxiEthernet {
+    fn mac_address(&self) -> [u8; 6] {
+        self.address
+    }
+
+    fn link_up(&self) -> bool {
+        phy::link_up(self, self.phy).unwrap_or(false)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        assert!(frame.len() <= BUFFER_SIZE);
+        let index = self.tx_next;
+        let desc = addr_of_mut!(self.rings.tx[index]);
+        self.rings.tx_buffer[index].0[..frame.len()].copy_from_slice(frame);
+        unsafe {
+            (*desc).control = frame.len() as u32 | CONTROL_SOF | CONTROL_EOF;
+            addr_of_mut!((*desc).status).write_volatile(0);
+        }
+        fence(Ordering::SeqCst);
+        self.write_desc_reg(MM2S_TAILDESC, desc as u64);
+        self.tx_next = (index + 1) % TX_COUNT;
+
+        let timer = Timer::new(TX_TIMEOUT);
+        loop {
+            let status = unsafe { addr_of!((*desc).status).read_volatile() };
+            if status & STATUS_ERROR != 0 {
+                return Err(Error::Textual("Ethernet transmit error"));
+            }
+            if status & STATUS_COMPLETE != 0 {
+                return Ok(());
+            }
+            if timer.fired() {
+                return Err(Error::Textual("Ethernet transmit timeout"));
+            }
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Option<usize> {
+        loop {
+            let index = self.rx_next;
+            let desc = addr_of_mut!(self.rings.rx[index]);
+            let status = unsafe { addr_of!((*desc).status).read_volatile() };
+            if status & STATUS_COMPLETE == 0 {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+
+            let len = core::cmp::min((status & STATUS_LENGTH) as usize, buf.len());
+            let ok = status & STATUS_ERROR == 0;
+            if ok {
+                buf[..len].copy_from_slice(&self.rings.rx_buffer[index].0[..len]);
+            }
+
+            // Hand the descriptor back to the DMA.
+            unsafe { addr_of_mut!((*desc).status).write_volatile(0) };
+            fence(Ordering::SeqCst);
+            self.write_desc_reg(S2MM_TAILDESC, desc as u64);
+            self.rx_next = (index + 1) % RX_COUNT;
+
+            if ok {
+                return Some(len);
+            }
+        }
+    }
+}
+
+impl Drop for AxiEthernet {
+    fn drop(&mut self) {
+        // Stop the DMA before the rings are freed.
+        self.mac.write_u32(RCW1, self.mac.read_u32(RCW1) & !RCW1_RX);
+        self.mac.write_u32(TC, self.mac.read_u32(TC) & !TC_TX);
+        let _ = self.reset_dma();
+    }
+}
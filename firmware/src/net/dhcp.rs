@@ -0,0 +1,249 @@
+//! DHCP client, obtaining an address and the boot file name.
+
+use alloc::string::String;
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+use super::{Interface, NetDevice, MAX_UDP_PAYLOAD};
+use crate::io::{Error, Result};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const RETRIES: u32 = 5;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Offsets into the fixed part of the message.
+const XID: usize = 4;
+const FLAGS: usize = 10;
+const YIADDR: usize = 16;
+const SIADDR: usize = 20;
+const CHADDR: usize = 28;
+const FILE: usize = 108;
+const COOKIE: usize = 236;
+const OPTIONS: usize = 240;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_TFTP_SERVER: u8 = 66;
+const OPT_BOOTFILE: u8 = 67;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Configuration handed out by the DHCP server.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    /// DHCP server that issued the lease.
+    pub server_id: Ipv4Addr,
+    /// TFTP server to boot from.
+    pub boot_server: Option<Ipv4Addr>,
+    pub boot_file: Option<String>,
+}
+
+fn parse_addr(data: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Parse a NUL-padded string field or option.
+fn parse_str(data: &[u8]) -> Option<String> {
+    let end = data.iter().position(|&x| x == 0).unwrap_or(data.len());
+    match core::str::from_utf8(&data[..end]) {
+        Ok(s) if !s.is_empty() => Some(s.into()),
+        _ => None,
+    }
+}
+
+/// Iterate over the options of a message as `(code, value)` pairs.
+fn options(msg: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = msg.get(OPTIONS..).unwrap_or(&[]);
+    core::iter::from_fn(move || loop {
+        match *rest {
+            [OPT_PAD, ref tail @ ..] => rest = tail,
+            [OPT_END, ..] | [] | [_] => return None,
+            [code, len, ref tail @ ..] => {
+                let len = len as usize;
+                if tail.len() < len {
+                    return None;
+                }
+                rest = &tail[len..];
+                return Some((code, &tail[..len]));
+            }
+        }
+    })
+}
+
+fn option(msg: &[u8], code: u8) -> Option<&[u8]> {
+    options(msg).find(|(x, _)| *x == code).map(|(_, v)| v)
+}
+
+struct Builder {
+    buf: [u8; 300],
+    len: usize,
+}
+
+impl Builder {
+    fn new(xid: u32, mac: [u8; 6]) -> Self {
+        let mut buf = [0; 300];
+        // Ethernet, 6-byte hardware address
+        buf[0..3].copy_from_slice(&[OP_REQUEST, 1, 6]);
+        buf[XID..XID + 4].copy_from_slice(&xid.to_be_bytes());
+        // Ask for broadcast replies, as we cannot receive unicast before we have an address.
+        buf[FLAGS..FLAGS + 2].copy_from_slice(&0x8000u16.to_be_bytes());
+        buf[CHADDR..CHADDR + 6].copy_from_slice(&mac);
+        buf[COOKIE..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+        Builder { buf, len: OPTIONS }
+    }
+
+    fn option(&mut self, code: u8, value: &[u8]) -> &mut Self {
+        self.buf[self.len] = code;
+        self.buf[self.len + 1] = value.len() as u8;
+        self.buf[self.len + 2..self.len + 2 + value.len()].copy_from_slice(value);
+        self.len += 2 + value.len();
+        self
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.buf[self.len] = OPT_END;
+        self.len += 1;
+        &self.buf[..self.len]
+    }
+}
+
+/// Wait for a reply to transaction `xid` with one of the given message types.
+fn recv_reply<D: NetDevice>(
+    iface: &mut Interface<D>,
+    buf: &mut [u8],
+    xid: u32,
+    types: &[u8],
+) -> Option<usize> {
+    let timer = crate::timer::Timer::new(TIMEOUT);
+    while !timer.fired() {
+        let remaining = timer.0.saturating_sub(crate::timer::time());
+        let (meta, len) = iface.recv_udp(CLIENT_PORT, buf, remaining)?;
+        let msg = &buf[..len];
+        if meta.src_port != SERVER_PORT
+            || len < OPTIONS
+            || msg[0] != OP_REPLY
+            || msg[XID..XID + 4] != xid.to_be_bytes()
+            || msg[CHADDR..CHADDR + 6] != iface.mac_address()
+            || msg[COOKIE..OPTIONS] != MAGIC_COOKIE
+        {
+            continue;
+        }
+        match option(msg, OPT_MESSAGE_TYPE) {
+            Some(&[ty]) if types.contains(&ty) => return Some(len),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn parse_lease(msg: &[u8]) -> Option<Lease> {
+    let ip = parse_addr(&msg[YIADDR..])?;
+    let server_id = parse_addr(option(msg, OPT_SERVER_ID)?)?;
+    let netmask = option(msg, OPT_SUBNET_MASK)
+        .and_then(parse_addr)
+        .unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+    let gateway = option(msg, OPT_ROUTER).and_then(parse_addr);
+
+    // Options take precedence over the legacy BOOTP fields.
+    let boot_server = option(msg, OPT_TFTP_SERVER)
+        .and_then(parse_str)
+        .and_then(|x| x.parse().ok())
+        .or_else(|| parse_addr(&msg[SIADDR..]).filter(|x| !x.is_unspecified()));
+    let boot_file = option(msg, OPT_BOOTFILE)
+        .and_then(parse_str)
+        .or_else(|| parse_str(&msg[FILE..FILE + 128]));
+
+    Some(Lease {
+        ip,
+        netmask,
+        gateway,
+        server_id,
+        boot_server,
+        boot_file,
+    })
+}
+
+/// Obtain a lease, and configure the interface with it.
+pub fn configure<D: NetDevice>(iface: &mut Interface<D>) -> Result<Lease> {
+    let mac = iface.mac_address();
+    let params = [OPT_SUBNET_MASK, OPT_ROUTER, OPT_TFTP_SERVER, OPT_BOOTFILE];
+    let mut buf = [0; MAX_UDP_PAYLOAD];
+
+    if !iface.link_up() {
+        return Err(Error::Textual("no Ethernet link"));
+    }
+
+    for attempt in 0..RETRIES {
+        let xid = crate::timer::time_u64() as u32 ^ attempt;
+
+        let mut discover = Builder::new(xid, mac);
+        discover
+            .option(OPT_MESSAGE_TYPE, &[DHCPDISCOVER])
+            .option(OPT_PARAMETER_LIST, &params);
+        iface.send_udp(
+            CLIENT_PORT,
+            Ipv4Addr::BROADCAST,
+            SERVER_PORT,
+            discover.finish(),
+        )?;
+        let len = match recv_reply(iface, &mut buf, xid, &[DHCPOFFER]) {
+            Some(v) => v,
+            None => continue,
+        };
+        let offer = match parse_lease(&buf[..len]) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let mut request = Builder::new(xid, mac);
+        request
+            .option(OPT_MESSAGE_TYPE, &[DHCPREQUEST])
+            .option(OPT_REQUESTED_IP, &offer.ip.octets())
+            .option(OPT_SERVER_ID, &offer.server_id.octets())
+            .option(OPT_PARAMETER_LIST, &params);
+        iface.send_udp(
+            CLIENT_PORT,
+            Ipv4Addr::BROADCAST,
+            SERVER_PORT,
+            request.finish(),
+        )?;
+        let len = match recv_reply(iface, &mut buf, xid, &[DHCPACK, DHCPNAK]) {
+            Some(v) => v,
+            None => continue,
+        };
+        if option(&buf[..len], OPT_MESSAGE_TYPE) == Some(&[DHCPNAK]) {
+            continue;
+        }
+        let lease = match parse_lease(&buf[..len]) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        iface.ip = lease.ip;
+        iface.netmask = lease.netmask;
+        iface.gateway = lease.gateway;
+        return Ok(lease);
+    }
+    Err(Error::Textual("DHCP timeout"))
+}
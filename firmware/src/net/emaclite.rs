@@ -0,0 +1,232 @@
+//! Driver for the Xilinx AXI EthernetLite MAC.
+//!
+//! The controller has no DMA: frames are copied word by word through its dual-port buffers, using
+//! both ping and pong buffers in each direction when the hardware is configured with them.
+
+use core::time::Duration;
+
+use super::phy::{self, Mdio};
+use super::{NetDevice, MAX_FRAME_SIZE};
+use crate::io::{Error, Result};
+use crate::iomem::IoMem;
+use crate::timer::Timer;
+
+const TX_PING: usize = 0x0000;
+const TX_PONG: usize = 0x0800;
+const RX_PING: usize = 0x1000;
+const RX_PONG: usize = 0x1800;
+
+// Offsets of registers relative to a TX or RX buffer.
+const TX_LENGTH: usize = 0x7F4;
+const CONTROL: usize = 0x7FC;
+
+const MDIO_ADDR: usize = 0x7E4;
+const MDIO_WR: usize = 0x7E8;
+const MDIO_RD: usize = 0x7EC;
+const MDIO_CTRL: usize = 0x7F0;
+
+/// TX: transmit in progress. RX: frame available.
+const CONTROL_STATUS: u32 = 0x1;
+const CONTROL_PROGRAM: u32 = 0x2;
+
+const MDIO_ADDR_READ: u32 = 0x400;
+const MDIO_CTRL_STATUS: u32 = 0x1;
+const MDIO_CTRL_ENABLE: u32 = 0x8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct EmacLite {
+    base: IoMem<0x2000>,
+    address: [u8; 6],
+    phy: u8,
+    tx_ping_pong: bool,
+    rx_ping_pong: bool,
+    /// Buffer to use for the next transmission.
+    tx_pong: bool,
+    /// Buffer expected to receive the next frame.
+    rx_pong: bool,
+}
+
+impl EmacLite {
+    /// Program the MAC address and negotiate the link.
+    ///
+    /// # Safety
+    /// `base` must be the register block of an EthernetLite configured with the given ping-pong
+    /// settings, not used by anything else while this driver exists.
+    pub unsafe fn new(
+        base: usize,
+        address: [u8; 6],
+        tx_ping_pong: bool,
+        rx_ping_pong: bool,
+    ) -> Result<Self> {
+        let mut eth = EmacLite {
+            base: unsafe { IoMem::new(base) },
+            address,
+            phy: 0,
+            tx_ping_pong,
+            rx_ping_pong,
+            tx_pong: false,
+            rx_pong: false,
+        };
+
+        eth.set_mac_address(address)?;
+
+        // Discard anything received before we are ready.
+        eth.base.write_u32(RX_PING + CONTROL, 0);
+        if rx_ping_pong {
+            eth.base.write_u32(RX_PONG + CONTROL, 0);
+        }
+
+        eth.base.write_u32(MDIO_CTRL, MDIO_CTRL_ENABLE);
+        eth.phy = phy::probe(&eth)?;
+        // The MAC only supports 10 and 100Mbps.
+        let link = phy::negotiate(&eth, eth.phy, false)?;
+        println!(
+            "Ethernet link up at {}Mbps {} duplex",
+            link.speed,
+            if link.full_duplex { "full" } else { "half" }
+        );
+        Ok(eth)
+    }
+
+    fn tx_wait(&self, buffer: usize) -> Result<()> {
+        let timer = Timer::new(TX_TIMEOUT);
+        while self.base.read_u32(buffer + CONTROL) & CONTROL_STATUS != 0 {
+            if timer.fired() {
+                return Err(Error::Textual("Ethernet transmit timeout"));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_buffer(&self, buffer: usize, data: &[u8]) {
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.base
+                .write_u32(buffer + i * 4, u32::from_le_bytes(word));
+        }
+    }
+
+    fn read_buffer(&self, buffer: usize, offset: usize, data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(4).enumerate() {
+            let word = self.base.read_u32(buffer + offset + i * 4).to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+    }
+
+    fn set_mac_address(&mut self, address: [u8; 6]) -> Result<()> {
+        self.tx_wait(TX_PING)?;
+        self.write_buffer(TX_PING, &address);
+        self.base
+            .write_u32(TX_PING + CONTROL, CONTROL_STATUS | CONTROL_PROGRAM);
+        self.tx_wait(TX_PING)?;
+        self.address = address;
+        Ok(())
+    }
+
+    fn mdio_wait(&self) -> Result<()> {
+        let timer = Timer::new(Duration::from_millis(10));
+        while self.base.read_u32(MDIO_CTRL) & MDIO_CTRL_STATUS != 0 {
+            if timer.fired() {
+                return Err(Error::Textual("MDIO timeout"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a frame from an RX buffer whose status indicates a frame is available.
+    ///
+    /// The controller does not report the frame length, so it is derived from the header.
+    fn read_frame(&self, buffer: usize, buf: &mut [u8]) -> usize {
+        let mut header = [0; 20];
+        self.read_buffer(buffer, 0, &mut header);
+        let len = match u16::from_be_bytes([header[12], header[13]]) {
+            ETHERTYPE_IPV4 => 14 + u16::from_be_bytes([header[16], header[17]]) as usize,
+            ETHERTYPE_ARP => 14 + 28,
+            // 802.3 length field
+            len if len <= 1500 => 14 + len as usize,
+            _ => MAX_FRAME_SIZE,
+        };
+        let len = core::cmp::min(core::cmp::min(len, MAX_FRAME_SIZE), buf.len());
+
+        let copied = core::cmp::min(len, header.len());
+        buf[..copied].copy_from_slice(&header[..copied]);
+        if len > copied {
+            self.read_buffer(buffer, copied, &mut buf[copied..len]);
+        }
+        len
+    }
+}
+
+impl Mdio for EmacLite {
+    fn mdio_read(&self, phy: u8, reg: u8) -> Result<u16> {
+        self.mdio_wait()?;
+        self.base
+            .write_u32(MDIO_ADDR, MDIO_ADDR_READ | (phy as u32) << 5 | reg as u32);
+        self.base
+            .write_u32(MDIO_CTRL, MDIO_CTRL_ENABLE | MDIO_CTRL_STATUS);
+        self.mdio_wait()?;
+        Ok(self.base.read_u32(MDIO_RD) as u16)
+    }
+
+    fn mdio_write(&self, phy: u8, reg: u8, value: u16) -> Result<()> {
+        self.mdio_wait()?;
+        self.base
+            .write_u32(MDIO_ADDR, (phy as u32) << 5 | reg as u32);
+        self.base.write_u32(MDIO_WR, value as u32);
+        self.base
+            .write_u32(MDIO_CTRL, MDIO_CTRL_ENABLE | MDIO_CTRL_STATUS);
+        self.mdio_wait()
+    }
+}
+
+impl NetDevice for EmacLite {
+    fn mac_address(&self) -> [u8; 6] {
+        self.address
+    }
+
+    fn link_up(&self) -> bool {
+        phy::link_up(self, self.phy).unwrap_or(false)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::Textual("frame too large"));
+        }
+        let buffer = if self.tx_pong { TX_PONG } else { TX_PING };
+        // With ping-pong buffers, the other buffer may still be transmitting while this one is
+        // filled.
+        self.tx_wait(buffer)?;
+        self.write_buffer(buffer, frame);
+        self.base.write_u32(buffer + TX_LENGTH, frame.len() as u32);
+        self.base.write_u32(buffer + CONTROL, CONTROL_STATUS);
+        if self.tx_ping_pong {
+            self.tx_pong = !self.tx_pong;
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let expected = if self.rx_pong { RX_PONG } else { RX_PING };
+        let other = if self.rx_pong { RX_PING } else { RX_PONG };
+        // The controller fills the buffers alternately, but resynchronise if we get out of step.
+        let buffer = if self.base.read_u32(expected + CONTROL) & CONTROL_STATUS != 0 {
+            expected
+        } else if self.rx_ping_pong && self.base.read_u32(other + CONTROL) & CONTROL_STATUS != 0 {
+            other
+        } else {
+            return None;
+        };
+
+        let len = self.read_frame(buffer, buf);
+        self.base.write_u32(buffer + CONTROL, 0);
+        if self.rx_ping_pong {
+            self.rx_pong = buffer == RX_PING;
+        }
+        Some(len)
+    }
+}
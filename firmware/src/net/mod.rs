@@ -0,0 +1,376 @@
+//! Minimal polling network stack: Ethernet, ARP, IPv4 and UDP.
+//!
+//! Only what is needed for network boot is implemented. There is no fragmentation support, and
+//! incoming packets are only processed while waiting for a UDP datagram.
+
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+use crate::io::{Error, Result};
+use crate::timer::Timer;
+
+#[cfg(has_ethernet)]
+pub mod axienet;
+pub mod dhcp;
+#[cfg(has_emaclite)]
+pub mod emaclite;
+mod phy;
+pub mod tftp;
+
+/// Largest Ethernet frame handled, without FCS.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const IP_PROTO_UDP: u8 = 17;
+
+const ETH_HEADER_SIZE: usize = 14;
+const IP_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+
+/// Largest UDP payload that fits in a single frame.
+pub const MAX_UDP_PAYLOAD: usize =
+    MAX_FRAME_SIZE - ETH_HEADER_SIZE - IP_HEADER_SIZE - UDP_HEADER_SIZE;
+
+const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+const ARP_TIMEOUT: Duration = Duration::from_millis(500);
+const ARP_RETRIES: u32 = 4;
+const ARP_CACHE_SIZE: usize = 4;
+
+/// An Ethernet device.
+pub trait NetDevice {
+    fn mac_address(&self) -> [u8; 6];
+
+    /// Transmit a single Ethernet frame, excluding FCS.
+    fn send(&mut self, frame: &[u8]) -> Result<()>;
+
+    /// Receive a single Ethernet frame if one is available, returning its length.
+    fn recv(&mut self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Whether the link is up. Devices that cannot tell always report it as up.
+    fn link_up(&self) -> bool {
+        true
+    }
+}
+
+/// Initialise the network controller described by the device tree.
+#[cfg(has_ethernet)]
+pub fn open() -> Result<axienet::AxiEthernet> {
+    use crate::address::{ETH_DMA_BASE, ETH_MAC_BASE, MAC_ADDRESS};
+    unsafe { axienet::AxiEthernet::new(ETH_MAC_BASE, ETH_DMA_BASE, MAC_ADDRESS) }
+}
+
+/// Initialise the network controller described by the device tree.
+#[cfg(all(has_emaclite, not(has_ethernet)))]
+pub fn open() -> Result<emaclite::EmacLite> {
+    use crate::address::{
+        EMACLITE_BASE, EMACLITE_RX_PING_PONG, EMACLITE_TX_PING_PONG, MAC_ADDRESS,
+    };
+    unsafe {
+        emaclite::EmacLite::new(
+            EMACLITE_BASE,
+            MAC_ADDRESS,
+            EMACLITE_TX_PING_PONG,
+            EMACLITE_RX_PING_PONG,
+        )
+    }
+}
+
+/// Internet checksum of `data`, continuing from `sum`.
+fn checksum(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, udp: &[u8]) -> u16 {
+    let mut sum = checksum(0, &src.octets());
+    sum = checksum(sum, &dst.octets());
+    sum += IP_PROTO_UDP as u32 + udp.len() as u32;
+    match checksum_finish(checksum(sum, udp)) {
+        // Zero means no checksum, so it is transmitted as all ones instead.
+        0 => 0xFFFF,
+        v => v,
+    }
+}
+
+/// Source and destination of a received UDP datagram.
+#[derive(Clone, Copy, Debug)]
+pub struct UdpMeta {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+pub struct Interface<D: NetDevice> {
+    dev: D,
+    mac: [u8; 6],
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    arp_cache: [(Ipv4Addr, [u8; 6]); ARP_CACHE_SIZE],
+    arp_next: usize,
+    rx: [u8; MAX_FRAME_SIZE],
+    tx: [u8; MAX_FRAME_SIZE],
+}
+
+impl<D: NetDevice> Interface<D> {
+    /// Create an interface without an IP address, which can only send and receive broadcasts.
+    pub fn new(dev: D) -> Self {
+        let mac = dev.mac_address();
+        Interface {
+            dev,
+            mac,
+            ip: Ipv4Addr::UNSPECIFIED,
+            netmask: Ipv4Addr::UNSPECIFIED,
+            gateway: None,
+            arp_cache: [(Ipv4Addr::UNSPECIFIED, [0; 6]); ARP_CACHE_SIZE],
+            arp_next: 0,
+            rx: [0; MAX_FRAME_SIZE],
+            tx: [0; MAX_FRAME_SIZE],
+        }
+    }
+
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.dev.link_up()
+    }
+
+    /// Send an Ethernet frame whose payload of size `len` is already in `self.tx`.
+    fn send_frame(&mut self, dst: [u8; 6], ethertype: u16, len: usize) -> Result<()> {
+        self.tx[0..6].copy_from_slice(&dst);
+        self.tx[6..12].copy_from_slice(&self.mac);
+        self.tx[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        // Pad to the minimum frame size.
+        let end = ETH_HEADER_SIZE + len;
+        let padded = core::cmp::max(end, 60);
+        self.tx[end..padded].fill(0);
+        self.dev.send(&self.tx[..padded])
+    }
+
+    fn send_arp(&mut self, op: u16, dst_mac: [u8; 6], target: ([u8; 6], Ipv4Addr)) -> Result<()> {
+        let arp = &mut self.tx[ETH_HEADER_SIZE..ETH_HEADER_SIZE + 28];
+        // Ethernet, IPv4, address sizes
+        arp[0..6].copy_from_slice(&[0, 1, 8, 0, 6, 4]);
+        arp[6..8].copy_from_slice(&op.to_be_bytes());
+        arp[8..14].copy_from_slice(&self.mac);
+        arp[14..18].copy_from_slice(&self.ip.octets());
+        arp[18..24].copy_from_slice(&target.0);
+        arp[24..28].copy_from_slice(&target.1.octets());
+        self.send_frame(dst_mac, ETHERTYPE_ARP, 28)
+    }
+
+    fn arp_lookup(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.arp_cache
+            .iter()
+            .find(|(x, _)| *x == ip && !ip.is_unspecified())
+            .map(|(_, mac)| *mac)
+    }
+
+    fn arp_insert(&mut self, ip: Ipv4Addr, mac: [u8; 6]) {
+        if let Some(entry) = self.arp_cache.iter_mut().find(|(x, _)| *x == ip) {
+            entry.1 = mac;
+            return;
+        }
+        self.arp_cache[self.arp_next] = (ip, mac);
+        self.arp_next = (self.arp_next + 1) % ARP_CACHE_SIZE;
+    }
+
+    fn handle_arp(&mut self, len: usize) {
+        if len < ETH_HEADER_SIZE + 28 {
+            return;
+        }
+        let arp = &self.rx[ETH_HEADER_SIZE..ETH_HEADER_SIZE + 28];
+        if arp[0..6] != [0, 1, 8, 0, 6, 4] {
+            return;
+        }
+        let op = u16::from_be_bytes([arp[6], arp[7]]);
+        let sender_mac: [u8; 6] = arp[8..14].try_into().unwrap();
+        let sender_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&arp[14..18]).unwrap());
+        let target_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&arp[24..28]).unwrap());
+
+        if self.ip.is_unspecified() || target_ip != self.ip {
+            return;
+        }
+        self.arp_insert(sender_ip, sender_mac);
+        if op == 1 {
+            let _ = self.send_arp(2, sender_mac, (sender_mac, sender_ip));
+        }
+    }
+
+    /// Process a received IPv4 frame, returning the UDP payload range if it is for us.
+    fn handle_ipv4(&mut self, len: usize) -> Option<(UdpMeta, core::ops::Range<usize>)> {
+        let ip = &self.rx[ETH_HEADER_SIZE..len];
+        if ip.len() < IP_HEADER_SIZE || ip[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (ip[0] & 0xF) as usize * 4;
+        let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        // Fragments are not supported.
+        let fragment = u16::from_be_bytes([ip[6], ip[7]]) & 0x3FFF;
+        if header_len < IP_HEADER_SIZE
+            || total_len < header_len + UDP_HEADER_SIZE
+            || total_len > ip.len()
+            || fragment != 0
+            || ip[9] != IP_PROTO_UDP
+            || checksum_finish(checksum(0, &ip[..header_len])) != 0
+        {
+            return None;
+        }
+        let src = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[12..16]).unwrap());
+        let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[16..20]).unwrap());
+        if dst != self.ip && dst != Ipv4Addr::BROADCAST && !self.ip.is_unspecified() {
+            return None;
+        }
+
+        let udp = &ip[header_len..total_len];
+        let udp_len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+        if udp_len < UDP_HEADER_SIZE || udp_len > udp.len() {
+            return None;
+        }
+        let udp = &udp[..udp_len];
+        if u16::from_be_bytes([udp[6], udp[7]]) != 0 {
+            let mut sum = checksum(0, &src.octets());
+            sum = checksum(sum, &dst.octets());
+            sum += IP_PROTO_UDP as u32 + udp.len() as u32;
+            if checksum_finish(checksum(sum, udp)) != 0 {
+                return None;
+            }
+        }
+
+        let meta = UdpMeta {
+            src,
+            src_port: u16::from_be_bytes([udp[0], udp[1]]),
+            dst_port: u16::from_be_bytes([udp[2], udp[3]]),
+        };
+        let start = ETH_HEADER_SIZE + header_len + UDP_HEADER_SIZE;
+        Some((meta, start..start + udp_len - UDP_HEADER_SIZE))
+    }
+
+    /// Receive and process one frame, returning the UDP payload range if it is a datagram for us.
+    fn poll(&mut self) -> Option<(UdpMeta, core::ops::Range<usize>)> {
+        let len = self.dev.recv(&mut self.rx)?;
+        if len < ETH_HEADER_SIZE {
+            return None;
+        }
+        match u16::from_be_bytes([self.rx[12], self.rx[13]]) {
+            ETHERTYPE_ARP => {
+                self.handle_arp(len);
+                None
+            }
+            ETHERTYPE_IPV4 => self.handle_ipv4(len),
+            _ => None,
+        }
+    }
+
+    fn is_local(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(ip) & mask == u32::from(self.ip) & mask
+    }
+
+    /// Find the MAC address of the next hop towards `ip`.
+    fn resolve(&mut self, ip: Ipv4Addr) -> Result<[u8; 6]> {
+        if ip == Ipv4Addr::BROADCAST {
+            return Ok(BROADCAST_MAC);
+        }
+        let next_hop = if self.is_local(ip) {
+            ip
+        } else {
+            self.gateway
+                .ok_or(Error::Textual("destination unreachable"))?
+        };
+        if let Some(mac) = self.arp_lookup(next_hop) {
+            return Ok(mac);
+        }
+
+        for _ in 0..ARP_RETRIES {
+            self.send_arp(1, BROADCAST_MAC, ([0; 6], next_hop))?;
+            let timer = Timer::new(ARP_TIMEOUT);
+            while !timer.fired() {
+                self.poll();
+                if let Some(mac) = self.arp_lookup(next_hop) {
+                    return Ok(mac);
+                }
+            }
+        }
+        Err(Error::Textual("ARP timeout"))
+    }
+
+    /// Send a UDP datagram.
+    pub fn send_udp(
+        &mut self,
+        src_port: u16,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        data: &[u8],
+    ) -> Result<()> {
+        if data.len() > MAX_UDP_PAYLOAD {
+            return Err(Error::Textual("UDP payload too large"));
+        }
+        let dst_mac = self.resolve(dst)?;
+
+        let udp_len = UDP_HEADER_SIZE + data.len();
+        let total_len = IP_HEADER_SIZE + udp_len;
+        let src = self.ip;
+
+        let ip = &mut self.tx[ETH_HEADER_SIZE..ETH_HEADER_SIZE + total_len];
+        // Version 4, no options, DF set, TTL 64
+        ip[0..10].copy_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, IP_PROTO_UDP]);
+        ip[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        ip[10..12].fill(0);
+        ip[12..16].copy_from_slice(&src.octets());
+        ip[16..20].copy_from_slice(&dst.octets());
+        let sum = checksum_finish(checksum(0, &ip[..IP_HEADER_SIZE]));
+        ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+        let udp = &mut ip[IP_HEADER_SIZE..];
+        udp[0..2].copy_from_slice(&src_port.to_be_bytes());
+        udp[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        udp[6..8].fill(0);
+        udp[UDP_HEADER_SIZE..].copy_from_slice(data);
+        let sum = udp_checksum(src, dst, udp);
+        udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+        self.send_frame(dst_mac, ETHERTYPE_IPV4, total_len)
+    }
+
+    /// Wait for a UDP datagram to `port`, copying its payload into `buf`.
+    ///
+    /// Returns `None` on timeout.
+    pub fn recv_udp(
+        &mut self,
+        port: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Option<(UdpMeta, usize)> {
+        let timer = Timer::new(timeout);
+        while !timer.fired() {
+            if let Some((meta, range)) = self.poll() {
+                if meta.dst_port != port {
+                    continue;
+                }
+                let len = core::cmp::min(range.len(), buf.len());
+                buf[..len].copy_from_slice(&self.rx[range.start..range.start + len]);
+                return Some((meta, len));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,106 @@
+//! Generic IEEE 802.3 clause 22 PHY management.
+
+use core::time::Duration;
+
+use crate::io::{Error, Result};
+use crate::timer::Timer;
+
+const BMCR: u8 = 0;
+const BMSR: u8 = 1;
+const PHYID1: u8 = 2;
+const ANAR: u8 = 4;
+const ANLPAR: u8 = 5;
+const GBCR: u8 = 9;
+const GBSR: u8 = 10;
+
+const BMCR_AUTONEG: u16 = 0x1000;
+const BMCR_RESTART_AUTONEG: u16 = 0x0200;
+const BMSR_EXTENDED_STATUS: u16 = 0x0100;
+const BMSR_AUTONEG_COMPLETE: u16 = 0x0020;
+const BMSR_LINK: u16 = 0x0004;
+/// 10/100 half/full duplex, IEEE 802.3 selector.
+const ANAR_ALL: u16 = 0x01E1;
+const ANAR_100FULL: u16 = 0x0100;
+const ANAR_100HALF: u16 = 0x0080;
+const ANAR_10FULL: u16 = 0x0040;
+const GBCR_1000FULL: u16 = 0x0200;
+const GBCR_1000HALF: u16 = 0x0100;
+
+const AUTONEG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Access to the MDIO bus of a MAC.
+pub trait Mdio {
+    fn mdio_read(&self, phy: u8, reg: u8) -> Result<u16>;
+    fn mdio_write(&self, phy: u8, reg: u8, value: u16) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Link {
+    /// Speed in Mbps.
+    pub speed: u32,
+    pub full_duplex: bool,
+}
+
+/// Find the address of the first PHY on the bus.
+pub fn probe(mdio: &impl Mdio) -> Result<u8> {
+    for phy in 0..32 {
+        match mdio.mdio_read(phy, PHYID1)? {
+            0 | 0xFFFF => (),
+            _ => return Ok(phy),
+        }
+    }
+    Err(Error::Textual("no PHY found"))
+}
+
+/// Whether the PHY currently reports a link.
+pub fn link_up(mdio: &impl Mdio, phy: u8) -> Result<bool> {
+    // Link status is latched low, so read twice to get the current state.
+    mdio.mdio_read(phy, BMSR)?;
+    Ok(mdio.mdio_read(phy, BMSR)? & BMSR_LINK != 0)
+}
+
+/// Run auto-negotiation, advertising 1000Mbps only if `gigabit` is set.
+pub fn negotiate(mdio: &impl Mdio, phy: u8, gigabit: bool) -> Result<Link> {
+    let has_gbcr = mdio.mdio_read(phy, BMSR)? & BMSR_EXTENDED_STATUS != 0;
+    mdio.mdio_write(phy, ANAR, ANAR_ALL)?;
+    if has_gbcr {
+        let gbcr = if gigabit {
+            GBCR_1000FULL | GBCR_1000HALF
+        } else {
+            0
+        };
+        mdio.mdio_write(phy, GBCR, gbcr)?;
+    }
+    mdio.mdio_write(phy, BMCR, BMCR_AUTONEG | BMCR_RESTART_AUTONEG)?;
+
+    let timer = Timer::new(AUTONEG_TIMEOUT);
+    loop {
+        let bmsr = mdio.mdio_read(phy, BMSR)?;
+        if bmsr & BMSR_AUTONEG_COMPLETE != 0 && bmsr & BMSR_LINK != 0 {
+            break;
+        }
+        if timer.fired() {
+            return Err(Error::Textual("no Ethernet link"));
+        }
+    }
+
+    if gigabit && has_gbcr {
+        // Link partner abilities in GBSR are 2 bits above our advertisement in GBCR.
+        let common = mdio.mdio_read(phy, GBCR)? & (mdio.mdio_read(phy, GBSR)? >> 2);
+        if common & (GBCR_1000FULL | GBCR_1000HALF) != 0 {
+            return Ok(Link {
+                speed: 1000,
+                full_duplex: common & GBCR_1000FULL != 0,
+            });
+        }
+    }
+    let common = mdio.mdio_read(phy, ANAR)? & mdio.mdio_read(phy, ANLPAR)?;
+    let (speed, full_duplex) = if common & ANAR_100FULL != 0 {
+        (100, true)
+    } else if common & ANAR_100HALF != 0 {
+        (100, false)
+    } else {
+        (10, common & ANAR_10FULL != 0)
+    };
+    Ok(Link { speed, full_duplex })
+}
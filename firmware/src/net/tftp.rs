@@ -0,0 +1,163 @@
+//! TFTP client for reading files (RFC 1350), with block size and transfer size options.
+
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+use super::{Interface, NetDevice, MAX_UDP_PAYLOAD};
+use crate::io::{Error, Result};
+
+const SERVER_PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERROR_NOT_FOUND: u16 = 1;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Largest block size that avoids IP fragmentation on a 1500-byte MTU.
+const BLOCK_SIZE: usize = MAX_UDP_PAYLOAD - 4;
+/// Smallest block size allowed by RFC 2348.
+const MIN_BLOCK_SIZE: usize = 8;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const RETRIES: u32 = 5;
+
+/// Parse the `(name, value)` pairs of an OACK.
+fn parse_oack(data: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    let mut fields = data
+        .split(|&x| x == 0)
+        .map(|x| core::str::from_utf8(x).unwrap_or(""));
+    core::iter::from_fn(move || Some((fields.next()?, fields.next()?)))
+}
+
+fn send_ack<D: NetDevice>(
+    iface: &mut Interface<D>,
+    port: u16,
+    server: (Ipv4Addr, u16),
+    block: u16,
+) -> Result<()> {
+    let mut ack = [0; 4];
+    ack[0..2].copy_from_slice(&OP_ACK.to_be_bytes());
+    ack[2..4].copy_from_slice(&block.to_be_bytes());
+    iface.send_udp(port, server.0, server.1, &ack)
+}
+
+/// Read a file from a TFTP server, failing if it is larger than `max_size`.
+///
+/// Returns `None` if the file does not exist.
+pub fn read<D: NetDevice>(
+    iface: &mut Interface<D>,
+    server: Ipv4Addr,
+    name: &str,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    // Use a different port for each transfer so stray packets of an earlier one are ignored.
+    let port = 49152 + (crate::timer::time_u64() & 0x3FFF) as u16;
+
+    let mut rrq = Vec::new();
+    rrq.extend_from_slice(&OP_RRQ.to_be_bytes());
+    rrq.extend_from_slice(name.as_bytes());
+    rrq.extend_from_slice(b"\0octet\0blksize\0");
+    rrq.extend_from_slice(format!("{}", BLOCK_SIZE).as_bytes());
+    // Ask for the transfer size, which the server fills in.
+    rrq.extend_from_slice(b"\0tsize\0");
+    rrq.extend_from_slice(b"0\0");
+    if rrq.len() > MAX_UDP_PAYLOAD {
+        return Err(Error::Textual("file name too long"));
+    }
+
+    let mut buf = [0; MAX_UDP_PAYLOAD];
+    let mut data = Vec::new();
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    // Server's transfer ID, known after its first reply.
+    let mut peer: Option<(Ipv4Addr, u16)> = None;
+    let mut expected: u16 = 1;
+    let mut retries = 0;
+
+    iface.send_udp(port, server, SERVER_PORT, &rrq)?;
+    loop {
+        let (meta, len) = match iface.recv_udp(port, &mut buf, TIMEOUT) {
+            Some(v) => v,
+            None => {
+                retries += 1;
+                if retries > RETRIES {
+                    return Err(Error::Textual("TFTP timeout"));
+                }
+                // Retransmit the last packet we sent.
+                match peer {
+                    None => iface.send_udp(port, server, SERVER_PORT, &rrq)?,
+                    Some(peer) => send_ack(iface, port, peer, expected.wrapping_sub(1))?,
+                }
+                continue;
+            }
+        };
+        if len < 4 || meta.src != server {
+            continue;
+        }
+        match peer {
+            Some(peer) if peer != (meta.src, meta.src_port) => continue,
+            _ => (),
+        }
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        let arg = u16::from_be_bytes([buf[2], buf[3]]);
+
+        match opcode {
+            OP_ERROR => {
+                if arg == ERROR_NOT_FOUND {
+                    return Ok(None);
+                }
+                let end = buf[4..len].iter().position(|&x| x == 0).unwrap_or(len - 4);
+                println!(
+                    "TFTP error {}: {}",
+                    arg,
+                    core::str::from_utf8(&buf[4..4 + end]).unwrap_or("")
+                );
+                return Err(Error::Textual("TFTP error"));
+            }
+            OP_OACK if peer.is_none() => {
+                peer = Some((meta.src, meta.src_port));
+                for (name, value) in parse_oack(&buf[2..len]) {
+                    if name.eq_ignore_ascii_case("blksize") {
+                        block_size = value
+                            .parse()
+                            .ok()
+                            .filter(|x| (MIN_BLOCK_SIZE..=BLOCK_SIZE).contains(x))
+                            .ok_or(Error::Textual("invalid TFTP block size"))?;
+                    } else if name.eq_ignore_ascii_case("tsize") {
+                        if let Ok(size) = value.parse() {
+                            if size > max_size {
+                                return Err(Error::Textual("file too large"));
+                            }
+                            data.reserve_exact(size);
+                        }
+                    }
+                }
+                send_ack(iface, port, peer.unwrap(), 0)?;
+            }
+            OP_DATA => {
+                let peer = *peer.get_or_insert((meta.src, meta.src_port));
+                if arg != expected {
+                    // Duplicate of an earlier block, whose ACK may have been lost.
+                    send_ack(iface, port, peer, expected.wrapping_sub(1))?;
+                    continue;
+                }
+                let payload = &buf[4..len];
+                if payload.len() > max_size - data.len() {
+                    return Err(Error::Textual("file too large"));
+                }
+                data.extend_from_slice(payload);
+                send_ack(iface, port, peer, arg)?;
+                expected = expected.wrapping_add(1);
+                retries = 0;
+                if payload.len() < block_size {
+                    return Ok(Some(data));
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Minimal driver for the platform-level interrupt controller.
+//!
+//! The firmware installs no external interrupt handler. Instead, devices are waited on with WFI,
+//! which wakes up on locally enabled interrupts even when they are globally disabled. The PLIC
+//! only has S-mode contexts, one per hart, which the firmware borrows until the kernel takes over.
+
+use core::arch::asm;
+
+use crate::address::PLIC_BASE;
+use crate::iomem::IoMem;
+use crate::timer::{self, Timer};
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+const MIE_MTIE: usize = 1 << 7;
+const MIE_SEIE: usize = 1 << 9;
+
+fn plic() -> IoMem<0x40_0000> {
+    unsafe { IoMem::new(PLIC_BASE) }
+}
+
+/// Context of the current hart, as listed in `interrupts-extended` of the PLIC node.
+fn context() -> usize {
+    crate::hartid()
+}
+
+pub fn set_priority(irq: u32, priority: u32) {
+    plic().write_u32(PRIORITY + irq as usize * 4, priority);
+}
+
+pub fn set_threshold(threshold: u32) {
+    plic().write_u32(CONTEXT + context() * CONTEXT_STRIDE + THRESHOLD, threshold);
+}
+
+fn set_enable(irq: u32, enable: bool) {
+    let offset = ENABLE + context() * ENABLE_STRIDE + (irq as usize / 32) * 4;
+    let bit = 1 << (irq % 32);
+    let value = plic().read_u32(offset);
+    plic().write_u32(offset, if enable { value | bit } else { value & !bit });
+}
+
+pub fn enable(irq: u32) {
+    set_enable(irq, true);
+}
+
+pub fn disable(irq: u32) {
+    set_enable(irq, false);
+}
+
+/// Claim the highest priority pending interrupt of the current hart.
+pub fn claim() -> Option<u32> {
+    match plic().read_u32(CONTEXT + context() * CONTEXT_STRIDE + CLAIM) {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+pub fn complete(irq: u32) {
+    plic().write_u32(CONTEXT + context() * CONTEXT_STRIDE + CLAIM, irq);
+}
+
+/// Sleep until `irq` is raised or `timer` fires.
+///
+/// Wake-ups may be spurious, so the caller must re-check the device afterwards.
+pub fn wait(irq: u32, timer: &Timer) {
+    let hart = crate::hartid();
+    set_priority(irq, 1);
+    set_threshold(0);
+    enable(irq);
+    timer::set_timer_u64(hart, timer.0.as_micros() as u64);
+    unsafe {
+        asm!("csrs mie, {}", in(reg) MIE_SEIE | MIE_MTIE, options(nomem, nostack));
+        asm!("wfi", options(nomem, nostack));
+        asm!("csrc mie, {}", in(reg) MIE_SEIE | MIE_MTIE, options(nomem, nostack));
+    }
+    timer::set_timer_u64(hart, u64::MAX);
+
+    // Acknowledge so the interrupt does not stay pending for the kernel. A level-triggered source
+    // that is still asserted becomes pending again.
+    if let Some(claimed) = claim() {
+        complete(claimed);
+    }
+    disable(irq);
+}
@@ -0,0 +1,209 @@
+//! Driver for the Digilent AXI PS/2 controller with a scancode set 2 keyboard decoder.
+
+use crate::iomem::IoMem;
+use spin::Mutex;
+
+const SRST: usize = 0x00;
+const STATUS: usize = 0x04;
+const RX_DATA: usize = 0x08;
+
+const SRST_RESET: u32 = 0xA;
+/// The receive FIFO is empty. Bit 2 (0x04) is set only once it is full.
+const STATUS_RX_EMPTY: u32 = 0x08;
+
+/// Make codes of printable keys, with their unshifted and shifted characters.
+const KEYMAP: &[(u8, u8, u8)] = &[
+    (0x0D, b'\t', b'\t'),
+    (0x0E, b'`', b'~'),
+    (0x15, b'q', b'Q'),
+    (0x16, b'1', b'!'),
+    (0x1A, b'z', b'Z'),
+    (0x1B, b's', b'S'),
+    (0x1C, b'a', b'A'),
+    (0x1D, b'w', b'W'),
+    (0x1E, b'2', b'@'),
+    (0x21, b'c', b'C'),
+    (0x22, b'x', b'X'),
+    (0x23, b'd', b'D'),
+    (0x24, b'e', b'E'),
+    (0x25, b'4', b'$'),
+    (0x26, b'3', b'#'),
+    (0x29, b' ', b' '),
+    (0x2A, b'v', b'V'),
+    (0x2B, b'f', b'F'),
+    (0x2C, b't', b'T'),
+    (0x2D, b'r', b'R'),
+    (0x2E, b'5', b'%'),
+    (0x31, b'n', b'N'),
+    (0x32, b'b', b'B'),
+    (0x33, b'h', b'H'),
+    (0x34, b'g', b'G'),
+    (0x35, b'y', b'Y'),
+    (0x36, b'6', b'^'),
+    (0x3A, b'm', b'M'),
+    (0x3B, b'j', b'J'),
+    (0x3C, b'u', b'U'),
+    (0x3D, b'7', b'&'),
+    (0x3E, b'8', b'*'),
+    (0x41, b',', b'<'),
+    (0x42, b'k', b'K'),
+    (0x43, b'i', b'I'),
+    (0x44, b'o', b'O'),
+    (0x45, b'0', b')'),
+    (0x46, b'9', b'('),
+    (0x49, b'.', b'>'),
+    (0x4A, b'/', b'?'),
+    (0x4B, b'l', b'L'),
+    (0x4C, b';', b':'),
+    (0x4D, b'p', b'P'),
+    (0x4E, b'-', b'_'),
+    (0x52, b'\'', b'"'),
+    (0x54, b'[', b'{'),
+    (0x55, b'=', b'+'),
+    (0x5A, b'\r', b'\r'),
+    (0x5B, b']', b'}'),
+    (0x5D, b'\\', b'|'),
+    (0x66, 0x7F, 0x7F),
+    (0x76, 0x1B, 0x1B),
+    // Keypad
+    (0x69, b'1', b'1'),
+    (0x6B, b'4', b'4'),
+    (0x6C, b'7', b'7'),
+    (0x70, b'0', b'0'),
+    (0x71, b'.', b'.'),
+    (0x72, b'2', b'2'),
+    (0x73, b'5', b'5'),
+    (0x74, b'6', b'6'),
+    (0x75, b'8', b'8'),
+    (0x79, b'+', b'+'),
+    (0x7A, b'3', b'3'),
+    (0x7B, b'-', b'-'),
+    (0x7C, b'*', b'*'),
+    (0x7D, b'9', b'9'),
+];
+
+/// Make codes of keys prefixed by 0xE0, with the escape sequence they produce.
+const EXTENDED_KEYMAP: &[(u8, &[u8])] = &[
+    (0x4A, b"/"),
+    (0x5A, b"\r"),
+    (0x69, b"\x1B[F"),
+    (0x6B, b"\x1B[D"),
+    (0x6C, b"\x1B[H"),
+    (0x71, b"\x1B[3~"),
+    (0x72, b"\x1B[B"),
+    (0x74, b"\x1B[C"),
+    (0x75, b"\x1B[A"),
+];
+
+const LSHIFT: u8 = 0x12;
+const RSHIFT: u8 = 0x59;
+const CTRL: u8 = 0x14;
+const CAPS_LOCK: u8 = 0x58;
+
+/// Translate scancode set 2 into characters.
+pub struct Decoder {
+    extended: bool,
+    release: bool,
+    lshift: bool,
+    rshift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            extended: false,
+            release: false,
+            lshift: false,
+            rshift: false,
+            ctrl: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Feed a byte received from the keyboard, calling `emit` for each resulting character.
+    pub fn feed(&mut self, code: u8, mut emit: impl FnMut(u8)) {
+        match code {
+            0xE0 => {
+                self.extended = true;
+                return;
+            }
+            0xF0 => {
+                self.release = true;
+                return;
+            }
+            // Self-test passed and acknowledgement
+            0xAA | 0xFA => return,
+            _ => (),
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let release = core::mem::replace(&mut self.release, false);
+
+        match (extended, code) {
+            (false, LSHIFT) => self.lshift = !release,
+            (false, RSHIFT) => self.rshift = !release,
+            (_, CTRL) => self.ctrl = !release,
+            (false, CAPS_LOCK) if !release => self.caps_lock = !self.caps_lock,
+            _ if release => (),
+            (true, _) => {
+                if let Some((_, seq)) = EXTENDED_KEYMAP.iter().find(|(x, _)| *x == code) {
+                    seq.iter().copied().for_each(emit);
+                }
+            }
+            (false, _) => {
+                let (_, normal, shifted) = match KEYMAP.iter().find(|(x, ..)| *x == code) {
+                    Some(v) => *v,
+                    None => return,
+                };
+                let mut shift = self.lshift || self.rshift;
+                if normal.is_ascii_lowercase() {
+                    shift ^= self.caps_lock;
+                    if self.ctrl {
+                        emit(normal & 0x1F);
+                        return;
+                    }
+                }
+                emit(if shift { shifted } else { normal });
+            }
+        }
+    }
+}
+
+struct Ps2 {
+    base: IoMem<0x20>,
+    decoder: Decoder,
+}
+
+impl Ps2 {
+    const unsafe fn new(base: usize) -> Self {
+        Ps2 {
+            base: IoMem::new(base),
+            decoder: Decoder::new(),
+        }
+    }
+
+    fn try_recv_byte(&self) -> Option<u8> {
+        if self.base.read_u32(STATUS) & STATUS_RX_EMPTY == 0 {
+            Some(self.base.read_u32(RX_DATA) as u8)
+        } else {
+            None
+        }
+    }
+}
+
+static PS2: Mutex<Ps2> = Mutex::new(unsafe { Ps2::new(crate::address::PS2_BASE) });
+
+pub fn init() {
+    PS2.lock().base.write_u32(SRST, SRST_RESET);
+}
+
+/// Decode all pending scancodes, calling `emit` for each resulting character.
+pub fn poll(mut emit: impl FnMut(u8)) {
+    let mut guard = PS2.lock();
+    let ps2 = &mut *guard;
+    while let Some(code) = ps2.try_recv_byte() {
+        ps2.decoder.feed(code, &mut emit);
+    }
+}
@@ -186,7 +186,7 @@ fn handle_sbi_legacy(ctx: &mut Context) -> SbiResult {
         }
         2 => {
             // getchar
-            match super::uart::uart_try_recv_byte() {
+            match super::input::try_getchar() {
                 None => Err(SbiError::Failed),
                 Some(v) => Ok(v as isize),
             }
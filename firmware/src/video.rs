@@ -144,17 +144,40 @@ pub unsafe fn get_fbcon() -> Option<&'static mut fbcon::Fbcon<'static>> {
     unsafe { FBCON.as_mut() }
 }
 
+/// Framebuffer configuration chosen by the firmware.
+pub struct FramebufferInfo {
+    pub base: usize,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: &'static str,
+}
+
+const FB_WIDTH: u32 = 1280;
+const FB_HEIGHT: u32 = 720;
+const FB_BPP: u32 = 2;
+
+pub fn framebuffer_info() -> FramebufferInfo {
+    FramebufferInfo {
+        base: crate::address::FRAMEBUFFER_BASE,
+        width: FB_WIDTH,
+        height: FB_HEIGHT,
+        stride: FB_WIDTH * FB_BPP,
+        format: "r5g6b5",
+    }
+}
+
 pub fn init() {
     unsafe {
         core::ptr::write_volatile(
             reg(CR_FB_BASE) as *mut usize,
             crate::address::FRAMEBUFFER_BASE,
         );
-        core::ptr::write_volatile(reg(CR_FB_WIDTH), 1280);
-        core::ptr::write_volatile(reg(CR_FB_HEIGHT), 720);
+        core::ptr::write_volatile(reg(CR_FB_WIDTH), FB_WIDTH);
+        core::ptr::write_volatile(reg(CR_FB_HEIGHT), FB_HEIGHT);
         // r5g6b5
         core::ptr::write_volatile(reg(CR_FB_DEPTH), 1);
-        core::ptr::write_volatile(reg(CR_FB_BPL), 1280 * 2);
+        core::ptr::write_volatile(reg(CR_FB_BPL), FB_WIDTH * FB_BPP);
         core::ptr::write_volatile(reg(CR_FB_COMMIT), 1);
     }
 
@@ -166,15 +189,15 @@ pub fn init() {
         let framebuffer = unsafe {
             core::slice::from_raw_parts_mut(
                 crate::address::FRAMEBUFFER_BASE as *mut u8,
-                1280 * 720 * 2,
+                (FB_WIDTH * FB_HEIGHT * FB_BPP) as usize,
             )
         };
         let fb = fbcon::Framebuffer {
             framebuffer,
-            width: 1280,
-            height: 720,
-            bpl: 1280 * 2,
-            bpp: 2,
+            width: FB_WIDTH,
+            height: FB_HEIGHT,
+            bpl: FB_WIDTH * FB_BPP,
+            bpp: FB_BPP,
         };
         unsafe {
             FBCON = Some(fbcon::Fbcon::new(fb));
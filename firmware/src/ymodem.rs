@@ -0,0 +1,241 @@
+//! YMODEM receiver for uploading files over the UART.
+//!
+//! Only CRC-16 mode is supported, with both 128-byte and 1K blocks accepted. The UART is used
+//! directly rather than through the console, so nothing must be printed during a transfer.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::crc::crc16;
+use crate::timer::Timer;
+use crate::uart::{uart_send_byte, uart_try_recv_byte};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of consecutive errors tolerated before giving up.
+const MAX_ERRORS: u32 = 10;
+/// Number of seconds to wait for the sender to start.
+const START_TIMEOUT: u32 = 60;
+
+#[derive(Debug)]
+pub enum Error {
+    Timeout,
+    Cancelled,
+    InvalidHeader,
+    OutOfSequence,
+    TooManyErrors,
+    /// Fewer bytes are received than the size announced in the header.
+    Truncated,
+    /// The files do not fit in the memory they are to be loaded into.
+    TooLarge,
+}
+
+pub struct File {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+enum Packet {
+    Data { block: u8, len: usize },
+    Eot,
+}
+
+enum PacketError {
+    Timeout,
+    Corrupt,
+    Cancelled,
+}
+
+fn recv_byte(timeout: Duration) -> Option<u8> {
+    let timer = Timer::new(timeout);
+    loop {
+        if let Some(byte) = uart_try_recv_byte() {
+            return Some(byte);
+        }
+        if timer.fired() {
+            return None;
+        }
+    }
+}
+
+/// Discard input until the line goes quiet, so the sender can resynchronise.
+fn purge() {
+    while recv_byte(BYTE_TIMEOUT).is_some() {}
+}
+
+fn cancel() {
+    for _ in 0..5 {
+        uart_send_byte(CAN);
+    }
+}
+
+fn recv_packet(buf: &mut [u8; 1024]) -> Result<Packet, PacketError> {
+    let len = match recv_byte(BYTE_TIMEOUT).ok_or(PacketError::Timeout)? {
+        SOH => 128,
+        STX => 1024,
+        EOT => return Ok(Packet::Eot),
+        CAN if recv_byte(BYTE_TIMEOUT) == Some(CAN) => return Err(PacketError::Cancelled),
+        _ => return Err(PacketError::Corrupt),
+    };
+
+    let recv = || recv_byte(BYTE_TIMEOUT).ok_or(PacketError::Corrupt);
+    let block = recv()?;
+    let block_inv = recv()?;
+    for byte in buf[..len].iter_mut() {
+        *byte = recv()?;
+    }
+    let crc = (recv()? as u16) << 8 | recv()? as u16;
+
+    if block != !block_inv || crc16(0, &buf[..len]) != crc {
+        return Err(PacketError::Corrupt);
+    }
+    Ok(Packet::Data { block, len })
+}
+
+/// Receive the header block of the next file.
+///
+/// Returns the length of the header in `buf`.
+fn recv_header(buf: &mut [u8; 1024], first: bool) -> Result<usize, Error> {
+    let max_errors = if first { START_TIMEOUT } else { MAX_ERRORS };
+    let mut errors = 0;
+    loop {
+        uart_send_byte(CRC);
+        match recv_packet(buf) {
+            Ok(Packet::Data { block: 0, len }) => return Ok(len),
+            // Retransmitted EOT of the previous file, whose ACK was lost.
+            Ok(Packet::Eot) => uart_send_byte(ACK),
+            Ok(Packet::Data { .. }) | Err(PacketError::Corrupt) => purge(),
+            Err(PacketError::Timeout) => (),
+            Err(PacketError::Cancelled) => return Err(Error::Cancelled),
+        }
+        errors += 1;
+        if errors >= max_errors {
+            cancel();
+            return Err(if first {
+                Error::Timeout
+            } else {
+                Error::TooManyErrors
+            });
+        }
+    }
+}
+
+/// Parse the header block, consisting of a file name and optionally the decimal file size.
+fn parse_header(header: &[u8]) -> Result<(String, Option<usize>), Error> {
+    let name_end = header
+        .iter()
+        .position(|&x| x == 0)
+        .ok_or(Error::InvalidHeader)?;
+    let name = core::str::from_utf8(&header[..name_end]).map_err(|_| Error::InvalidHeader)?;
+    // Only keep the base name.
+    let name = name.rsplit('/').next().unwrap();
+
+    let size = header[name_end + 1..]
+        .split(|&x| x == b' ' || x == 0)
+        .next()
+        .and_then(|x| core::str::from_utf8(x).ok())
+        .and_then(|x| x.parse().ok());
+    Ok((name.into(), size))
+}
+
+/// Receive the next file, which must not be larger than `max_size`.
+fn recv_file(first: bool, max_size: usize) -> Result<Option<File>, Error> {
+    let mut buf = [0; 1024];
+
+    let len = recv_header(&mut buf, first)?;
+    // An empty file name terminates the batch.
+    if buf[0] == 0 {
+        uart_send_byte(ACK);
+        return Ok(None);
+    }
+    let (name, size) = match parse_header(&buf[..len]) {
+        Ok(v) => v,
+        Err(err) => {
+            cancel();
+            return Err(err);
+        }
+    };
+    if size.is_some_and(|size| size > max_size) {
+        cancel();
+        return Err(Error::TooLarge);
+    }
+    uart_send_byte(ACK);
+
+    let mut data = Vec::with_capacity(size.unwrap_or(0));
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+    uart_send_byte(CRC);
+    loop {
+        match recv_packet(&mut buf) {
+            Ok(Packet::Data { block, len }) if block == expected => {
+                // Only the last block may go beyond `max_size`, with its padding.
+                if data.len() >= max_size {
+                    cancel();
+                    return Err(Error::TooLarge);
+                }
+                data.extend_from_slice(&buf[..len]);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                uart_send_byte(ACK);
+                continue;
+            }
+            // Retransmission of a block whose ACK was lost.
+            Ok(Packet::Data { block, .. }) if block == expected.wrapping_sub(1) => {
+                uart_send_byte(ACK);
+                continue;
+            }
+            Ok(Packet::Data { .. }) => {
+                cancel();
+                return Err(Error::OutOfSequence);
+            }
+            Ok(Packet::Eot) => {
+                // NAK the first EOT to guard against line noise being mistaken for EOT.
+                uart_send_byte(NAK);
+                let _ = recv_byte(BYTE_TIMEOUT);
+                uart_send_byte(ACK);
+                break;
+            }
+            Err(PacketError::Corrupt) => {
+                purge();
+                uart_send_byte(NAK);
+            }
+            Err(PacketError::Timeout) => uart_send_byte(NAK),
+            Err(PacketError::Cancelled) => return Err(Error::Cancelled),
+        }
+        errors += 1;
+        if errors >= MAX_ERRORS {
+            cancel();
+            return Err(Error::TooManyErrors);
+        }
+    }
+
+    if let Some(size) = size {
+        if data.len() < size {
+            return Err(Error::Truncated);
+        }
+        // The last block is padded.
+        data.truncate(size);
+    } else if data.len() > max_size {
+        return Err(Error::TooLarge);
+    }
+    Ok(Some(File { name, data }))
+}
+
+/// Receive a batch of files, of at most `max_size` bytes in total.
+pub fn receive(max_size: usize) -> Result<Vec<File>, Error> {
+    let mut files = Vec::new();
+    let mut remaining = max_size;
+    while let Some(file) = recv_file(files.is_empty(), remaining)? {
+        remaining -= file.data.len();
+        files.push(file);
+    }
+    Ok(files)
+}
@@ -0,0 +1,91 @@
+//! Memory-mapped I/O routed to simulated devices.
+//!
+//! Devices are registered per thread with [`map`], and accesses through an [`IoMem`] whose base
+//! address matches are forwarded to them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A simulated device, accessed with its register offset and access size in bytes.
+pub trait Mmio {
+    fn read(&mut self, offset: usize, size: usize) -> u64;
+    fn write(&mut self, offset: usize, size: usize, value: u64);
+}
+
+thread_local! {
+    static DEVICES: RefCell<HashMap<usize, Rc<RefCell<dyn Mmio>>>> = RefCell::default();
+}
+
+/// Make `device` accessible at `base` on the current thread.
+pub fn map(base: usize, device: Rc<RefCell<dyn Mmio>>) {
+    DEVICES.with(|devices| devices.borrow_mut().insert(base, device));
+}
+
+fn device(base: usize) -> Rc<RefCell<dyn Mmio>> {
+    DEVICES.with(|devices| {
+        devices
+            .borrow()
+            .get(&base)
+            .unwrap_or_else(|| panic!("no device mapped at {:#x}", base))
+            .clone()
+    })
+}
+
+#[derive(Clone, Copy)]
+pub struct IoMem<const SIZE: usize> {
+    ptr: usize,
+}
+
+impl<const SIZE: usize> IoMem<SIZE> {
+    /// # Safety
+    ///
+    /// Unsafe only to match the firmware signature, as accesses go to a simulated device.
+    pub const unsafe fn new(ptr: usize) -> Self {
+        Self { ptr }
+    }
+
+    fn read(&self, offset: usize, size: usize) -> u64 {
+        assert!(offset + size <= SIZE);
+        assert!(offset.is_multiple_of(size));
+        device(self.ptr).borrow_mut().read(offset, size)
+    }
+
+    fn write(&self, offset: usize, size: usize, value: u64) {
+        assert!(offset + size <= SIZE);
+        assert!(offset.is_multiple_of(size));
+        device(self.ptr).borrow_mut().write(offset, size, value)
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.read(offset, 1) as u8
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        self.read(offset, 2) as u16
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        self.read(offset, 4) as u32
+    }
+
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        self.read(offset, 8)
+    }
+
+    pub fn write_u8(&self, offset: usize, value: u8) {
+        self.write(offset, 1, value as u64)
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        self.write(offset, 2, value as u64)
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        self.write(offset, 4, value as u64)
+    }
+
+    pub fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, 8, value)
+    }
+}
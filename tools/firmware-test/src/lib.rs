@@ -0,0 +1,38 @@
+//! Host tests for the hardware-independent parts of the firmware.
+//!
+//! Firmware modules are compiled for the host through `#[path]`, with the modules that access
+//! hardware replaced by the simulated versions in this crate. Lints on firmware modules are left
+//! to the firmware build.
+
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/block/mod.rs"]
+pub mod block;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/crc.rs"]
+pub mod crc;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/fs/mod.rs"]
+pub mod fs;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/inflate.rs"]
+pub mod inflate;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/io.rs"]
+pub mod io;
+pub mod iomem;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/net/mod.rs"]
+pub mod net;
+pub mod plic;
+pub mod timer;
+pub mod uart;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/util.rs"]
+pub mod util;
+#[allow(clippy::all)]
+#[path = "../../../firmware/src/ymodem.rs"]
+pub mod ymodem;
@@ -0,0 +1,33 @@
+//! Timer backed by the host clock.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+pub fn time_u64() -> u64 {
+    epoch().elapsed().as_micros() as u64
+}
+
+pub fn time() -> Duration {
+    Duration::from_micros(time_u64())
+}
+
+pub fn sleep(duration: Duration) {
+    std::thread::sleep(duration)
+}
+
+pub struct Timer(pub Duration);
+
+impl Timer {
+    pub fn new(duration: Duration) -> Self {
+        Timer(time() + duration)
+    }
+
+    pub fn fired(&self) -> bool {
+        self.0 < time()
+    }
+}
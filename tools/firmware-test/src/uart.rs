@@ -0,0 +1,32 @@
+//! UART connected to a port provided by the test.
+//!
+//! A port is installed per thread with [`attach`], as the receiving side runs on the test thread.
+
+use std::cell::RefCell;
+
+/// The other end of the serial line.
+pub trait Port {
+    fn send(&mut self, byte: u8);
+    fn try_recv(&mut self) -> Option<u8>;
+}
+
+thread_local! {
+    static PORT: RefCell<Option<Box<dyn Port>>> = RefCell::new(None);
+}
+
+/// Connect the UART of the current thread to `port`, returning the previous one.
+pub fn attach(port: Option<Box<dyn Port>>) -> Option<Box<dyn Port>> {
+    PORT.with(|x| x.replace(port))
+}
+
+fn with_port<T>(f: impl FnOnce(&mut dyn Port) -> T) -> T {
+    PORT.with(|x| f(x.borrow_mut().as_deref_mut().expect("no port attached")))
+}
+
+pub fn uart_send_byte(byte: u8) {
+    with_port(|port| port.send(byte))
+}
+
+pub fn uart_try_recv_byte() -> Option<u8> {
+    with_port(|port| port.try_recv())
+}
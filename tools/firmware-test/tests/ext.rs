@@ -0,0 +1,594 @@
+//! ext2/3/4 on images generated by `mke2fs -d`, with files laid out to reach every level of the
+//! block map and the boundaries between them, with single bytes of metadata corrupted, and with
+//! symbolic links of every kind.
+
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+
+use firmware_test::block::Block;
+use firmware_test::fs::{mount, File, FileSystem, FileType};
+use firmware_test::io::{Error, Result};
+
+/// Filesystem image in memory.
+struct Image(Vec<u8>);
+
+impl Block for Image {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.0.get(offset..)?.get(..buf.len()))
+            .ok_or(Error::Textual("I/O error"))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+/// A file of `size` bytes, with data in the given ranges of blocks and holes elsewhere.
+struct Spec {
+    name: &'static str,
+    size: u64,
+    data: Vec<(u64, u64)>,
+}
+
+impl Spec {
+    /// A file without holes, `blocks` blocks and `tail` bytes long.
+    fn dense(name: &'static str, block_size: u64, blocks: u64, tail: u64) -> Self {
+        Spec {
+            name,
+            size: blocks * block_size + tail,
+            data: vec![(0, blocks + (tail != 0) as u64)],
+        }
+    }
+
+    fn expected(&self, block_size: u64, offset: u64) -> u8 {
+        let block = offset / block_size;
+        if self
+            .data
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&block))
+        {
+            pattern(offset)
+        } else {
+            0
+        }
+    }
+}
+
+/// Content of data blocks. Never zero, so `mke2fs` cannot turn data into holes.
+fn pattern(offset: u64) -> u8 {
+    (offset.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as u8 | 1
+}
+
+/// Block map layout, with 4-byte block pointers and 12 direct blocks.
+fn boundaries(block_size: u64) -> [u64; 3] {
+    let ptrs = block_size / 4;
+    [12, 12 + ptrs, 12 + ptrs + ptrs * ptrs]
+}
+
+fn specs(block_size: u64) -> Vec<Spec> {
+    let [single, double, triple] = boundaries(block_size);
+    let mut specs = vec![
+        Spec::dense("direct", block_size, single, 0),
+        Spec::dense("single", block_size, single, 1),
+        Spec::dense("single_full", block_size, double, 0),
+        Spec::dense("double", block_size, double + 1, 0),
+    ];
+    // A dense triple indirect file is only practical with 1KiB blocks.
+    if block_size == 1024 {
+        specs.push(Spec::dense("triple", block_size, triple + 300, 123));
+    } else {
+        specs.push(Spec::dense("double_more", block_size, double + 300, 123));
+    }
+    // Holes in the direct blocks, whole indirect blocks missing, and data on each side of every
+    // boundary.
+    specs.push(Spec {
+        name: "holes",
+        size: (triple + 2 * block_size / 4 + 7) * block_size,
+        data: vec![
+            (0, 1),
+            (single - 1, single + 1),
+            (double - 1, double + 1),
+            (double + block_size / 4 * 3, double + block_size / 4 * 3 + 1),
+            (triple - 1, triple + 1),
+            (triple + block_size / 4 + 1, triple + block_size / 4 + 5),
+        ],
+    });
+    specs.push(Spec {
+        name: "hole_at_end",
+        size: (double + 10) * block_size,
+        data: vec![(3, 4)],
+    });
+    specs
+}
+
+/// Write files laid out as in `specs` into `dir`.
+fn write_files(dir: &Path, specs: &[Spec], block_size: u64) {
+    let _ = fs::remove_dir_all(dir);
+    for spec in specs {
+        let path = dir.join(spec.name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = fs::File::create(path).unwrap();
+        for &(start, end) in &spec.data {
+            let start = start * block_size;
+            let end = (end * block_size).min(spec.size);
+            let data: Vec<u8> = (start..end).map(pattern).collect();
+            file.seek(SeekFrom::Start(start)).unwrap();
+            file.write_all(&data).unwrap();
+        }
+        file.set_len(spec.size).unwrap();
+    }
+}
+
+/// Write the files of [`specs`] into a directory, shared between images of the same block size.
+fn source(block_size: u64) -> &'static Path {
+    static SOURCES: [OnceLock<PathBuf>; 2] = [OnceLock::new(), OnceLock::new()];
+    SOURCES[(block_size == 4096) as usize].get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("firmware-test-ext-{}", block_size));
+        write_files(&dir, &specs(block_size), block_size);
+        dir
+    })
+}
+
+/// Create an image file from the files in `source` with `mke2fs -d`.
+fn mke2fs(name: &str, args: &[&str], block_size: u64, source: &Path, size: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("firmware-test-ext-{}.img", name));
+    let _ = fs::remove_file(&path);
+    let status = Command::new("mke2fs")
+        .args(["-q", "-F", "-L", "boot", "-b", &block_size.to_string()])
+        .args(args)
+        .arg("-d")
+        .arg(source)
+        .arg(&path)
+        .arg(size)
+        .stdout(Stdio::null())
+        .status()
+        .expect("mke2fs from e2fsprogs is needed to generate images");
+    assert!(status.success(), "mke2fs failed");
+    path
+}
+
+/// Create an image with the files of [`specs`] and mount it.
+fn mkfs(name: &str, args: &[&str], block_size: u64, size: &str) -> Box<dyn FileSystem> {
+    let path = mke2fs(name, args, block_size, source(block_size), size);
+    let image = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let fs = mount(Arc::new(Image(image))).unwrap();
+    assert_eq!(fs.label(), "boot");
+    fs
+}
+
+fn read_at(file: &mut dyn File, buf: &mut [u8], offset: u64) {
+    file.read_exact_at(buf, offset).unwrap_or_else(|err| {
+        panic!(
+            "read of {} bytes at {} failed: {:?}",
+            buf.len(),
+            offset,
+            err
+        )
+    });
+}
+
+fn check_range(spec: &Spec, block_size: u64, buf: &[u8], offset: u64) {
+    for (i, &byte) in buf.iter().enumerate() {
+        let pos = offset + i as u64;
+        if byte != spec.expected(block_size, pos) {
+            panic!(
+                "{}: byte {} (block {}) is {:#x}, expected {:#x}",
+                spec.name,
+                pos,
+                pos / block_size,
+                byte,
+                spec.expected(block_size, pos)
+            );
+        }
+    }
+}
+
+fn check_file(fs: &dyn FileSystem, spec: &Spec, block_size: u64) {
+    let mut file = fs.open(spec.name).unwrap();
+    assert_eq!(file.size(), spec.size, "{}", spec.name);
+
+    // Read whole files sequentially, in chunks that do not line up with blocks.
+    if spec.size <= 128 << 20 {
+        let mut buf = vec![0; 100_000];
+        let mut offset = 0;
+        while offset < spec.size {
+            let len = file.read(&mut buf).unwrap();
+            assert_ne!(len, 0, "{}: early end of file at {}", spec.name, offset);
+            check_range(spec, block_size, &buf[..len], offset);
+            offset += len as u64;
+        }
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+
+    // Reads across every boundary of the block map.
+    let mut buf = vec![0; block_size as usize * 3];
+    for boundary in boundaries(block_size) {
+        let boundary = boundary * block_size;
+        for offset in [boundary - block_size, boundary - 100, boundary] {
+            let len = buf.len().min(spec.size.saturating_sub(offset) as usize);
+            if offset < spec.size {
+                read_at(&mut *file, &mut buf[..len], offset);
+                check_range(spec, block_size, &buf[..len], offset);
+            }
+        }
+    }
+
+    // Reads at random offsets, so the indirect block cache is hit and missed in any order.
+    let mut seed = 0x2545_F491_4F6C_DD1D_u64;
+    for _ in 0..200 {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let offset = seed % spec.size;
+        let len = ((seed >> 40) as usize % buf.len()).min((spec.size - offset) as usize);
+        read_at(&mut *file, &mut buf[..len], offset);
+        check_range(spec, block_size, &buf[..len], offset);
+    }
+}
+
+fn check_image(name: &str, args: &[&str], block_size: u64, size: &str) {
+    let fs = mkfs(name, args, block_size, size);
+    for spec in specs(block_size) {
+        check_file(&*fs, &spec, block_size);
+    }
+}
+
+#[test]
+fn ext2() {
+    check_image("ext2", &["-t", "ext2"], 1024, "100M");
+}
+
+#[test]
+fn ext3() {
+    check_image("ext3", &["-t", "ext3"], 1024, "100M");
+}
+
+/// ext4 files without extents use the same block map as ext2 and ext3.
+#[test]
+fn ext4_block_map() {
+    check_image(
+        "ext4-block-map",
+        &["-t", "ext4", "-O", "^extent,^64bit"],
+        1024,
+        "100M",
+    );
+}
+
+#[test]
+fn ext4_extents() {
+    check_image("ext4-extents", &["-t", "ext4"], 1024, "100M");
+}
+
+/// With 4KiB blocks, the triple indirect blocks start beyond 4GiB.
+#[test]
+fn ext2_4k() {
+    check_image("ext2-4k", &["-t", "ext2"], 4096, "64M");
+}
+
+const CSUM_BLOCK_SIZE: u64 = 1024;
+const CSUM_FILES: [&str; 3] = ["boot/vmlinux", "boot/config-6.1.0", "frag"];
+
+/// Files of the images for checksum tests. `frag` has more extents than fit in its inode.
+fn csum_specs() -> Vec<Spec> {
+    vec![
+        Spec::dense(CSUM_FILES[0], CSUM_BLOCK_SIZE, 40, 10),
+        Spec::dense(CSUM_FILES[1], CSUM_BLOCK_SIZE, 2, 0),
+        Spec {
+            name: CSUM_FILES[2],
+            size: 12 * CSUM_BLOCK_SIZE,
+            data: (0..6).map(|i| (i * 2, i * 2 + 1)).collect(),
+        },
+    ]
+}
+
+/// Image for checksum tests, with the offsets of metadata bytes whose corruption goes unnoticed
+/// without checksums.
+struct CsumImage {
+    data: Vec<u8>,
+    /// A byte of the volume label in the superblock.
+    superblock: usize,
+    /// A byte of the free block count in the group descriptor of `boot/vmlinux`.
+    group_desc: usize,
+    /// A byte of the access time of `boot/vmlinux`.
+    inode: usize,
+    /// A byte of the generation in the extent block of `frag`.
+    extent_block: usize,
+    /// A byte of the padding after the last entry of the `boot` directory.
+    dir_block: usize,
+}
+
+fn command_output(program: &str, args: &[&str], image: &Path) -> String {
+    let output = Command::new(program)
+        .args(args)
+        .arg(image)
+        .stderr(Stdio::null())
+        .output()
+        .unwrap_or_else(|_| panic!("{} from e2fsprogs is needed to inspect images", program));
+    assert!(output.status.success(), "{} failed", program);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Parse the number after the first occurrence of `prefix`.
+fn number_after(output: &str, prefix: &str) -> usize {
+    let rest = &output[output
+        .find(prefix)
+        .unwrap_or_else(|| panic!("no {:?}", prefix))
+        + prefix.len()..];
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    match rest[..end].strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).unwrap(),
+        None => rest[..end].parse().unwrap(),
+    }
+}
+
+fn csum_image(name: &str, args: &[&str]) -> CsumImage {
+    let dir = std::env::temp_dir().join(format!("firmware-test-ext-{}", name));
+    write_files(&dir, &csum_specs(), CSUM_BLOCK_SIZE);
+    let path = mke2fs(name, args, CSUM_BLOCK_SIZE, &dir, "8M");
+
+    let block_size = CSUM_BLOCK_SIZE as usize;
+    let header = command_output("dumpe2fs", &["-h"], &path);
+    let desc_size = match header.find("Group descriptor size:") {
+        Some(_) => number_after(&header, "Group descriptor size:"),
+        None => 32,
+    };
+    let imap = command_output("debugfs", &["-R", "imap /boot/vmlinux"], &path);
+    let group = number_after(&imap, "part of block group");
+    let inode =
+        number_after(&imap, "located at block") * block_size + number_after(&imap, "offset");
+    let frag = command_output("debugfs", &["-R", "stat /frag"], &path);
+    let boot = command_output("debugfs", &["-R", "stat /boot"], &path);
+
+    let image = CsumImage {
+        data: fs::read(&path).unwrap(),
+        superblock: 1024 + 0x78,
+        // The group descriptor table follows the superblock, in block 2 with 1KiB blocks.
+        group_desc: 2 * block_size + group * desc_size + 0x0C,
+        inode: inode + 0x08,
+        extent_block: number_after(&frag, "(ETB0):") * block_size + 8,
+        // The checksum tail takes the last 12 bytes.
+        dir_block: (number_after(&boot, "(0):") + 1) * block_size - 13,
+    };
+    fs::remove_file(&path).unwrap();
+    image
+}
+
+/// ext4 image with metadata checksums.
+fn metadata_csum_image() -> &'static CsumImage {
+    static IMAGE: OnceLock<CsumImage> = OnceLock::new();
+    IMAGE.get_or_init(|| csum_image("metadata-csum", &["-t", "ext4"]))
+}
+
+/// ext4 image with only group descriptor checksums.
+fn gdt_csum_image() -> &'static CsumImage {
+    static IMAGE: OnceLock<CsumImage> = OnceLock::new();
+    IMAGE.get_or_init(|| {
+        csum_image(
+            "gdt-csum",
+            &["-t", "ext4", "-O", "^metadata_csum,uninit_bg"],
+        )
+    })
+}
+
+fn read_file(fs: &dyn FileSystem, path: &str) -> Result<Vec<u8>> {
+    let mut file = fs.open(path)?;
+    let mut buf = vec![0; file.size() as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Flip a bit at `offset` and read every file. Files in `damaged` depend on the corrupted
+/// metadata, so they can only be read if checksum mismatches are merely warned about.
+fn check_corrupted(image: &CsumImage, offset: usize, damaged: &[&str]) {
+    let warn = cfg!(feature = "ext-csum-warn");
+    let mut data = image.data.clone();
+    data[offset] ^= 1;
+
+    let fs = match mount(Arc::new(Image(data))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            assert!(!warn, "mount failed: {:?}", err);
+            assert_eq!(damaged, CSUM_FILES, "mount failed: {:?}", err);
+            return;
+        }
+    };
+    for spec in csum_specs() {
+        if damaged.contains(&spec.name) && !warn {
+            assert!(
+                read_file(&*fs, spec.name).is_err(),
+                "{} was read",
+                spec.name
+            );
+        } else {
+            check_file(&*fs, &spec, CSUM_BLOCK_SIZE);
+        }
+    }
+}
+
+#[test]
+fn csum_intact() {
+    for image in [metadata_csum_image(), gdt_csum_image()] {
+        let fs = mount(Arc::new(Image(image.data.clone()))).unwrap();
+        for spec in csum_specs() {
+            check_file(&*fs, &spec, CSUM_BLOCK_SIZE);
+        }
+    }
+}
+
+#[test]
+fn csum_superblock() {
+    let image = metadata_csum_image();
+    check_corrupted(image, image.superblock, &CSUM_FILES);
+}
+
+#[test]
+fn csum_group_desc() {
+    let image = metadata_csum_image();
+    check_corrupted(image, image.group_desc, &CSUM_FILES);
+}
+
+#[test]
+fn csum_group_desc_crc16() {
+    let image = gdt_csum_image();
+    check_corrupted(image, image.group_desc, &CSUM_FILES);
+}
+
+#[test]
+fn csum_inode() {
+    let image = metadata_csum_image();
+    check_corrupted(image, image.inode, &["boot/vmlinux"]);
+}
+
+#[test]
+fn csum_extent_block() {
+    let image = metadata_csum_image();
+    check_corrupted(image, image.extent_block, &["frag"]);
+}
+
+#[test]
+fn csum_dir_block() {
+    let image = metadata_csum_image();
+    check_corrupted(
+        image,
+        image.dir_block,
+        &["boot/vmlinux", "boot/config-6.1.0"],
+    );
+}
+
+/// Image with a range of bytes that cannot be read.
+struct Unreadable {
+    image: Image,
+    bad: std::ops::Range<u64>,
+}
+
+impl Block for Unreadable {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        if offset < self.bad.end && offset + buf.len() as u64 > self.bad.start {
+            return Err(Error::Textual("I/O error"));
+        }
+        self.image.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn len(&self) -> u64 {
+        self.image.len()
+    }
+}
+
+/// Read errors on group descriptors and inodes are returned rather than panicking.
+#[test]
+fn unreadable_metadata() {
+    let image = metadata_csum_image();
+    for offset in [image.group_desc, image.inode] {
+        let block = offset as u64 / CSUM_BLOCK_SIZE * CSUM_BLOCK_SIZE;
+        let disk = Unreadable {
+            image: Image(image.data.clone()),
+            bad: block..block + CSUM_BLOCK_SIZE,
+        };
+        if let Ok(fs) = mount(Arc::new(disk)) {
+            assert!(read_file(&*fs, "boot/vmlinux").is_err());
+        }
+    }
+}
+
+const SYMLINK_FILE: &str = "boot/vmlinux-6.1";
+
+/// Links of the symlink image, with their targets.
+const SYMLINKS: [(&str, &str); 7] = [
+    // Fast link, relative to the directory containing it.
+    ("boot/vmlinux", "vmlinux-6.1"),
+    ("boot/absolute", "/boot/vmlinux-6.1"),
+    // Longer than the 60 bytes stored in the inode, so kept in a data block.
+    (
+        "boot/slow",
+        "./././././././././././././././././././././././././vmlinux-6.1",
+    ),
+    ("boot/old/parent", "../vmlinux-6.1"),
+    ("kernel", "boot"),
+    // Through a link to a directory, then `..` from its target.
+    ("vmlinux", "kernel/../boot/vmlinux"),
+    ("loop", "loop"),
+];
+
+/// Paths that lead to [`SYMLINK_FILE`] through links.
+const SYMLINK_PATHS: [&str; 7] = [
+    "boot/vmlinux",
+    "boot/absolute",
+    "boot/slow",
+    "boot/old/parent",
+    "vmlinux",
+    "kernel/vmlinux-6.1",
+    // A link relative to the directory it is in, reached through a link to that directory.
+    "kernel/vmlinux",
+];
+
+fn symlink_spec(name: &'static str) -> Spec {
+    Spec::dense(name, CSUM_BLOCK_SIZE, 5, 17)
+}
+
+/// Image with [`SYMLINK_FILE`] and the links of [`SYMLINKS`].
+fn symlink_image(name: &str, args: &[&str]) -> Box<dyn FileSystem> {
+    let dir = std::env::temp_dir().join(format!("firmware-test-ext-{}", name));
+    write_files(&dir, &[symlink_spec(SYMLINK_FILE)], CSUM_BLOCK_SIZE);
+    for (link, target) in SYMLINKS {
+        let link = dir.join(link);
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(target, link).unwrap();
+    }
+    let path = mke2fs(name, args, CSUM_BLOCK_SIZE, &dir, "8M");
+
+    // Make sure both kinds of link are generated.
+    let fast = command_output("debugfs", &["-R", "stat /boot/vmlinux"], &path);
+    assert!(fast.contains("Fast link dest"));
+    let slow = command_output("debugfs", &["-R", "stat /boot/slow"], &path);
+    assert!(!slow.contains("Fast link dest"));
+
+    let image = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    mount(Arc::new(Image(image))).unwrap()
+}
+
+#[test]
+fn symlinks() {
+    for (name, args) in [("symlinks-ext2", "ext2"), ("symlinks-ext4", "ext4")] {
+        let fs = symlink_image(name, &["-t", args]);
+        for path in SYMLINK_PATHS {
+            let metadata = fs
+                .stat(path)
+                .unwrap_or_else(|err| panic!("{}: {:?}", path, err));
+            assert_eq!(metadata.file_type, FileType::File, "{}", path);
+            check_file(&*fs, &symlink_spec(path), CSUM_BLOCK_SIZE);
+        }
+
+        assert_eq!(fs.stat("kernel").unwrap().file_type, FileType::Dir);
+        let entries = fs.read_dir("kernel").unwrap();
+        let vmlinux = entries
+            .iter()
+            .find(|entry| entry.name == "vmlinux")
+            .unwrap();
+        assert_eq!(vmlinux.file_type, FileType::Symlink);
+
+        // Following the loop stops after MAX_SYMLINKS links.
+        assert!(fs.stat("loop").is_err());
+        assert!(fs.open("loop").is_err());
+        assert!(fs.open("loop/x").is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! FAT32 on an image formatted and populated with the `fatfs` crate, with files fragmented
+//! across clusters and a directory spanning several clusters.
+
+use std::io::{Cursor, Write};
+use std::sync::{Arc, OnceLock};
+
+use fatfs::{FatType, FormatVolumeOptions, FsOptions};
+
+use firmware_test::block::Block;
+use firmware_test::fs::{mount, FileSystem, FileType};
+use firmware_test::io::{Error, Result};
+
+const CLUSTER_SIZE: u64 = 1024;
+/// Just large enough for FAT32, which needs at least 65525 clusters.
+const IMAGE_SIZE: usize = 72 << 20;
+/// Number of files in `many`, whose entries take several clusters.
+const MANY: usize = 40;
+
+/// Filesystem image in memory.
+struct Image(Vec<u8>);
+
+impl Block for Image {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.0.get(offset..)?.get(..buf.len()))
+            .ok_or(Error::Textual("I/O error"))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+/// Files of the image, with their sizes.
+const FILES: [(&str, u64); 6] = [
+    // Written a cluster at a time in turn, so their clusters interleave.
+    ("Image", 50 * CLUSTER_SIZE + 100),
+    ("initrd.img", 30 * CLUSTER_SIZE + 1000),
+    // A short name without a long name entry.
+    ("README.TXT", 300),
+    // Ends exactly at a cluster boundary.
+    ("boot/device_tree.dtb", 2 * CLUSTER_SIZE),
+    ("boot/cmdline.txt", 20),
+    ("empty", 0),
+];
+
+/// File content, different for each file.
+fn pattern(name: &str, offset: u64) -> u8 {
+    let seed = name
+        .bytes()
+        .fold(0u64, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u64));
+    (offset
+        .wrapping_add(seed)
+        .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        >> 56) as u8
+}
+
+fn content(name: &str, size: u64) -> Vec<u8> {
+    (0..size).map(|offset| pattern(name, offset)).collect()
+}
+
+fn image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| {
+        let mut image = vec![0; IMAGE_SIZE];
+        fatfs::format_volume(
+            Cursor::new(&mut image[..]),
+            FormatVolumeOptions::new()
+                .fat_type(FatType::Fat32)
+                .bytes_per_cluster(CLUSTER_SIZE as u32)
+                .volume_label(*b"BOOT       "),
+        )
+        .unwrap();
+
+        let fs = fatfs::FileSystem::new(Cursor::new(&mut image[..]), FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+        root.create_dir("boot").unwrap();
+        let mut files: Vec<_> = FILES
+            .iter()
+            .map(|&(name, size)| (root.create_file(name).unwrap(), content(name, size)))
+            .collect();
+        let mut offset = 0;
+        while files.iter().any(|(_, data)| offset < data.len()) {
+            for (file, data) in &mut files {
+                let end = data.len().min(offset + CLUSTER_SIZE as usize);
+                if offset < end {
+                    file.write_all(&data[offset..end]).unwrap();
+                }
+            }
+            offset += CLUSTER_SIZE as usize;
+        }
+        drop(files);
+
+        let many = root.create_dir("many").unwrap();
+        for i in 0..MANY {
+            let name = format!("file-{}", i);
+            let mut file = many.create_file(&name).unwrap();
+            file.write_all(name.as_bytes()).unwrap();
+        }
+        drop(many);
+        drop(root);
+        fs.unmount().unwrap();
+        image
+    })
+}
+
+fn mount_image() -> Box<dyn FileSystem> {
+    mount(Arc::new(Image(image().to_vec()))).unwrap()
+}
+
+fn check_range(name: &str, buf: &[u8], offset: u64) {
+    for (i, &byte) in buf.iter().enumerate() {
+        let pos = offset + i as u64;
+        assert_eq!(byte, pattern(name, pos), "{}: byte {}", name, pos);
+    }
+}
+
+/// `mount` detects FAT32, and the label is read from the BPB.
+#[test]
+fn label() {
+    let fs = mount_image();
+    assert_eq!(fs.label(), "BOOT");
+}
+
+#[test]
+fn read_dir() {
+    let fs = mount_image();
+    let mut root: Vec<_> = fs
+        .read_dir("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.name, entry.file_type))
+        .collect();
+    root.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        root,
+        [
+            ("Image".into(), FileType::File),
+            ("README.TXT".into(), FileType::File),
+            ("boot".into(), FileType::Dir),
+            ("empty".into(), FileType::File),
+            ("initrd.img".into(), FileType::File),
+            ("many".into(), FileType::Dir),
+        ]
+    );
+
+    let mut boot: Vec<_> = fs
+        .read_dir("boot")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    boot.sort();
+    assert_eq!(boot, ["cmdline.txt", "device_tree.dtb"]);
+
+    let mut many: Vec<_> = fs
+        .read_dir("/many/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    many.sort();
+    let mut expected: Vec<_> = (0..MANY).map(|i| format!("file-{}", i)).collect();
+    expected.sort();
+    assert_eq!(many, expected);
+    let mut file = fs.open(&format!("many/file-{}", MANY - 1)).unwrap();
+    let mut buf = vec![0; file.size() as usize];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, format!("file-{}", MANY - 1).as_bytes());
+
+    assert!(fs.read_dir("Image").is_err());
+    assert!(fs.read_dir("missing").is_err());
+}
+
+/// Names are looked up ignoring case, whether stored as long or short names.
+#[test]
+fn case_insensitive() {
+    let fs = mount_image();
+    for path in [
+        "image",
+        "IMAGE",
+        "/Image",
+        "readme.txt",
+        "Boot/Device_Tree.DTB",
+    ] {
+        let metadata = fs.stat(path).unwrap();
+        assert_eq!(metadata.file_type, FileType::File, "{}", path);
+        assert!(fs.open(path).is_ok(), "{}", path);
+    }
+    assert_eq!(fs.stat("IMAGE").unwrap().size, FILES[0].1);
+    assert_eq!(fs.stat("BOOT").unwrap().file_type, FileType::Dir);
+    assert!(fs.open("BOOT").is_err());
+    assert!(fs.stat("imag").is_err());
+    assert!(fs.stat("image/x").is_err());
+}
+
+#[test]
+fn sequential_reads() {
+    let fs = mount_image();
+    for (name, size) in FILES {
+        let mut file = fs.open(name).unwrap();
+        assert_eq!(file.size(), size, "{}", name);
+        // Chunks that do not line up with sectors or clusters.
+        let mut buf = vec![0; 1500];
+        let mut offset = 0;
+        while offset < size {
+            let len = file.read(&mut buf).unwrap();
+            assert_ne!(len, 0, "{}: early end of file at {}", name, offset);
+            check_range(name, &buf[..len], offset);
+            offset += len as u64;
+        }
+        assert_eq!(offset, size);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+}
+
+/// Reads on and across sector and cluster boundaries, at the end of the file and past it.
+#[test]
+fn reads_at() {
+    let fs = mount_image();
+    for (name, size) in FILES {
+        let mut file = fs.open(name).unwrap();
+        let mut offsets = vec![0, 1, 511, 512, 513];
+        for boundary in (CLUSTER_SIZE..=size).step_by(CLUSTER_SIZE as usize) {
+            offsets.extend([boundary - 600, boundary - 512, boundary - 1, boundary]);
+        }
+        offsets.push(size.saturating_sub(1));
+        for offset in offsets.into_iter().filter(|&offset| offset < size) {
+            for len in [1, 2, 100, 512, 1024, 2500] {
+                let len = len.min(size - offset) as usize;
+                let mut buf = vec![0; len];
+                file.read_exact_at(&mut buf, offset).unwrap_or_else(|err| {
+                    panic!("{}: read of {} at {} failed: {:?}", name, len, offset, err)
+                });
+                check_range(name, &buf, offset);
+            }
+        }
+
+        // At the end of the file nothing is left, and past it reads fail.
+        let mut buf = [0; 10];
+        assert_eq!(file.read_at(&mut buf, size).unwrap(), 0, "{}", name);
+        assert!(file.read_exact_at(&mut buf, size).is_err(), "{}", name);
+        assert!(
+            file.read_exact_at(&mut buf, size + 1000).is_err(),
+            "{}",
+            name
+        );
+        if size >= 5 {
+            assert!(file.read_exact_at(&mut buf, size - 5).is_err(), "{}", name);
+        }
+
+        // Going back after reaching the end.
+        if size != 0 {
+            let mut buf = [0; 1];
+            file.read_exact_at(&mut buf, 0).unwrap();
+            check_range(name, &buf, 0);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! DEFLATE and gzip decompression of streams produced by flate2.
+
+use std::io::Write;
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, GzBuilder};
+
+use firmware_test::inflate::{gunzip, gunzip_size, inflate, is_gzip};
+
+fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Type of the first block of a DEFLATE stream.
+fn block_type(stream: &[u8]) -> u8 {
+    (stream[0] >> 1) & 3
+}
+
+/// Pseudo-random bytes, which do not compress.
+fn noise(len: usize) -> Vec<u8> {
+    let mut seed = 0x9E37_79B9_u32;
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+        .collect()
+}
+
+/// Text with repetitions at short and long distances, compressed with dynamic Huffman codes.
+fn text(len: usize) -> Vec<u8> {
+    const WORDS: [&str; 8] = [
+        "kernel ", "initrd ", "device ", "tree ", "muntjac ", "boot ", "loader ", "\n",
+    ];
+    let noise = noise(len);
+    let mut text = Vec::new();
+    let mut i = 0;
+    while text.len() < len {
+        text.extend_from_slice(WORDS[noise[i] as usize % WORDS.len()].as_bytes());
+        i += 1;
+    }
+    text.truncate(len);
+    // Copy an earlier part, so some matches are close to the maximum distance of 32KiB.
+    if len > 40000 {
+        text.copy_within(100..1100, 32800);
+    }
+    text
+}
+
+fn check_inflate(data: &[u8], stream: &[u8]) {
+    let mut out = vec![0; data.len()];
+    let (consumed, written) = inflate(stream, &mut out).unwrap();
+    assert_eq!(consumed, stream.len());
+    assert_eq!(written, data.len());
+    assert!(out == data);
+}
+
+#[test]
+fn stored() {
+    // Stored blocks hold at most 65535 bytes, so this needs several.
+    let data = noise(150_000);
+    let stream = deflate(&data, 0);
+    assert_eq!(block_type(&stream), 0);
+    check_inflate(&data, &stream);
+
+    let stream = deflate(&[], 0);
+    check_inflate(&[], &stream);
+}
+
+#[test]
+fn fixed() {
+    let data = b"hello hello hello, hello world";
+    let stream = deflate(data, 1);
+    assert_eq!(block_type(&stream), 1);
+    check_inflate(data, &stream);
+}
+
+#[test]
+fn dynamic() {
+    for level in [1, 6, 9] {
+        let data = text(200_000);
+        let stream = deflate(&data, level);
+        assert_eq!(block_type(&stream), 2);
+        check_inflate(&data, &stream);
+    }
+}
+
+#[test]
+fn gzip_headers() {
+    let data = text(50_000);
+    let plain = gzip(&data);
+    let mut encoder = GzBuilder::new()
+        .filename("vmlinux")
+        .comment("kernel")
+        .extra(vec![1, 2, 3, 4])
+        .write(Vec::new(), Compression::best());
+    encoder.write_all(&data).unwrap();
+    let with_headers = encoder.finish().unwrap();
+
+    for file in [plain, with_headers] {
+        assert!(is_gzip(&file));
+        assert_eq!(gunzip_size(&file).unwrap(), data.len());
+        let mut out = vec![0; data.len()];
+        gunzip(&file, &mut out).unwrap();
+        assert!(out == data);
+    }
+    assert!(!is_gzip(&data));
+}
+
+#[test]
+fn output_too_small() {
+    let data = text(10_000);
+    for stream in [deflate(&data, 0), deflate(&data, 1), deflate(&data, 9)] {
+        let mut out = vec![0; data.len() - 1];
+        assert!(inflate(&stream, &mut out).is_err());
+    }
+
+    let file = gzip(&data);
+    let mut out = vec![0; data.len() - 1];
+    assert!(gunzip(&file, &mut out).is_err());
+}
+
+/// Every bit flip is either detected, or does not change the output.
+#[test]
+fn corrupted() {
+    let data = text(20_000);
+    let file = gzip(&data);
+    let mut out = vec![0; data.len()];
+    for i in 0..file.len() {
+        for bit in 0..8 {
+            let mut corrupted = file.clone();
+            corrupted[i] ^= 1 << bit;
+            if gunzip(&corrupted, &mut out).is_ok() {
+                assert!(out == data, "flip of bit {} at {} went unnoticed", bit, i);
+            }
+        }
+    }
+
+    for level in [0, 1, 9] {
+        let stream = deflate(&data, level);
+        for i in 0..stream.len() {
+            let mut corrupted = stream.clone();
+            corrupted[i] ^= 0x10;
+            let _ = inflate(&corrupted, &mut out);
+        }
+    }
+}
+
+#[test]
+fn garbage() {
+    let mut out = vec![0; 100_000];
+    for len in [1, 2, 10, 100, 1000, 10_000] {
+        let data = noise(len);
+        let _ = inflate(&data, &mut out);
+        let mut file = vec![0x1F, 0x8B, 8, 0xFF];
+        file.extend_from_slice(&data);
+        assert!(gunzip(&file, &mut out).is_err());
+    }
+}
+
+#[test]
+fn truncated() {
+    let data = text(20_000);
+    let mut out = vec![0; data.len()];
+    for stream in [
+        deflate(&data, 0),
+        deflate(&data[..30], 1),
+        deflate(&data, 9),
+    ] {
+        for len in 0..stream.len() {
+            assert!(
+                inflate(&stream[..len], &mut out).is_err(),
+                "stream truncated to {} bytes was accepted",
+                len
+            );
+        }
+    }
+
+    let file = gzip(&data);
+    for len in 0..file.len() {
+        assert!(
+            gunzip(&file[..len], &mut out).is_err(),
+            "file truncated to {} bytes was accepted",
+            len
+        );
+    }
+}
@@ -0,0 +1,484 @@
+//! DHCP and TFTP against a simulated server on the other end of a loopback device.
+//!
+//! Every frame that crosses the device is also written to a pcap file in the temporary
+//! directory, which can be opened in Wireshark when a test fails.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use firmware_test::io::Result;
+use firmware_test::net::{dhcp, tftp, Interface, NetDevice};
+
+const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const BOOT_FILE: &str = "Image";
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        sum += (chunk[0] as u32) << 8 | *chunk.get(1).unwrap_or(&0) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn udp_pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, udp: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&src.octets());
+    buf.extend_from_slice(&dst.octets());
+    buf.extend_from_slice(&[0, 17]);
+    buf.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    buf.extend_from_slice(udp);
+    buf
+}
+
+/// Build an Ethernet frame carrying a UDP datagram.
+fn udp_frame(dst_mac: [u8; 6], dst: (Ipv4Addr, u16), src_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = Vec::new();
+    udp.extend_from_slice(&src_port.to_be_bytes());
+    udp.extend_from_slice(&dst.1.to_be_bytes());
+    udp.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    let sum = checksum(&udp_pseudo_header(SERVER_IP, dst.0, &udp));
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+    ip[2..4].copy_from_slice(&(20 + udp.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&SERVER_IP.octets());
+    ip.extend_from_slice(&dst.0.octets());
+    let sum = checksum(&ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&SERVER_MAC);
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&ip);
+    frame.extend_from_slice(&udp);
+    frame
+}
+
+/// A TFTP transfer in progress.
+struct Transfer {
+    client_port: u16,
+    tid: u16,
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl Transfer {
+    fn block(&self, n: u16) -> Vec<u8> {
+        let start = (n as usize - 1) * self.block_size;
+        let end = (start + self.block_size).min(self.data.len());
+        let mut packet = vec![0, 3];
+        packet.extend_from_slice(&n.to_be_bytes());
+        packet.extend_from_slice(&self.data[start.min(end)..end]);
+        packet
+    }
+
+    fn blocks(&self) -> u16 {
+        (self.data.len() / self.block_size + 1) as u16
+    }
+}
+
+/// Host on the other end of the link, acting as DHCP and TFTP server.
+#[derive(Default)]
+struct Server {
+    files: HashMap<String, Vec<u8>>,
+    /// Whether TFTP options (RFC 2347) are understood.
+    options: bool,
+    /// Block size to acknowledge instead of the one requested.
+    block_size: Option<usize>,
+    /// Size to announce instead of the real one.
+    tsize: Option<usize>,
+    /// Data block whose first transmission is lost.
+    drop_block: Option<u16>,
+    transfer: Option<Transfer>,
+    next_tid: u16,
+    /// When each RRQ arrived.
+    rrqs: Vec<Instant>,
+    acks: usize,
+    leased: bool,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl Server {
+    fn handle(&mut self, frame: &[u8]) {
+        assert!(frame.len() >= 60, "runt frame");
+        assert_eq!(frame[6..12], CLIENT_MAC);
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            0x0806 => self.handle_arp(&frame[14..]),
+            0x0800 => self.handle_ipv4(&frame[14..]),
+            ty => panic!("unexpected ethertype {:#x}", ty),
+        }
+    }
+
+    fn handle_arp(&mut self, arp: &[u8]) {
+        assert_eq!(arp[0..8], [0, 1, 8, 0, 6, 4, 0, 1], "not an ARP request");
+        assert_eq!(arp[8..14], CLIENT_MAC);
+        assert_eq!(arp[14..18], CLIENT_IP.octets());
+        if arp[24..28] != SERVER_IP.octets() {
+            return;
+        }
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&CLIENT_MAC);
+        reply.extend_from_slice(&SERVER_MAC);
+        reply.extend_from_slice(&[0x08, 0x06, 0, 1, 8, 0, 6, 4, 0, 2]);
+        reply.extend_from_slice(&SERVER_MAC);
+        reply.extend_from_slice(&SERVER_IP.octets());
+        reply.extend_from_slice(&CLIENT_MAC);
+        reply.extend_from_slice(&CLIENT_IP.octets());
+        reply.resize(60, 0);
+        self.replies.push_back(reply);
+    }
+
+    fn handle_ipv4(&mut self, ip: &[u8]) {
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(checksum(&ip[..20]), 0, "bad IPv4 header checksum");
+        assert_eq!(ip[9], 17, "not UDP");
+        let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        let src = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[12..16]).unwrap());
+        let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&ip[16..20]).unwrap());
+        let udp = &ip[20..total_len];
+        assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+        assert_eq!(
+            checksum(&udp_pseudo_header(src, dst, udp)),
+            0,
+            "bad UDP checksum"
+        );
+        let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+        let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+        let payload = &udp[8..];
+
+        match dst_port {
+            67 => {
+                assert_eq!((src, dst), (Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST));
+                self.handle_dhcp(payload);
+            }
+            69 => {
+                assert_eq!((src, dst), (CLIENT_IP, SERVER_IP));
+                self.handle_rrq(src_port, payload);
+            }
+            port if self.transfer.as_ref().map(|t| t.tid) == Some(port) => {
+                assert_eq!(self.transfer.as_ref().unwrap().client_port, src_port);
+                self.handle_ack(payload);
+            }
+            port => panic!("datagram to unexpected port {}", port),
+        }
+    }
+
+    fn handle_dhcp(&mut self, msg: &[u8]) {
+        assert_eq!(msg[0..3], [1, 1, 6]);
+        assert_eq!(msg[28..34], CLIENT_MAC);
+        assert_eq!(msg[236..240], [99, 130, 83, 99]);
+        let mut options = HashMap::new();
+        let mut rest = &msg[240..];
+        while rest[0] != 255 {
+            options.insert(rest[0], rest[2..2 + rest[1] as usize].to_vec());
+            rest = &rest[2 + rest[1] as usize..];
+        }
+
+        let reply_type = match options[&53][0] {
+            // DHCPDISCOVER
+            1 => 2,
+            // DHCPREQUEST
+            3 => {
+                assert_eq!(options[&50], CLIENT_IP.octets());
+                assert_eq!(options[&54], SERVER_IP.octets());
+                self.leased = true;
+                5
+            }
+            ty => panic!("unexpected DHCP message type {}", ty),
+        };
+
+        let mut reply = vec![0; 240];
+        reply[0..3].copy_from_slice(&[2, 1, 6]);
+        reply[4..8].copy_from_slice(&msg[4..8]);
+        reply[16..20].copy_from_slice(&CLIENT_IP.octets());
+        reply[28..34].copy_from_slice(&CLIENT_MAC);
+        reply[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        let server = SERVER_IP.to_string();
+        for (code, value) in [
+            (53, &[reply_type][..]),
+            (54, &SERVER_IP.octets()),
+            (1, &[255, 255, 255, 0]),
+            (66, server.as_bytes()),
+            (67, BOOT_FILE.as_bytes()),
+        ] {
+            reply.extend_from_slice(&[code, value.len() as u8]);
+            reply.extend_from_slice(value);
+        }
+        reply.push(255);
+        self.replies
+            .push_back(udp_frame([0xFF; 6], (Ipv4Addr::BROADCAST, 68), 67, &reply));
+    }
+
+    fn handle_rrq(&mut self, port: u16, msg: &[u8]) {
+        self.rrqs.push(Instant::now());
+        assert_eq!(msg[0..2], [0, 1], "not an RRQ");
+        let fields: Vec<&str> = std::str::from_utf8(&msg[2..msg.len() - 1])
+            .unwrap()
+            .split('\0')
+            .collect();
+        assert_eq!(fields[1], "octet");
+        let requested: HashMap<&str, &str> = fields[2..]
+            .chunks(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+
+        self.next_tid += 1;
+        let tid = 50000 + self.next_tid;
+        let data = match self.files.get(fields[0]) {
+            Some(v) => v.clone(),
+            None => {
+                let mut error = vec![0, 5, 0, 1];
+                error.extend_from_slice(b"File not found\0");
+                self.replies
+                    .push_back(udp_frame(CLIENT_MAC, (CLIENT_IP, port), tid, &error));
+                return;
+            }
+        };
+
+        let mut transfer = Transfer {
+            client_port: port,
+            tid,
+            data,
+            block_size: 512,
+        };
+        if self.options {
+            let block_size = self
+                .block_size
+                .unwrap_or_else(|| requested["blksize"].parse().unwrap());
+            transfer.block_size = block_size;
+            let tsize = self.tsize.unwrap_or(transfer.data.len());
+            let mut oack = vec![0, 6];
+            oack.extend_from_slice(format!("blksize\0{}\0", block_size).as_bytes());
+            oack.extend_from_slice(format!("tsize\0{}\0", tsize).as_bytes());
+            self.replies
+                .push_back(udp_frame(CLIENT_MAC, (CLIENT_IP, port), tid, &oack));
+            self.transfer = Some(transfer);
+        } else {
+            self.transfer = Some(transfer);
+            self.send_block(1);
+        }
+    }
+
+    fn send_block(&mut self, n: u16) {
+        if self.drop_block == Some(n) {
+            self.drop_block = None;
+            return;
+        }
+        let transfer = self.transfer.as_ref().unwrap();
+        let packet = transfer.block(n);
+        let frame = udp_frame(
+            CLIENT_MAC,
+            (CLIENT_IP, transfer.client_port),
+            transfer.tid,
+            &packet,
+        );
+        self.replies.push_back(frame);
+    }
+
+    fn handle_ack(&mut self, msg: &[u8]) {
+        assert_eq!(msg[0..2], [0, 4], "not an ACK");
+        self.acks += 1;
+        let n = u16::from_be_bytes([msg[2], msg[3]]);
+        if n < self.transfer.as_ref().unwrap().blocks() {
+            self.send_block(n + 1);
+        }
+    }
+}
+
+/// Loopback device whose other end is a [`Server`].
+struct Loopback {
+    server: Rc<RefCell<Server>>,
+    pcap: File,
+}
+
+impl Loopback {
+    fn new(server: Rc<RefCell<Server>>, name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("firmware-test-{}.pcap", name));
+        let mut pcap = File::create(path).unwrap();
+        // Version 2.4, microsecond timestamps, Ethernet link type.
+        let mut header = Vec::new();
+        for field in [0xA1B2C3D4u32, 0x0004_0002, 0, 0, 65535, 1] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        pcap.write_all(&header).unwrap();
+        Loopback { server, pcap }
+    }
+
+    fn capture(&mut self, frame: &[u8]) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        let mut record = Vec::new();
+        for field in [
+            time.as_secs() as u32,
+            time.subsec_micros(),
+            frame.len() as u32,
+            frame.len() as u32,
+        ] {
+            record.extend_from_slice(&field.to_le_bytes());
+        }
+        record.extend_from_slice(frame);
+        self.pcap.write_all(&record).unwrap();
+    }
+}
+
+impl NetDevice for Loopback {
+    fn mac_address(&self) -> [u8; 6] {
+        CLIENT_MAC
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        self.capture(frame);
+        self.server.borrow_mut().handle(frame);
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let frame = self.server.borrow_mut().replies.pop_front()?;
+        self.capture(&frame);
+        buf[..frame.len()].copy_from_slice(&frame);
+        Some(frame.len())
+    }
+}
+
+fn setup(name: &str, server: Server) -> (Interface<Loopback>, Rc<RefCell<Server>>) {
+    let server = Rc::new(RefCell::new(server));
+    let mut iface = Interface::new(Loopback::new(server.clone(), name));
+    let lease = dhcp::configure(&mut iface).unwrap();
+    assert!(server.borrow().leased);
+    assert_eq!(lease.ip, CLIENT_IP);
+    assert_eq!(lease.server_id, SERVER_IP);
+    assert_eq!(lease.boot_server, Some(SERVER_IP));
+    assert_eq!(lease.boot_file.as_deref(), Some(BOOT_FILE));
+    assert_eq!(iface.ip, CLIENT_IP);
+    (iface, server)
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len as u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect()
+}
+
+#[test]
+fn dhcp_then_tftp() {
+    let file = pattern(100_000);
+    let mut server = Server {
+        options: true,
+        ..Default::default()
+    };
+    server.files.insert(BOOT_FILE.into(), file.clone());
+    let (mut iface, server) = setup("dhcp_then_tftp", server);
+
+    let start = Instant::now();
+    let data = tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 1 << 20).unwrap();
+    assert_eq!(data.as_deref(), Some(&file[..]));
+
+    let server = server.borrow();
+    // The request must go out straight away, not after a receive timeout.
+    assert_eq!(server.rrqs.len(), 1);
+    assert!(server.rrqs[0] - start < Duration::from_millis(500));
+    let transfer = server.transfer.as_ref().unwrap();
+    assert!(transfer.block_size > 512);
+    // ACK of the OACK, and of every data block.
+    assert_eq!(server.acks, 1 + transfer.blocks() as usize);
+}
+
+#[test]
+fn tftp_without_options() {
+    // A multiple of the block size, so the transfer ends with an empty block.
+    let file = pattern(512 * 4);
+    let mut server = Server::default();
+    server.files.insert(BOOT_FILE.into(), file.clone());
+    let (mut iface, server) = setup("tftp_without_options", server);
+
+    let data = tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 1 << 20).unwrap();
+    assert_eq!(data.as_deref(), Some(&file[..]));
+    assert_eq!(server.borrow().acks, 5);
+}
+
+#[test]
+fn tftp_lost_block() {
+    let file = pattern(5000);
+    let mut server = Server {
+        options: true,
+        drop_block: Some(2),
+        ..Default::default()
+    };
+    server.files.insert(BOOT_FILE.into(), file.clone());
+    let (mut iface, _server) = setup("tftp_lost_block", server);
+
+    let data = tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 1 << 20).unwrap();
+    assert_eq!(data.as_deref(), Some(&file[..]));
+}
+
+#[test]
+fn tftp_not_found() {
+    let (mut iface, server) = setup("tftp_not_found", Server::default());
+    assert_eq!(
+        tftp::read(&mut iface, SERVER_IP, "missing", 1 << 20).unwrap(),
+        None
+    );
+    assert_eq!(server.borrow().rrqs.len(), 1);
+}
+
+#[test]
+fn tftp_invalid_block_size() {
+    for block_size in [0, 7, 1469] {
+        let mut server = Server {
+            options: true,
+            block_size: Some(block_size),
+            ..Default::default()
+        };
+        server.files.insert(BOOT_FILE.into(), pattern(5000));
+        let (mut iface, _server) = setup("tftp_invalid_block_size", server);
+        assert!(tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 1 << 20).is_err());
+    }
+}
+
+#[test]
+fn tftp_too_large() {
+    let file = pattern(5000);
+    let mut server = Server {
+        options: true,
+        ..Default::default()
+    };
+    server.files.insert(BOOT_FILE.into(), file.clone());
+    let (mut iface, server) = setup("tftp_too_large", server);
+
+    assert!(tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 4999).is_err());
+    // Refused from the announced size, before any data.
+    assert_eq!(server.borrow().acks, 0);
+
+    let data = tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 5000).unwrap();
+    assert_eq!(data.as_deref(), Some(&file[..]));
+}
+
+/// The size limit holds even when the server understates the size, or does not announce it.
+#[test]
+fn tftp_too_large_unannounced() {
+    for (options, tsize) in [(true, Some(100)), (false, None)] {
+        let mut server = Server {
+            options,
+            tsize,
+            ..Default::default()
+        };
+        server.files.insert(BOOT_FILE.into(), pattern(5000));
+        let (mut iface, _server) = setup("tftp_too_large_unannounced", server);
+        assert!(tftp::read(&mut iface, SERVER_IP, BOOT_FILE, 4999).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! SD driver against a register model of an SDHCI controller.
+
+mod sdhci;
+
+use firmware_test::block::Block;
+use sdhci::{attach, initial_block, setup, Controller};
+
+#[test]
+fn read_write() {
+    let (sd, controller) = setup(Controller::new(true, 1 << 21));
+    let mut buf = vec![0; 512 * 3];
+    sd.read_exact_at(&mut buf, 512 * 10).unwrap();
+    for (i, block) in buf.chunks(512).enumerate() {
+        assert_eq!(block, initial_block(10 + i as u64));
+    }
+
+    let data: Vec<u8> = (0..512 * 2).map(|i| (i * 7) as u8).collect();
+    sd.write_all_at(&data, 512 * 11).unwrap();
+    sd.read_exact_at(&mut buf, 512 * 10).unwrap();
+    assert_eq!(buf[..512], initial_block(10));
+    assert_eq!(buf[512..], data);
+
+    assert_eq!(
+        controller.borrow().data_commands(),
+        [(18, 10, 3), (25, 11, 2), (18, 10, 3)]
+    );
+}
+
+/// Requests larger than BLK_CNT can express are split into several transfers.
+#[test]
+fn large_requests() {
+    let (sd, controller) = setup(Controller::new(true, 1 << 21));
+    let blocks = 65536;
+    let start = 1000;
+
+    let mut buf = vec![0; 512 * blocks];
+    sd.read_exact_at(&mut buf, 512 * start).unwrap();
+    for (i, block) in buf.chunks(512).enumerate() {
+        assert_eq!(block, initial_block(start + i as u64));
+    }
+    assert_eq!(
+        controller.borrow().data_commands(),
+        [(18, 1000, 65535), (17, 1000 + 65535, 1)]
+    );
+
+    controller.borrow_mut().commands.clear();
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    sd.write_all_at(&buf, 512 * start).unwrap();
+    assert_eq!(
+        controller.borrow().data_commands(),
+        [(25, 1000, 65535), (24, 1000 + 65535, 1)]
+    );
+    let controller = controller.borrow();
+    for (i, block) in buf.chunks(512).enumerate() {
+        assert_eq!(controller.written[&(start + i as u64)][..], *block);
+    }
+}
+
+/// SDSC cards are byte addressed, so blocks from 4GiB onwards cannot be addressed.
+#[test]
+fn sdsc_addressing() {
+    let (sd, controller) = setup(Controller::new(false, 1 << 22));
+    let mut buf = vec![0; 512 * 2];
+    sd.read_exact_at(&mut buf, 512 * 5).unwrap();
+    assert_eq!(buf[..512], initial_block(5));
+    assert_eq!(controller.borrow().data_commands(), [(18, 512 * 5, 2)]);
+
+    controller.borrow_mut().commands.clear();
+    assert!(sd.read_exact_at(&mut buf, 1 << 32).is_err());
+    assert!(sd.write_all_at(&buf, (1 << 32) + 512).is_err());
+    assert!(sd.discard((1 << 32) - 512, 1024).is_err());
+    // Rejected before any command, and without reinitialising the card.
+    assert_eq!(controller.borrow().commands, []);
+}
+
+/// SDHC/SDXC cards are block addressed, up to 2^32 blocks.
+#[test]
+fn sdhc_addressing() {
+    let (sd, controller) = setup(Controller::new(true, 1 << 32));
+    let mut buf = vec![0; 512];
+    sd.read_exact_at(&mut buf, 512 * 0xFFFF_FFFF).unwrap();
+    assert_eq!(buf, initial_block(0xFFFF_FFFF));
+    assert_eq!(controller.borrow().data_commands(), [(17, 0xFFFF_FFFF, 1)]);
+
+    controller.borrow_mut().commands.clear();
+    assert!(sd.read_exact_at(&mut buf, 512 << 32).is_err());
+    assert_eq!(controller.borrow().commands, []);
+}
+
+/// Discarded blocks read back as the value given by DATA_STAT_AFTER_ERASE in the SCR.
+#[test]
+fn discard() {
+    for (controller, fill) in [
+        (Controller::new(true, 1 << 21), 0x00),
+        (Controller::new(true, 1 << 21).with_erased_ones(), 0xFF),
+    ] {
+        let (sd, controller) = setup(controller);
+        assert!(controller.borrow().commands.contains(&(51, 0, 0)));
+        let capability = sd.capability();
+        assert!(capability.discard);
+        assert_eq!(capability.discard_fill, Some(fill));
+
+        sd.discard(512 * 4, 512 * 3).unwrap();
+        let mut buf = vec![0; 512 * 5];
+        sd.read_exact_at(&mut buf, 512 * 3).unwrap();
+        assert_eq!(buf[..512], initial_block(3));
+        assert!(buf[512..512 * 4].iter().all(|&x| x == fill));
+        assert_eq!(buf[512 * 4..], initial_block(7));
+    }
+}
+
+/// Clocks the controller cannot provide are reported as errors.
+#[test]
+fn unsupported_clock() {
+    // Unknown base clock, and unknown timeout clock.
+    for controller in [
+        Controller::new(true, 1 << 21).with_clocks(0, 50),
+        Controller::new(true, 1 << 21).with_clocks(50, 0),
+    ] {
+        let (sd, _controller) = attach(controller);
+        assert!(sd.power_on().is_err());
+    }
+}
+
+/// Each failure halves the highest clock, down from where it was rather than from the clock
+/// in use.
+#[test]
+fn retry_lowers_clock() {
+    let (sd, controller) = setup(Controller::new(true, 1 << 21));
+    controller.borrow_mut().failing_reads = 2;
+    let mut buf = vec![0; 512];
+    sd.read_exact_at(&mut buf, 512 * 3).unwrap();
+    assert_eq!(buf, initial_block(3));
+
+    let controller = controller.borrow();
+    let clocks: Vec<u32> = controller
+        .clocks
+        .iter()
+        .copied()
+        .filter(|&x| x > 400)
+        .collect();
+    assert_eq!(clocks, [25000, 25000, 12500]);
+    assert_eq!(
+        controller.data_commands(),
+        [(17, 3, 1), (17, 3, 1), (17, 3, 1)]
+    );
+}
@@ -0,0 +1,399 @@
+//! Register model of an SDHCI controller with a card attached.
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use firmware_test::block::{Block, Sd};
+use firmware_test::iomem::{self, Mmio};
+
+const BASE: usize = 0x1000_0000;
+
+const BLK_SIZE: usize = 0x04;
+const ARGUMENT: usize = 0x08;
+const XFER_MODE: usize = 0x0C;
+const CMD: usize = 0x0E;
+const RESPONSE: usize = 0x10;
+const BUFFER_PORT: usize = 0x20;
+const PRESENT_STATE: usize = 0x24;
+const CLOCK_CTRL: usize = 0x2C;
+const SW_RESET: usize = 0x2F;
+const IRQ_STATUS: usize = 0x30;
+const CAPABILITIES: usize = 0x40;
+
+const RCA: u32 = 0x1234;
+/// Card status with READY_FOR_DATA set, in the transfer state.
+const STATUS_TRAN: u32 = 1 << 8 | 4 << 9;
+const STATUS_APP_CMD: u32 = 1 << 5;
+
+/// Contents of a block that was never written.
+pub fn initial_block(block: u64) -> [u8; 512] {
+    let mut data = [0; 512];
+    for (i, chunk) in data.chunks_exact_mut(8).enumerate() {
+        chunk.copy_from_slice(&(block << 6 | i as u64).to_le_bytes());
+    }
+    data
+}
+
+/// A data transfer in progress.
+struct Transfer {
+    read: bool,
+    block: u64,
+    remaining: u32,
+    offset: usize,
+    /// Size of each block, which is less than 512 only for registers read as data.
+    len: usize,
+    buf: [u8; 512],
+}
+
+/// SDHCI controller with a card in the slot.
+pub struct Controller {
+    regs: [u8; 0x100],
+    /// SDHC/SDXC if set, otherwise SDSC.
+    ccs: bool,
+    /// Capacity in 512-byte blocks.
+    blocks: u64,
+    pub written: HashMap<u64, [u8; 512]>,
+    app_cmd: bool,
+    transfer: Option<Transfer>,
+    /// Commands issued, with their argument and the block count of data commands.
+    pub commands: Vec<(u8, u32, u16)>,
+    scr: [u8; 8],
+    /// First and last block to erase, as set by CMD32 and CMD33.
+    erase: (u64, u64),
+    /// Number of read commands still to fail with a data CRC error.
+    pub failing_reads: u32,
+    /// SD clocks enabled, in kHz.
+    pub clocks: Vec<u32>,
+}
+
+impl Controller {
+    /// Create a controller without High-Speed support.
+    pub fn new(ccs: bool, blocks: u64) -> Self {
+        let mut regs = [0; 0x100];
+        // 50MHz base and timeout clocks.
+        let cap: u32 = 50 << 8 | 1 << 7 | 50;
+        regs[CAPABILITIES..CAPABILITIES + 4].copy_from_slice(&cap.to_le_bytes());
+        Controller {
+            regs,
+            ccs,
+            blocks,
+            written: HashMap::new(),
+            app_cmd: false,
+            transfer: None,
+            commands: Vec::new(),
+            // SD spec 2.00, 1-bit and 4-bit bus, erased blocks reading as 0s.
+            scr: [0x02, 0x05, 0, 0, 0, 0, 0, 0],
+            erase: (0, 0),
+            failing_reads: 0,
+            clocks: Vec::new(),
+        }
+    }
+
+    /// Set the base and timeout clocks in MHz, where 0 means unknown.
+    pub fn with_clocks(mut self, base: u32, timeout: u32) -> Self {
+        let cap = self.reg(CAPABILITIES, 4) as u32 & !0xFFFF;
+        let cap = cap | base << 8 | 1 << 7 | timeout;
+        self.set_reg(CAPABILITIES, 4, cap as u64);
+        self
+    }
+
+    /// Have erased blocks read as 1s rather than 0s.
+    pub fn with_erased_ones(mut self) -> Self {
+        self.scr[1] |= 0x80;
+        self
+    }
+
+    fn reg(&self, offset: usize, size: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(&self.regs[offset..offset + size]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn set_reg(&mut self, offset: usize, size: usize, value: u64) {
+        self.regs[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    fn set_irq(&mut self, bits: u32) {
+        let status = self.reg(IRQ_STATUS, 4) as u32 | bits;
+        self.set_reg(IRQ_STATUS, 4, status as u64);
+    }
+
+    fn set_response(&mut self, resp: u128) {
+        self.regs[RESPONSE..RESPONSE + 16].copy_from_slice(&resp.to_le_bytes());
+    }
+
+    fn block_count(&self) -> u16 {
+        self.reg(0x06, 2) as u16
+    }
+
+    fn present_state(&self) -> u32 {
+        // Card inserted and stable.
+        let mut state = 0x0003_0000;
+        if let Some(transfer) = &self.transfer {
+            state |= 0b10;
+            state |= if transfer.read { 1 << 11 } else { 1 << 10 };
+        }
+        state
+    }
+
+    /// Start the next block of the current transfer, or finish it.
+    fn next_block(&mut self) {
+        let transfer = self.transfer.as_mut().unwrap();
+        if transfer.remaining == 0 {
+            self.transfer = None;
+            self.set_irq(1 << 1);
+            return;
+        }
+        assert!(transfer.block < self.blocks, "access beyond capacity");
+        transfer.offset = 0;
+        if transfer.read {
+            transfer.buf = match self.written.get(&transfer.block) {
+                Some(data) => *data,
+                None => initial_block(transfer.block),
+            };
+        }
+    }
+
+    /// Block addressed by the argument of a data or erase command.
+    fn block_address(&self, arg: u32) -> u64 {
+        if self.ccs {
+            arg as u64
+        } else {
+            assert_eq!(arg % 512, 0, "unaligned SDSC byte address");
+            (arg / 512) as u64
+        }
+    }
+
+    fn start_transfer(&mut self, read: bool, multi: bool, arg: u32) {
+        let block = self.block_address(arg);
+        assert_eq!(self.reg(BLK_SIZE, 2), 512, "wrong block size");
+        let mode = self.reg(XFER_MODE, 2);
+        assert_eq!(mode & 1, 0, "DMA enabled without DMA support");
+        assert_eq!(mode & 0b10000 != 0, read, "wrong transfer direction");
+        let remaining = if multi {
+            // Block count enable and auto CMD12 are required for a multi-block transfer.
+            assert_eq!(mode & 0b100110, 0b100110);
+            let count = self.block_count();
+            assert_ne!(count, 0);
+            count as u32
+        } else {
+            1
+        };
+        self.transfer = Some(Transfer {
+            read,
+            block,
+            remaining,
+            offset: 0,
+            len: 512,
+            buf: [0; 512],
+        });
+        self.next_block();
+    }
+
+    fn command(&mut self, index: u8) {
+        let arg = self.reg(ARGUMENT, 4) as u32;
+        let app_cmd = std::mem::take(&mut self.app_cmd);
+        let count = match index {
+            18 | 25 => self.block_count(),
+            17 | 24 => 1,
+            _ => 0,
+        };
+        self.commands.push((index, arg, count));
+        assert!(self.transfer.is_none(), "command issued during a transfer");
+
+        let resp = match (app_cmd, index) {
+            (_, 0) => 0,
+            (_, 8) => arg & 0xFFF,
+            (_, 55) => {
+                self.app_cmd = true;
+                STATUS_TRAN | STATUS_APP_CMD
+            }
+            (true, 41) => 0x8030_0000 | (self.ccs as u32) << 30,
+            (true, 6) => STATUS_TRAN,
+            (true, 51) => {
+                let len = self.reg(BLK_SIZE, 2) as usize;
+                assert_eq!(len, self.scr.len(), "wrong block size");
+                let mut buf = [0; 512];
+                buf[..len].copy_from_slice(&self.scr);
+                self.transfer = Some(Transfer {
+                    read: true,
+                    block: 0,
+                    remaining: 1,
+                    offset: 0,
+                    len,
+                    buf,
+                });
+                STATUS_TRAN
+            }
+            (_, 2 | 10) => {
+                self.set_response(0x0353_4453_4430_3847_8012_3456_7801_3c00 >> 8);
+                return;
+            }
+            (_, 3) => RCA << 16,
+            (_, 9) => {
+                self.set_response(self.csd() >> 8);
+                return;
+            }
+            (_, 7 | 13) => {
+                assert_eq!(arg, RCA << 16);
+                STATUS_TRAN
+            }
+            (_, 17 | 18) if self.failing_reads > 0 => {
+                self.failing_reads -= 1;
+                self.set_irq(1 | 1 << 15 | 0x20 << 16);
+                return;
+            }
+            (_, 17 | 18) => {
+                self.start_transfer(true, index == 18, arg);
+                STATUS_TRAN
+            }
+            (_, 24 | 25) => {
+                self.start_transfer(false, index == 25, arg);
+                STATUS_TRAN
+            }
+            (_, 32) => {
+                self.erase.0 = self.block_address(arg);
+                STATUS_TRAN
+            }
+            (_, 33) => {
+                self.erase.1 = self.block_address(arg);
+                STATUS_TRAN
+            }
+            (_, 38) => {
+                let fill = if self.scr[1] & 0x80 != 0 { 0xFF } else { 0 };
+                for block in self.erase.0..=self.erase.1 {
+                    self.written.insert(block, [fill; 512]);
+                }
+                STATUS_TRAN
+            }
+            _ => panic!("unexpected command {} (app {})", index, app_cmd),
+        };
+        self.set_response(resp as u128);
+        self.set_irq(1);
+    }
+
+    /// CSD register describing the capacity of the card.
+    fn csd(&self) -> u128 {
+        if self.ccs {
+            // Version 2.0, C_SIZE in units of 512KiB.
+            let c_size = (self.blocks / 1024 - 1) as u128;
+            1 << 126 | c_size << 48
+        } else {
+            // Version 1.0, with 1024-byte blocks and the largest multiplier.
+            let c_size = (self.blocks * 512 / 1024 / 512 - 1) as u128;
+            10 << 80 | c_size << 62 | 7 << 47
+        }
+    }
+
+    fn read_buffer(&mut self) -> u32 {
+        let transfer = self
+            .transfer
+            .as_mut()
+            .expect("buffer read without transfer");
+        assert!(transfer.read, "buffer read during a write");
+        let word = u32::from_le_bytes(
+            transfer.buf[transfer.offset..transfer.offset + 4]
+                .try_into()
+                .unwrap(),
+        );
+        transfer.offset += 4;
+        if transfer.offset == transfer.len {
+            transfer.block += 1;
+            transfer.remaining -= 1;
+            self.next_block();
+        }
+        word
+    }
+
+    fn write_buffer(&mut self, word: u32) {
+        let transfer = self
+            .transfer
+            .as_mut()
+            .expect("buffer write without transfer");
+        assert!(!transfer.read, "buffer write during a read");
+        transfer.buf[transfer.offset..transfer.offset + 4].copy_from_slice(&word.to_le_bytes());
+        transfer.offset += 4;
+        if transfer.offset == transfer.len {
+            self.written.insert(transfer.block, transfer.buf);
+            transfer.block += 1;
+            transfer.remaining -= 1;
+            self.next_block();
+        }
+    }
+
+    /// Data commands issued after initialisation.
+    pub fn data_commands(&self) -> Vec<(u8, u32, u16)> {
+        self.commands
+            .iter()
+            .copied()
+            .filter(|&(index, _, _)| matches!(index, 17 | 18 | 24 | 25))
+            .collect()
+    }
+}
+
+impl Mmio for Controller {
+    fn read(&mut self, offset: usize, size: usize) -> u64 {
+        match (offset, size) {
+            (BUFFER_PORT, 4) => self.read_buffer() as u64,
+            (PRESENT_STATE, 4) => self.present_state() as u64,
+            _ => self.reg(offset, size),
+        }
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) {
+        match (offset, size) {
+            (CMD, 2) => self.command((value >> 8) as u8),
+            (BUFFER_PORT, 4) => self.write_buffer(value as u32),
+            (CLOCK_CTRL, 2) => {
+                // The internal clock is stable as soon as it is enabled.
+                let stable = (value & 1) << 1;
+                if value & 0b100 != 0 {
+                    let base = (self.reg(CAPABILITIES, 4) >> 8 & 0x3F) as u32 * 1000;
+                    let divisor = match value >> 8 & 0xFF {
+                        0 => 1,
+                        select => select as u32 * 2,
+                    };
+                    self.clocks.push(base / divisor);
+                }
+                self.set_reg(CLOCK_CTRL, 2, value & !0b10 | stable);
+            }
+            (SW_RESET, 1) => {
+                // Resets complete immediately, aborting any transfer.
+                self.transfer = None;
+                if value & 1 != 0 {
+                    self.set_reg(IRQ_STATUS, 4, 0);
+                }
+            }
+            (IRQ_STATUS, 4) => {
+                let status = self.reg(IRQ_STATUS, 4) & !value;
+                // The error summary bit reflects the error status bits.
+                let summary = if status & 0xFFFF_0000 != 0 {
+                    1 << 15
+                } else {
+                    0
+                };
+                self.set_reg(IRQ_STATUS, 4, status & !(1 << 15) | summary);
+            }
+            _ => self.set_reg(offset, size, value),
+        }
+    }
+}
+
+/// Attach `controller`, without initialising the card.
+pub fn attach(controller: Controller) -> (Sd, Rc<RefCell<Controller>>) {
+    let controller = Rc::new(RefCell::new(controller));
+    iomem::map(BASE, controller.clone());
+    (unsafe { Sd::new(BASE, None) }, controller)
+}
+
+/// Attach `controller` and initialise the card through it.
+pub fn setup(controller: Controller) -> (Sd, Rc<RefCell<Controller>>) {
+    let blocks = controller.blocks;
+    let (sd, controller) = attach(controller);
+    sd.power_on().unwrap();
+    assert_eq!(sd.len(), blocks * 512);
+    (sd, controller)
+}
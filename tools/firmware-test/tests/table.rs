@@ -0,0 +1,311 @@
+//! Partition table parsing on in-memory disk images.
+
+use std::collections::HashSet;
+
+use firmware_test::block::{partitions, Block};
+use firmware_test::io::{Error, Result};
+
+const SECTORS: u64 = 2048;
+
+/// Disk image whose reads fail if they touch one of the `bad` sectors.
+struct Disk {
+    data: Vec<u8>,
+    bad: HashSet<u64>,
+}
+
+impl Disk {
+    fn new() -> Self {
+        Disk {
+            data: vec![0; SECTORS as usize * 512],
+            bad: HashSet::new(),
+        }
+    }
+
+    fn sector(&mut self, lba: u64) -> &mut [u8] {
+        &mut self.data[lba as usize * 512..][..512]
+    }
+}
+
+impl Block for Disk {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let first = offset / 512;
+        let last = (offset + buf.len() as u64 - 1) / 512;
+        if (first..=last).any(|lba| self.bad.contains(&lba)) {
+            return Err(Error::Textual("I/O error"));
+        }
+        buf.copy_from_slice(&self.data[offset as usize..][..buf.len()]);
+        Ok(())
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _offset: u64) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Write a MBR with entries of (boot indicator, system ID, start LBA, sector count).
+fn write_mbr(disk: &mut Disk, entries: &[(u8, u8, u32, u32)]) {
+    write_table(disk, 0, entries);
+}
+
+/// Write a EBR at `lba` with entries of (system ID, start LBA, sector count).
+fn write_ebr(disk: &mut Disk, lba: u64, entries: &[(u8, u32, u32)]) {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|&(ty, lba, sectors)| (0, ty, lba, sectors))
+        .collect();
+    write_table(disk, lba, &entries);
+}
+
+fn write_table(disk: &mut Disk, lba: u64, entries: &[(u8, u8, u32, u32)]) {
+    let sector = disk.sector(lba);
+    for (i, &(boot, ty, lba, sectors)) in entries.iter().enumerate() {
+        let entry = &mut sector[0x1BE + i * 16..][..16];
+        entry[0] = boot;
+        entry[4] = ty;
+        entry[8..12].copy_from_slice(&lba.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+    sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+/// Write a FAT32 boot sector, whose boot code fills the rest of the sector with `code`.
+fn write_fat32_boot_sector(disk: &mut Disk, code: u8) {
+    let sector = disk.sector(0);
+    sector[0x5A..510].fill(code);
+    sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"mkfs.fat");
+    sector[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+    sector[0x0D] = 1;
+    sector[0x0E..0x10].copy_from_slice(&32u16.to_le_bytes());
+    sector[0x10] = 2;
+    sector[0x15] = 0xF8;
+    sector[0x20..0x24].copy_from_slice(&(SECTORS as u32).to_le_bytes());
+    sector[0x24..0x28].copy_from_slice(&16u32.to_le_bytes());
+    sector[0x2C..0x30].copy_from_slice(&2u32.to_le_bytes());
+    sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+const LINUX_FS: [u8; 16] = [
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+];
+
+/// Write a protective MBR and both GPT copies, with partitions of (first LBA, last LBA, name).
+fn write_gpt(disk: &mut Disk, parts: &[(u64, u64, &str)]) {
+    write_mbr(disk, &[(0, 0xEE, 1, SECTORS as u32 - 1)]);
+
+    let mut entries = vec![0; 128 * 128];
+    for (entry, &(first, last, name)) in entries.chunks_exact_mut(128).zip(parts) {
+        entry[0..16].copy_from_slice(&LINUX_FS);
+        entry[16] = 1;
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (i, c) in name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    let last_lba = SECTORS - 1;
+    for (header_lba, alternate_lba, entries_lba) in [(1, last_lba, 2), (last_lba, 1, last_lba - 32)]
+    {
+        let mut header = [0; 512];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[0x0C..0x10].copy_from_slice(&92u32.to_le_bytes());
+        header[0x18..0x20].copy_from_slice(&header_lba.to_le_bytes());
+        header[0x20..0x28].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[0x28..0x30].copy_from_slice(&34u64.to_le_bytes());
+        header[0x30..0x38].copy_from_slice(&(last_lba - 33).to_le_bytes());
+        header[0x48..0x50].copy_from_slice(&entries_lba.to_le_bytes());
+        header[0x50..0x54].copy_from_slice(&128u32.to_le_bytes());
+        header[0x54..0x58].copy_from_slice(&128u32.to_le_bytes());
+        header[0x58..0x5C].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[0x10..0x14].copy_from_slice(&crc.to_le_bytes());
+
+        disk.sector(header_lba).copy_from_slice(&header);
+        disk.data[entries_lba as usize * 512..][..entries.len()].copy_from_slice(&entries);
+    }
+}
+
+#[test]
+fn mbr() {
+    let mut disk = Disk::new();
+    write_mbr(&mut disk, &[(0x80, 0x0C, 64, 512), (0, 0x83, 576, 1024)]);
+    let parts = partitions(&disk).unwrap();
+    let parts: Vec<_> = parts
+        .iter()
+        .map(|p| (p.number, p.ty.is_linux(), p.offset / 512, p.len / 512))
+        .collect();
+    assert_eq!(parts, [(1, false, 64, 512), (2, true, 576, 1024)]);
+}
+
+/// Logical partitions start relative to their EBR, and links to the next EBR relative to the
+/// extended partition.
+#[test]
+fn extended() {
+    let mut disk = Disk::new();
+    write_mbr(
+        &mut disk,
+        &[
+            (0x80, 0x0C, 64, 128),
+            (0, 0x05, 256, 1024),
+            (0, 0x83, 1400, 100),
+        ],
+    );
+    write_ebr(&mut disk, 256, &[(0x83, 32, 200), (0x05, 300, 400)]);
+    write_ebr(&mut disk, 556, &[(0x0C, 16, 100)]);
+    let parts = partitions(&disk).unwrap();
+    let parts: Vec<_> = parts
+        .iter()
+        .map(|p| (p.number, p.ty.is_linux(), p.offset / 512, p.len / 512))
+        .collect();
+    assert_eq!(
+        parts,
+        [
+            (1, false, 64, 128),
+            (3, true, 1400, 100),
+            (5, true, 288, 200),
+            (6, false, 572, 100),
+        ]
+    );
+}
+
+/// An EBR linking to itself is rejected rather than followed forever.
+#[test]
+fn extended_loop() {
+    let mut disk = Disk::new();
+    write_mbr(&mut disk, &[(0, 0x05, 256, 1024)]);
+    write_ebr(&mut disk, 256, &[(0x83, 32, 200), (0x05, 300, 400)]);
+    write_ebr(&mut disk, 556, &[(0x83, 16, 100), (0x05, 300, 400)]);
+    assert!(partitions(&disk).is_err());
+}
+
+/// Entries with a boot indicator other than 0x00 or 0x80 mean this is not a MBR.
+#[test]
+fn invalid_boot_indicator() {
+    let mut disk = Disk::new();
+    write_mbr(&mut disk, &[(0x80, 0x0C, 64, 512), (0x12, 0x83, 576, 1024)]);
+    assert!(partitions(&disk).unwrap().is_empty());
+}
+
+/// A FAT32 filesystem on the whole device is not mistaken for a partition table.
+#[test]
+fn fat_boot_sector() {
+    // Boot code that makes up invalid boot indicators.
+    let mut disk = Disk::new();
+    write_fat32_boot_sector(&mut disk, 0x4E);
+    assert!(partitions(&disk).unwrap().is_empty());
+
+    // Boot code that looks like a valid entry.
+    let mut disk = Disk::new();
+    write_fat32_boot_sector(&mut disk, 0);
+    write_mbr(&mut disk, &[(0, 0x0C, 1, 1)]);
+    assert!(partitions(&disk).unwrap().is_empty());
+}
+
+fn gpt_disk() -> Disk {
+    let mut disk = Disk::new();
+    write_gpt(&mut disk, &[(34, 1033, "boot"), (1034, 2000, "root")]);
+    disk
+}
+
+fn gpt_partitions(disk: &Disk) -> Vec<(u32, String, u64, u64)> {
+    partitions(disk)
+        .unwrap()
+        .into_iter()
+        .map(|p| {
+            assert!(p.ty.is_linux());
+            (p.number, p.name, p.offset / 512, p.len / 512)
+        })
+        .collect()
+}
+
+fn gpt_expected() -> Vec<(u32, String, u64, u64)> {
+    vec![(1, "boot".into(), 34, 1000), (2, "root".into(), 1034, 967)]
+}
+
+#[test]
+fn gpt() {
+    assert_eq!(gpt_partitions(&gpt_disk()), gpt_expected());
+}
+
+#[test]
+fn gpt_primary_corrupted() {
+    let mut disk = gpt_disk();
+    disk.sector(1)[0x20] ^= 1;
+    assert_eq!(gpt_partitions(&disk), gpt_expected());
+
+    let mut disk = gpt_disk();
+    disk.sector(2)[0] ^= 1;
+    assert_eq!(gpt_partitions(&disk), gpt_expected());
+}
+
+#[test]
+fn gpt_primary_unreadable() {
+    let mut disk = gpt_disk();
+    disk.bad.insert(1);
+    assert_eq!(gpt_partitions(&disk), gpt_expected());
+
+    let mut disk = gpt_disk();
+    disk.bad.insert(5);
+    assert_eq!(gpt_partitions(&disk), gpt_expected());
+}
+
+/// Entries whose byte offset or length does not fit in 64 bits are ignored.
+#[test]
+fn gpt_range_overflow() {
+    let mut disk = Disk::new();
+    write_gpt(
+        &mut disk,
+        &[
+            (34, 1033, "boot"),
+            (1 << 55, (1 << 55) + 1, "far"),
+            (0, u64::MAX, "huge"),
+            (1034, 2000, "root"),
+        ],
+    );
+    let parts: Vec<_> = gpt_partitions(&disk)
+        .into_iter()
+        .map(|(number, name, _, _)| (number, name))
+        .collect();
+    assert_eq!(parts, [(1, "boot".into()), (4, "root".into())]);
+}
+
+/// A header whose entry array lies beyond 2^64 bytes is not valid.
+#[test]
+fn gpt_entries_overflow() {
+    let mut disk = gpt_disk();
+    let header = disk.sector(1);
+    header[0x48..0x50].copy_from_slice(&(1u64 << 55).to_le_bytes());
+    header[0x10..0x14].fill(0);
+    let crc = crc32(&header[..92]);
+    header[0x10..0x14].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(gpt_partitions(&disk), gpt_expected());
+}
+
+#[test]
+fn gpt_both_damaged() {
+    let mut disk = gpt_disk();
+    disk.bad.insert(1);
+    disk.sector(SECTORS - 1)[0x20] ^= 1;
+    assert!(partitions(&disk).is_err());
+}
@@ -0,0 +1,204 @@
+//! The firmware YMODEM receiver against the host sender, over a pty.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use firmware_test::uart::{attach, Port};
+use firmware_test::ymodem::{receive, Error};
+
+const NAK: u8 = 0x15;
+
+/// Open a pty, returning the master, the slave and the path of the slave.
+fn open_pty() -> (File, File, PathBuf) {
+    unsafe {
+        let mut master = 0;
+        let mut slave = 0;
+        let ret = libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+        assert_eq!(ret, 0, "openpty failed");
+
+        // Make the slave raw before the sender opens it, so nothing written early is echoed.
+        let mut termios = std::mem::zeroed();
+        libc::tcgetattr(slave, &mut termios);
+        libc::cfmakeraw(&mut termios);
+        libc::tcsetattr(slave, libc::TCSANOW, &termios);
+
+        let mut name = [0 as libc::c_char; 64];
+        assert_eq!(libc::ttyname_r(slave, name.as_mut_ptr(), name.len()), 0);
+        let path = std::ffi::CStr::from_ptr(name.as_ptr()).to_str().unwrap();
+        (
+            File::from_raw_fd(master),
+            File::from_raw_fd(slave),
+            path.into(),
+        )
+    }
+}
+
+/// Master side of the pty as seen by the firmware UART, with faults that can be injected.
+struct Pty {
+    master: File,
+    /// Number of bytes received so far.
+    received: usize,
+    /// Index of a received byte to corrupt.
+    corrupt: Option<usize>,
+    /// Number of NAKs sent.
+    naks: Rc<Cell<u32>>,
+}
+
+impl Port for Pty {
+    fn send(&mut self, byte: u8) {
+        if byte == NAK {
+            self.naks.set(self.naks.get() + 1);
+        }
+        self.master.write_all(&[byte]).unwrap();
+    }
+
+    fn try_recv(&mut self) -> Option<u8> {
+        // Wait a little, rather than have the receiver spin on an empty line.
+        let mut pollfd = libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, 10) } <= 0 {
+            return None;
+        }
+        let mut byte = [0];
+        match self.master.read(&mut byte) {
+            Ok(1) => (),
+            _ => return None,
+        }
+        if self.corrupt == Some(self.received) {
+            byte[0] ^= 0x20;
+        }
+        self.received += 1;
+        Some(byte[0])
+    }
+}
+
+struct Transfer {
+    received: Result<Vec<(String, Vec<u8>)>, Error>,
+    sent: Result<(), String>,
+    naks: u32,
+}
+
+fn run(test: &str, files: &[(&str, Vec<u8>)], max_size: usize, corrupt: Option<usize>) -> Transfer {
+    let dir = std::env::temp_dir().join(format!("firmware-test-ymodem-{}", test));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|(name, data)| {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        })
+        .collect();
+
+    // Keep the slave open until the sender is done, so the pty is not hung up early.
+    let (master, slave, slave_path) = open_pty();
+    let sender = std::thread::spawn(move || {
+        ymodem::send(&slave_path, None, &paths).map_err(|err| err.to_string())
+    });
+
+    let naks = Rc::new(Cell::new(0));
+    attach(Some(Box::new(Pty {
+        master,
+        received: 0,
+        corrupt,
+        naks: naks.clone(),
+    })));
+    let received = receive(max_size).map(|files| {
+        files
+            .into_iter()
+            .map(|file| (file.name, file.data))
+            .collect()
+    });
+    let sent = sender.join().unwrap();
+    attach(None);
+    drop(slave);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    Transfer {
+        received,
+        sent,
+        naks: naks.get(),
+    }
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| {
+            (i as u32)
+                .wrapping_mul(2654435761)
+                .wrapping_add(seed as u32) as u8
+        })
+        .collect()
+}
+
+fn check_received(transfer: &Transfer, files: &[(&str, Vec<u8>)]) {
+    transfer.sent.as_ref().unwrap();
+    let received = transfer.received.as_ref().unwrap();
+    assert_eq!(received.len(), files.len());
+    for ((name, data), (received_name, received_data)) in files.iter().zip(received) {
+        assert_eq!(name, received_name);
+        assert!(data == received_data, "{} differs", name);
+    }
+}
+
+#[test]
+fn batch() {
+    let files = [
+        ("Image", pattern(5000, 1)),
+        ("device_tree.dtb", pattern(1024, 2)),
+        ("initrd", pattern(1, 3)),
+    ];
+    let transfer = run("batch", &files, 1 << 20, None);
+    check_received(&transfer, &files);
+    // Only the first EOT of each file.
+    assert_eq!(transfer.naks, 3);
+}
+
+#[test]
+fn crc_retransmission() {
+    let files = [("Image", pattern(4096, 4))];
+    // A data byte of block 2, after the 128-byte header block and block 1.
+    let transfer = run("crc", &files, 1 << 20, Some(133 + 1029 + 100));
+    check_received(&transfer, &files);
+    assert_eq!(transfer.naks, 2);
+}
+
+/// Files exactly filling the space to load into are accepted.
+#[test]
+fn exact_fit() {
+    let files = [("Image", pattern(3000, 5)), ("initrd", pattern(2000, 6))];
+    let transfer = run("exact-fit", &files, 5000, None);
+    check_received(&transfer, &files);
+}
+
+/// A file announced as larger than the space to load into is refused before any data.
+#[test]
+fn file_too_large() {
+    let files = [("Image", pattern(3000, 7))];
+    let transfer = run("too-large", &files, 2999, None);
+    assert!(matches!(transfer.received, Err(Error::TooLarge)));
+    assert!(transfer.sent.is_err());
+}
+
+/// Files that fit on their own but not together are refused.
+#[test]
+fn batch_too_large() {
+    let files = [("Image", pattern(3000, 8)), ("initrd", pattern(2000, 9))];
+    let transfer = run("batch-too-large", &files, 4999, None);
+    assert!(matches!(transfer.received, Err(Error::TooLarge)));
+    assert!(transfer.sent.is_err());
+}
@@ -0,0 +1,190 @@
+//! YMODEM sender for uploading a kernel, device tree and initrd to the firmware boot menu.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../../../firmware/src/crc.rs"]
+mod crc;
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+
+const MAX_RETRIES: u32 = 10;
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Put the serial port into raw mode, with reads timing out after 1 second.
+fn configure(port: &File, baud: Option<u32>) -> Result<()> {
+    let fd = port.as_raw_fd();
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 10;
+        if let Some(baud) = baud {
+            let speed = match baud {
+                9600 => libc::B9600,
+                19200 => libc::B19200,
+                38400 => libc::B38400,
+                57600 => libc::B57600,
+                115200 => libc::B115200,
+                230400 => libc::B230400,
+                460800 => libc::B460800,
+                921600 => libc::B921600,
+                _ => return Err(format!("unsupported baud rate {}", baud).into()),
+            };
+            libc::cfsetispeed(&mut termios, speed);
+            libc::cfsetospeed(&mut termios, speed);
+        }
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        libc::tcflush(fd, libc::TCIFLUSH);
+    }
+    Ok(())
+}
+
+struct Sender {
+    port: File,
+}
+
+impl Sender {
+    /// Read a byte, returning `None` on timeout.
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        match self.port.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Wait for the receiver to request a transfer.
+    fn wait_for_start(&mut self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            match self.read_byte()? {
+                Some(CRC) => return Ok(()),
+                Some(CAN) => return Err("cancelled by receiver".into()),
+                _ => (),
+            }
+        }
+        Err("timeout waiting for receiver".into())
+    }
+
+    /// Send a block and wait for it to be acknowledged.
+    fn send_block(&mut self, block: u8, data: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.push(if data.len() == 1024 { STX } else { SOH });
+        packet.push(block);
+        packet.push(!block);
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&crc::crc16(0, data).to_be_bytes());
+
+        for _ in 0..MAX_RETRIES {
+            self.port.write_all(&packet)?;
+            loop {
+                match self.read_byte()? {
+                    Some(ACK) => return Ok(()),
+                    Some(NAK) | None => break,
+                    Some(CAN) => return Err("cancelled by receiver".into()),
+                    // Stray 'C' from the receiver before it saw the block.
+                    Some(_) => (),
+                }
+            }
+        }
+        Err("too many errors".into())
+    }
+
+    fn send_eot(&mut self) -> Result<()> {
+        for _ in 0..MAX_RETRIES {
+            self.port.write_all(&[EOT])?;
+            match self.read_byte()? {
+                Some(ACK) => return Ok(()),
+                Some(CAN) => return Err("cancelled by receiver".into()),
+                _ => (),
+            }
+        }
+        Err("too many errors".into())
+    }
+
+    fn send_header(&mut self, header: &[u8]) -> Result<()> {
+        let mut block = vec![0; if header.len() > 128 { 1024 } else { 128 }];
+        block[..header.len()].copy_from_slice(header);
+        self.send_block(0, &block)
+    }
+
+    fn send_file(&mut self, path: &Path, first: bool) -> Result<()> {
+        let data = std::fs::read(path)?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("invalid file name")?;
+        let header = format!("{}\0{}\0", name, data.len());
+        if header.len() > 1024 {
+            return Err("file name too long".into());
+        }
+
+        self.wait_for_start(if first {
+            START_TIMEOUT
+        } else {
+            Duration::from_secs(10)
+        })?;
+        self.send_header(header.as_bytes())?;
+        self.wait_for_start(Duration::from_secs(10))?;
+
+        let start = Instant::now();
+        for (i, chunk) in data.chunks(1024).enumerate() {
+            let mut block = chunk.to_vec();
+            // Pad the last block with SUB.
+            block.resize(1024, 0x1A);
+            self.send_block((i + 1) as u8, &block)?;
+            eprint!(
+                "\r{}: {}/{} KiB",
+                name,
+                (i * 1024 + chunk.len()) / 1024,
+                data.len() / 1024
+            );
+        }
+        self.send_eot()?;
+        eprintln!(
+            "\r{}: {} KiB sent in {:.1?}",
+            name,
+            data.len() / 1024,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.wait_for_start(Duration::from_secs(10))?;
+        self.send_header(&[])
+    }
+}
+
+/// Send `files` as one batch over the serial port at `port_path`, at `baud` if given.
+pub fn send(port_path: &Path, baud: Option<u32>, files: &[PathBuf]) -> Result<()> {
+    let port = OpenOptions::new().read(true).write(true).open(port_path)?;
+    configure(&port, baud)?;
+
+    let mut sender = Sender { port };
+    eprintln!("Waiting for receiver...");
+    for (i, file) in files.iter().enumerate() {
+        sender.send_file(file, i == 0)?;
+    }
+    sender.finish()
+}
@@ -0,0 +1,28 @@
+//! Upload files to the firmware boot menu.
+//!
+//! Usage: ymodem <serial port> [--baud <rate>] <files>...
+
+use std::path::{Path, PathBuf};
+
+use ymodem::{send, Result};
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let port_path = args
+        .next()
+        .ok_or("usage: ymodem <serial port> [--baud <rate>] <files>...")?;
+    let mut baud = None;
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--baud" {
+            baud = Some(args.next().ok_or("missing baud rate")?.parse()?);
+        } else {
+            files.push(PathBuf::from(arg));
+        }
+    }
+    if files.is_empty() {
+        return Err("no files to send".into());
+    }
+
+    send(Path::new(&port_path), baud, &files)
+}